@@ -0,0 +1,1006 @@
+use crate::error::Result;
+use crate::savestate::StateReader;
+use std::collections::VecDeque;
+
+// Output rate of the sample stream handed to the audio backend
+pub const SAMPLE_RATE: u32 = 44100;
+const CPU_CLOCK: u32 = 1789773;
+// About a second of audio, older samples are dropped when nobody drains them
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// NTSC DMC output periods in CPU cycles
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Frame sequencer steps as the CPU cycle they happen on and whether they
+// clock the half frame units on top of the quarter frame ones. The 5-step
+// sequence has an extra step that clocks nothing
+const FOUR_STEP_SEQUENCE: [(u16, Option<bool>); 4] = [
+    (7457, Some(false)),
+    (14913, Some(true)),
+    (22371, Some(false)),
+    (29829, Some(true)),
+];
+const FIVE_STEP_SEQUENCE: [(u16, Option<bool>); 5] = [
+    (7457, Some(false)),
+    (14913, Some(true)),
+    (22371, Some(false)),
+    (29829, None),
+    (37281, Some(true)),
+];
+
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn write_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&[
+            self.start as u8,
+            self.looping as u8,
+            self.constant as u8,
+            self.volume,
+            self.divider,
+            self.decay,
+        ]);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.start = reader.bool()?;
+        self.looping = reader.bool()?;
+        self.constant = reader.bool()?;
+        self.volume = reader.u8()? & 0x0F;
+        self.divider = reader.u8()? & 0x0F;
+        self.decay = reader.u8()? & 0x0F;
+        Ok(())
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    period: u16,
+    length: u8,
+    // Also the envelope loop flag
+    length_halt: bool,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    // Pulse 1 negates with the ones' complement, pulse 2 with the two's
+    ones_complement: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            enabled: false,
+            duty: 0,
+            step: 0,
+            timer: 0,
+            period: 0,
+            length: 0,
+            length_halt: false,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+            ones_complement,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length_halt = data & 0x20 != 0;
+                self.envelope.looping = self.length_halt;
+                self.envelope.constant = data & 0x10 != 0;
+                self.envelope.volume = data & 0x0F;
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x7;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x7;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0x7) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[data as usize >> 3];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn write_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&[
+            self.enabled as u8,
+            self.duty,
+            self.step,
+            self.length,
+            self.length_halt as u8,
+            self.sweep_enabled as u8,
+            self.sweep_period,
+            self.sweep_negate as u8,
+            self.sweep_shift,
+            self.sweep_divider,
+            self.sweep_reload as u8,
+        ]);
+        state.extend_from_slice(&self.timer.to_le_bytes());
+        state.extend_from_slice(&self.period.to_le_bytes());
+        self.envelope.write_state(state);
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.bool()?;
+        self.duty = reader.u8()? & 0x3;
+        self.step = reader.u8()? & 0x7;
+        self.length = reader.u8()?;
+        self.length_halt = reader.bool()?;
+        self.sweep_enabled = reader.bool()?;
+        self.sweep_period = reader.u8()? & 0x7;
+        self.sweep_negate = reader.bool()?;
+        self.sweep_shift = reader.u8()? & 0x7;
+        self.sweep_divider = reader.u8()? & 0x7;
+        self.sweep_reload = reader.bool()?;
+        self.timer = reader.u16()? & 0x7FF;
+        self.period = reader.u16()? & 0x7FF;
+        self.envelope.read_state(reader)
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length_halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    // The sweep mutes the channel whenever its target is out of range, even if
+    // the sweep itself is disabled
+    fn sweep_muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.sweep_muted()
+        {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0
+            || self.sweep_muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            return 0;
+        }
+        self.envelope.output()
+    }
+}
+
+struct Triangle {
+    enabled: bool,
+    step: u8,
+    timer: u16,
+    period: u16,
+    length: u8,
+    // Also halts the length counter
+    control: bool,
+    linear: u8,
+    linear_reload_value: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn new() -> Self {
+        Triangle {
+            enabled: false,
+            step: 0,
+            timer: 0,
+            period: 0,
+            length: 0,
+            control: false,
+            linear: 0,
+            linear_reload_value: 0,
+            linear_reload: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7F;
+            }
+            // $4009 is unused
+            1 => (),
+            2 => self.period = (self.period & 0x700) | data as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((data as u16 & 0x7) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[data as usize >> 3];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    fn write_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&[
+            self.enabled as u8,
+            self.step,
+            self.length,
+            self.control as u8,
+            self.linear,
+            self.linear_reload_value,
+            self.linear_reload as u8,
+        ]);
+        state.extend_from_slice(&self.timer.to_le_bytes());
+        state.extend_from_slice(&self.period.to_le_bytes());
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.bool()?;
+        self.step = reader.u8()? & 31;
+        self.length = reader.u8()?;
+        self.control = reader.bool()?;
+        self.linear = reader.u8()? & 0x7F;
+        self.linear_reload_value = reader.u8()? & 0x7F;
+        self.linear_reload = reader.bool()?;
+        self.timer = reader.u16()? & 0x7FF;
+        self.period = reader.u16()? & 0x7FF;
+        Ok(())
+    }
+
+    // Clocked every CPU cycle, so a given period sounds an octave above the
+    // pulse channels. The sequencer only moves while both counters are running
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear = self.linear_reload_value;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    // Periods under 2 are ultrasonic, games use them to silence the channel
+    fn output(&self) -> u8 {
+        if self.period < 2 {
+            return 0;
+        }
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+// Delta modulation channel, plays 1-bit deltas read from the cartridge. The
+// bytes are fetched by DMA, the APU only asks for them through dmc_request
+struct Dmc {
+    enabled: bool,
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    fetch_pending: bool,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            enabled: false,
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            period: DMC_RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            fetch_pending: false,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.period = DMC_RATE_TABLE[data as usize & 0xF];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    // Enabling restarts the sample only when the last one is over
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Address of the next sample byte once the buffer runs empty, asked only
+    // once per byte
+    fn request(&mut self) -> Option<u16> {
+        if self.buffer.is_some() || self.bytes_remaining == 0 || self.fetch_pending {
+            return None;
+        }
+        self.fetch_pending = true;
+        Some(self.address)
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.fetch_pending = false;
+        if self.bytes_remaining == 0 {
+            return;
+        }
+        self.buffer = Some(data);
+        // The address wraps to $8000, not $0000
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn write_state(&self, state: &mut Vec<u8>) {
+        state.extend_from_slice(&[
+            self.enabled as u8,
+            self.irq_enabled as u8,
+            self.irq as u8,
+            self.looping as u8,
+            self.level,
+            self.buffer.is_some() as u8,
+            self.buffer.unwrap_or(0),
+            self.fetch_pending as u8,
+            self.shift,
+            self.bits_remaining,
+            self.silence as u8,
+        ]);
+        for value in [
+            self.period,
+            self.timer,
+            self.sample_address,
+            self.sample_length,
+            self.address,
+            self.bytes_remaining,
+        ]
+        .iter()
+        {
+            state.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.bool()?;
+        self.irq_enabled = reader.bool()?;
+        self.irq = reader.bool()?;
+        self.looping = reader.bool()?;
+        self.level = reader.u8()? & 0x7F;
+        let buffered = reader.bool()?;
+        let buffer = reader.u8()?;
+        self.buffer = if buffered { Some(buffer) } else { None };
+        self.fetch_pending = reader.bool()?;
+        self.shift = reader.u8()?;
+        self.bits_remaining = reader.u8()?.clamp(1, 8);
+        self.silence = reader.bool()?;
+        self.period = reader.u16()?.max(1);
+        self.timer = reader.u16()?;
+        self.sample_address = reader.u16()?;
+        self.sample_length = reader.u16()?;
+        self.address = reader.u16()?;
+        self.bytes_remaining = reader.u16()?;
+        Ok(())
+    }
+}
+
+pub struct Apu {
+    pulse1: Pulse,
+    triangle: Triangle,
+    dmc: Dmc,
+    // CPU cycles into the current frame sequence
+    frame_cycle: u16,
+    // Next step of the frame sequence
+    frame_step: usize,
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // A $4017 write restarts the sequence 3 or 4 cycles later
+    frame_reset_delay: u8,
+    // The frame sequencer only moves through clock_frame_sequencer
+    manual_frame_sequencer: bool,
+    // Odd CPU cycles clock the channel timers
+    odd_cycle: bool,
+    // Fractional position of the next output sample, in CPU cycles times
+    // SAMPLE_RATE
+    sample_clock: u32,
+    samples: VecDeque<f32>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse1: Pulse::new(true),
+            triangle: Triangle::new(),
+            dmc: Dmc::new(),
+            frame_cycle: 0,
+            frame_step: 0,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_reset_delay: 0,
+            manual_frame_sequencer: false,
+            odd_cycle: false,
+            sample_clock: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn power_on(&mut self) {
+        let manual_frame_sequencer = self.manual_frame_sequencer;
+        *self = Apu::new();
+        self.manual_frame_sequencer = manual_frame_sequencer;
+    }
+
+    // Reset silences every channel like a $4015 write of 0 and restarts the
+    // frame sequence, the $4017 mode is kept
+    pub fn reset(&mut self) {
+        self.pulse1.set_enabled(false);
+        self.triangle.set_enabled(false);
+        self.dmc.set_enabled(false);
+        self.frame_cycle = 0;
+        self.frame_step = 0;
+        self.frame_irq = false;
+    }
+
+    // Channel and frame sequencer state, buffered samples are left out
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        self.pulse1.write_state(&mut state);
+        self.triangle.write_state(&mut state);
+        self.dmc.write_state(&mut state);
+        state.extend_from_slice(&self.frame_cycle.to_le_bytes());
+        state.extend_from_slice(&[
+            self.frame_step as u8,
+            self.five_step_mode as u8,
+            self.irq_inhibit as u8,
+            self.frame_irq as u8,
+            self.frame_reset_delay,
+            self.odd_cycle as u8,
+        ]);
+        state.extend_from_slice(&(self.sample_clock as u64).to_le_bytes());
+        state
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(state);
+        self.pulse1.read_state(&mut reader)?;
+        self.triangle.read_state(&mut reader)?;
+        self.dmc.read_state(&mut reader)?;
+        self.frame_cycle = reader.u16()?;
+        self.frame_step = reader.u8()? as usize;
+        self.five_step_mode = reader.bool()?;
+        self.irq_inhibit = reader.bool()?;
+        self.frame_irq = reader.bool()?;
+        self.frame_reset_delay = reader.u8()?;
+        self.odd_cycle = reader.bool()?;
+        self.sample_clock = reader.u64()? as u32 % CPU_CLOCK;
+        self.frame_step %= self.frame_sequence().len();
+        reader.finish()
+    }
+
+    // For music tools that clock the envelopes and length counters themselves
+    // instead of following the CPU clock
+    pub fn set_manual_frame_sequencer(&mut self, manual: bool) {
+        self.manual_frame_sequencer = manual;
+    }
+
+    pub fn cpu_write(&mut self, address: u16, data: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address & 0x3, data),
+            0x4008..=0x400B => self.triangle.write(address & 0x3, data),
+            0x4010..=0x4013 => self.dmc.write(address & 0x3, data),
+            0x4015 => {
+                self.pulse1.set_enabled(data & 0x01 != 0);
+                self.triangle.set_enabled(data & 0x04 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step_mode = data & 0x80 != 0;
+                self.irq_inhibit = data & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_reset_delay = if self.odd_cycle { 4 } else { 3 };
+                // The 5-step mode clocks every unit right away
+                if self.five_step_mode {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Reading the status clears the frame IRQ flag, but not the DMC one
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        let data = self.cpu_peek(address);
+        if address == 0x4015 {
+            self.frame_irq = false;
+        }
+        data
+    }
+
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        if address != 0x4015 {
+            return 0;
+        }
+        // Channels with a running length counter, then the IRQ flags
+        let mut status = (self.frame_irq as u8) << 6;
+        if self.pulse1.length > 0 {
+            status |= 0x01;
+        }
+        if self.triangle.length > 0 {
+            status |= 0x04;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        status | (self.dmc.irq as u8) << 7
+    }
+
+    // IRQ line driven by the frame counter
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Address the DMC wants a sample byte from, the DMA fetches it while the
+    // CPU is stalled and hands it back with dmc_fill
+    pub fn dmc_request(&mut self) -> Option<u16> {
+        self.dmc.request()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    // Called once per CPU cycle, the APU has no say on the CPU timing
+    pub fn tick(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.dmc.clock_timer();
+
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                self.frame_step = 0;
+            }
+        }
+        if !self.manual_frame_sequencer {
+            self.frame_cycle += 1;
+            let sequence = self.frame_sequence();
+            if self.frame_cycle == sequence[self.frame_step].0 {
+                self.clock_frame_sequencer();
+                // The frame IRQ is raised at the end of the 4-step sequence
+                if self.frame_step == 0 {
+                    self.frame_cycle = 0;
+                    if !self.five_step_mode && !self.irq_inhibit {
+                        self.frame_irq = true;
+                    }
+                }
+            }
+        }
+
+        self.sample_clock += SAMPLE_RATE;
+        if self.sample_clock >= CPU_CLOCK {
+            self.sample_clock -= CPU_CLOCK;
+            if self.samples.len() == MAX_BUFFERED_SAMPLES {
+                self.samples.pop_front();
+            }
+            let sample = self.output();
+            self.samples.push_back(sample);
+        }
+    }
+
+    fn frame_sequence(&self) -> &'static [(u16, Option<bool>)] {
+        if self.five_step_mode {
+            &FIVE_STEP_SEQUENCE
+        } else {
+            &FOUR_STEP_SEQUENCE
+        }
+    }
+
+    // Runs the next step of the frame sequence
+    pub fn clock_frame_sequencer(&mut self) {
+        let sequence = self.frame_sequence();
+        if let Some(half) = sequence[self.frame_step].1 {
+            self.quarter_frame();
+            if half {
+                self.half_frame();
+            }
+        }
+        self.frame_step = (self.frame_step + 1) % sequence.len();
+    }
+
+    // Envelopes and the triangle linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    // Length counters and sweeps
+    fn half_frame(&mut self) {
+        self.pulse1.clock_length();
+        self.pulse1.clock_sweep();
+        self.triangle.clock_length();
+    }
+
+    // Nonlinear mix from the NESdev wiki, between 0.0 and 1.0
+    fn output(&self) -> f32 {
+        let pulse = self.pulse1.output() as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let triangle = self.triangle.output() as f32;
+        let dmc = self.dmc.level as f32;
+        let tnd_out = if triangle == 0.0 && dmc == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / (triangle / 8227.0 + dmc / 22638.0) + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    // Samples produced since the last call, at SAMPLE_RATE
+    pub fn drain_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_frame_sequencer_only_moves_when_clocked() {
+        let mut apu = Apu::new();
+        apu.set_manual_frame_sequencer(true);
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4000, 0x00);
+        // Length index 1, 254
+        apu.cpu_write(0x4003, 0x08);
+        for _ in 0..40000 {
+            apu.tick();
+        }
+        assert_eq!(apu.pulse1.length, 254);
+        assert!(!apu.irq());
+
+        // The first step only clocks the quarter frame units, the second one
+        // the length counters too
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.pulse1.length, 254);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.pulse1.length, 253);
+    }
+
+    #[test]
+    fn manual_frame_sequencer_survives_power_on() {
+        let mut apu = Apu::new();
+        apu.set_manual_frame_sequencer(true);
+        apu.power_on();
+        assert!(apu.manual_frame_sequencer);
+    }
+
+    #[test]
+    fn triangle_steps_only_while_both_counters_run() {
+        let mut apu = Apu::new();
+        apu.set_manual_frame_sequencer(true);
+        apu.cpu_write(0x4015, 0x04);
+        // Control set, linear counter of 1, period 2
+        apu.cpu_write(0x4008, 0x81);
+        apu.cpu_write(0x400A, 0x02);
+        apu.cpu_write(0x400B, 0x08);
+        assert_eq!(apu.cpu_peek(0x4015), 0x04);
+
+        // The linear counter is only loaded on the next quarter frame
+        for _ in 0..9 {
+            apu.tick();
+        }
+        assert_eq!(apu.triangle.output(), 15);
+
+        apu.clock_frame_sequencer();
+        for _ in 0..9 {
+            apu.tick();
+        }
+        // A step every period + 1 cycles, the timer started at 0
+        assert_eq!(apu.triangle.output(), 12);
+
+        // Ultrasonic periods are muted
+        apu.cpu_write(0x400A, 0x01);
+        assert_eq!(apu.triangle.output(), 0);
+    }
+
+    #[test]
+    fn four_step_sequence_raises_the_frame_irq() {
+        let mut apu = Apu::new();
+        for _ in 0..29828 {
+            apu.tick();
+        }
+        assert!(!apu.irq());
+        apu.tick();
+        assert!(apu.irq());
+        assert_eq!(apu.cpu_peek(0x4015), 0x40);
+
+        // Reading the status acknowledges it
+        apu.cpu_read(0x4015);
+        assert!(!apu.irq());
+
+        // Inhibited, or in 5-step mode, it never fires
+        for mode in [0x40, 0x80].iter() {
+            let mut apu = Apu::new();
+            apu.cpu_write(0x4017, *mode);
+            for _ in 0..40000 {
+                apu.tick();
+            }
+            assert!(!apu.irq());
+        }
+    }
+
+    #[test]
+    fn status_reports_running_length_counters() {
+        let mut apu = Apu::new();
+        apu.cpu_write(0x4015, 0x05);
+        assert_eq!(apu.cpu_peek(0x4015), 0x00);
+        apu.cpu_write(0x4003, 0x08);
+        assert_eq!(apu.cpu_peek(0x4015), 0x01);
+        apu.cpu_write(0x400B, 0x08);
+        assert_eq!(apu.cpu_peek(0x4015), 0x05);
+
+        // Disabling a channel clears its counter
+        apu.cpu_write(0x4015, 0x04);
+        assert_eq!(apu.cpu_read(0x4015), 0x04);
+    }
+
+    #[test]
+    fn dmc_requests_each_sample_byte_once() {
+        let mut apu = Apu::new();
+        // IRQ on, a 1 byte sample at $C000
+        apu.cpu_write(0x4010, 0x80);
+        apu.cpu_write(0x4012, 0x00);
+        apu.cpu_write(0x4013, 0x00);
+        apu.cpu_write(0x4015, 0x10);
+        assert_eq!(apu.dmc_request(), Some(0xC000));
+        assert_eq!(apu.dmc_request(), None);
+
+        apu.dmc_fill(0xFF);
+        assert_eq!(apu.dmc_request(), None);
+        assert!(apu.irq());
+        assert_eq!(apu.cpu_read(0x4015), 0x80);
+        // Unlike the frame IRQ a status read doesn't acknowledge it
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn dmc_address_wraps_to_8000() {
+        let mut apu = Apu::new();
+        // 65 bytes from $FFC0
+        apu.cpu_write(0x4012, 0xFF);
+        apu.cpu_write(0x4013, 0x04);
+        apu.cpu_write(0x4015, 0x10);
+        for _ in 0..64 {
+            assert!(apu.dmc_request().is_some());
+            apu.dmc_fill(0);
+            apu.dmc.buffer = None;
+        }
+        assert_eq!(apu.dmc_request(), Some(0x8000));
+    }
+}
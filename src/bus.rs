@@ -0,0 +1,12 @@
+// Shared by the CPU and PPU, each side is handed a bus that only decodes its
+// own address space
+pub trait BusOps {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+}
+
+// Side-effect free view of a bus for debuggers, returns what a read would
+// without touching latches, buffers or toggles
+pub trait PeekOps {
+    fn peek(&self, address: u16) -> u8;
+}
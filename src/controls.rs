@@ -0,0 +1,373 @@
+use nesrust::error::{NesError, Result};
+use nesrust::joystick::{Button, Joystick};
+use sfml::window::{joystick::Axis, Key};
+use std::path::Path;
+
+// Config names of the buttons, the key arrays follow this order
+const BUTTONS: [(&str, Button); 8] = [
+    ("a", Button::A),
+    ("b", Button::B),
+    ("select", Button::Select),
+    ("start", Button::Start),
+    ("up", Button::Up),
+    ("down", Button::Down),
+    ("left", Button::Left),
+    ("right", Button::Right),
+];
+
+// Keys are named like the sfml Key variants
+const KEYS: [(&str, Key); 101] = [
+    ("A", Key::A),
+    ("B", Key::B),
+    ("C", Key::C),
+    ("D", Key::D),
+    ("E", Key::E),
+    ("F", Key::F),
+    ("G", Key::G),
+    ("H", Key::H),
+    ("I", Key::I),
+    ("J", Key::J),
+    ("K", Key::K),
+    ("L", Key::L),
+    ("M", Key::M),
+    ("N", Key::N),
+    ("O", Key::O),
+    ("P", Key::P),
+    ("Q", Key::Q),
+    ("R", Key::R),
+    ("S", Key::S),
+    ("T", Key::T),
+    ("U", Key::U),
+    ("V", Key::V),
+    ("W", Key::W),
+    ("X", Key::X),
+    ("Y", Key::Y),
+    ("Z", Key::Z),
+    ("Num0", Key::Num0),
+    ("Num1", Key::Num1),
+    ("Num2", Key::Num2),
+    ("Num3", Key::Num3),
+    ("Num4", Key::Num4),
+    ("Num5", Key::Num5),
+    ("Num6", Key::Num6),
+    ("Num7", Key::Num7),
+    ("Num8", Key::Num8),
+    ("Num9", Key::Num9),
+    ("Escape", Key::Escape),
+    ("LControl", Key::LControl),
+    ("LShift", Key::LShift),
+    ("LAlt", Key::LAlt),
+    ("LSystem", Key::LSystem),
+    ("RControl", Key::RControl),
+    ("RShift", Key::RShift),
+    ("RAlt", Key::RAlt),
+    ("RSystem", Key::RSystem),
+    ("Menu", Key::Menu),
+    ("LBracket", Key::LBracket),
+    ("RBracket", Key::RBracket),
+    ("SemiColon", Key::SemiColon),
+    ("Comma", Key::Comma),
+    ("Period", Key::Period),
+    ("Quote", Key::Quote),
+    ("Slash", Key::Slash),
+    ("BackSlash", Key::BackSlash),
+    ("Tilde", Key::Tilde),
+    ("Equal", Key::Equal),
+    ("Dash", Key::Dash),
+    ("Space", Key::Space),
+    ("Return", Key::Return),
+    ("BackSpace", Key::BackSpace),
+    ("Tab", Key::Tab),
+    ("PageUp", Key::PageUp),
+    ("PageDown", Key::PageDown),
+    ("End", Key::End),
+    ("Home", Key::Home),
+    ("Insert", Key::Insert),
+    ("Delete", Key::Delete),
+    ("Add", Key::Add),
+    ("Subtract", Key::Subtract),
+    ("Multiply", Key::Multiply),
+    ("Divide", Key::Divide),
+    ("Left", Key::Left),
+    ("Right", Key::Right),
+    ("Up", Key::Up),
+    ("Down", Key::Down),
+    ("Numpad0", Key::Numpad0),
+    ("Numpad1", Key::Numpad1),
+    ("Numpad2", Key::Numpad2),
+    ("Numpad3", Key::Numpad3),
+    ("Numpad4", Key::Numpad4),
+    ("Numpad5", Key::Numpad5),
+    ("Numpad6", Key::Numpad6),
+    ("Numpad7", Key::Numpad7),
+    ("Numpad8", Key::Numpad8),
+    ("Numpad9", Key::Numpad9),
+    ("F1", Key::F1),
+    ("F2", Key::F2),
+    ("F3", Key::F3),
+    ("F4", Key::F4),
+    ("F5", Key::F5),
+    ("F6", Key::F6),
+    ("F7", Key::F7),
+    ("F8", Key::F8),
+    ("F9", Key::F9),
+    ("F10", Key::F10),
+    ("F11", Key::F11),
+    ("F12", Key::F12),
+    ("F13", Key::F13),
+    ("F14", Key::F14),
+    ("F15", Key::F15),
+    ("Pause", Key::Pause),
+];
+
+// Keyboard layout of both controllers, controller 2 lives on the numeric
+// keypad by default
+pub struct Controls {
+    keys: [[Key; 8]; 2],
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Controls {
+            keys: [
+                [
+                    Key::X,
+                    Key::Z,
+                    Key::L,
+                    Key::S,
+                    Key::Up,
+                    Key::Down,
+                    Key::Left,
+                    Key::Right,
+                ],
+                [
+                    Key::Numpad2,
+                    Key::Numpad1,
+                    Key::Numpad7,
+                    Key::Numpad9,
+                    Key::Numpad8,
+                    Key::Numpad5,
+                    Key::Numpad4,
+                    Key::Numpad6,
+                ],
+            ],
+        }
+    }
+
+    // A missing file keeps the default layout
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Controls::new());
+        }
+        Controls::parse(&std::fs::read_to_string(path)?)
+    }
+
+    // Only the TOML subset the config needs is understood, a [player1] or
+    // [player2] table followed by button = "Key" lines, buttons left out keep
+    // their default key:
+    //
+    // [player1]
+    // a = "K"
+    // b = "J"
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut controls = Controls::new();
+        let mut player = None;
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| {
+                NesError::InvalidConfig(format!("line {}: {}", number + 1, message))
+            };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                player = match line[1..line.len() - 1].trim() {
+                    "player1" => Some(0),
+                    "player2" => Some(1),
+                    _ => return Err(error("unknown table")),
+                };
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = parts
+                .next()
+                .ok_or_else(|| error("expected button = \"Key\""))?;
+            let value = value.trim();
+            if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+                return Err(error("key name must be quoted"));
+            }
+
+            let player = player.ok_or_else(|| error("button outside of a player table"))?;
+            let button = BUTTONS
+                .iter()
+                .position(|(button, _)| *button == name)
+                .ok_or_else(|| error("unknown button"))?;
+            let key = KEYS
+                .iter()
+                .find(|(key, _)| *key == &value[1..value.len() - 1])
+                .ok_or_else(|| error("unknown key"))?;
+            controls.keys[player][button] = key.1;
+        }
+        Ok(controls)
+    }
+
+    // Controller port and button bound to a key
+    pub fn button(&self, code: Key) -> Option<(usize, Button)> {
+        for (port, keys) in self.keys.iter().enumerate() {
+            if let Some(index) = keys.iter().position(|&key| key == code) {
+                return Some((port, BUTTONS[index].1));
+            }
+        }
+        None
+    }
+}
+
+// Axis positions go from -100 to 100, sticks rest near the center but rarely
+// exactly on it
+const DEAD_ZONE: f32 = 40.0;
+
+// Position past the dead zone as -1, 0 or 1
+fn axis_direction(position: f32) -> i8 {
+    if position < -DEAD_ZONE {
+        -1
+    } else if position > DEAD_ZONE {
+        1
+    } else {
+        0
+    }
+}
+
+// Gamepads 0 and 1 drive controllers 1 and 2 alongside the keyboard. Only
+// changes are forwarded so a centered stick doesn't release a direction held
+// on the keyboard
+pub struct Gamepads {
+    // Stick X, stick Y, hat X and hat Y directions on each gamepad, the stick
+    // and the hat are tracked apart so letting go of one keeps a direction
+    // the other still holds
+    directions: [[i8; 4]; 2],
+}
+
+impl Gamepads {
+    pub fn new() -> Self {
+        Gamepads {
+            directions: [[0; 4]; 2],
+        }
+    }
+
+    // Layout of the common XInput style pads, the bottom face button is B and
+    // the right one is A like on the NES pad
+    fn button(button: u32) -> Option<Button> {
+        match button {
+            0 | 2 => Some(Button::B),
+            1 | 3 => Some(Button::A),
+            6 => Some(Button::Select),
+            7 => Some(Button::Start),
+            _ => None,
+        }
+    }
+
+    pub fn button_pressed(&self, joystick: &mut Joystick, id: u32, button: u32) {
+        match Gamepads::button(button) {
+            Some(button) if id < 2 => joystick.press(id as usize, button),
+            _ => (),
+        }
+    }
+
+    pub fn button_released(&self, joystick: &mut Joystick, id: u32, button: u32) {
+        match Gamepads::button(button) {
+            Some(button) if id < 2 => joystick.release(id as usize, button),
+            _ => (),
+        }
+    }
+
+    // Both the left stick and the hat drive the D-pad, a direction is held
+    // while either of them points that way
+    pub fn axis_moved(&mut self, joystick: &mut Joystick, id: u32, axis: Axis, position: f32) {
+        if id >= 2 {
+            return;
+        }
+        let (index, direction) = match axis {
+            Axis::X => (0, axis_direction(position)),
+            Axis::Y => (1, axis_direction(position)),
+            Axis::PovX => (2, axis_direction(position)),
+            // The hat reports up as positive
+            Axis::PovY => (3, -axis_direction(position)),
+            _ => return,
+        };
+        let buttons = if index % 2 == 0 {
+            [Button::Left, Button::Right]
+        } else {
+            [Button::Up, Button::Down]
+        };
+
+        let port = id as usize;
+        let held = |directions: &[i8; 4]| {
+            let stick = directions[index % 2];
+            let hat = directions[index % 2 + 2];
+            [stick == -1 || hat == -1, stick == 1 || hat == 1]
+        };
+        let previous = held(&self.directions[port]);
+        self.directions[port][index] = direction;
+        let current = held(&self.directions[port]);
+
+        for side in 0..2 {
+            match (previous[side], current[side]) {
+                (false, true) => joystick.press(port, buttons[side]),
+                (true, false) => joystick.release(port, buttons[side]),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rebinds_the_listed_buttons() {
+        let controls = Controls::parse(
+            "# Home row layout\n\
+             [player1]\n\
+             a = \"K\"\n\
+             b = \"J\"  # run\n\
+             \n\
+             [player2]\n\
+             start = \"Return\"\n",
+        )
+        .unwrap();
+
+        assert!(matches!(controls.button(Key::K), Some((0, Button::A))));
+        assert!(matches!(controls.button(Key::J), Some((0, Button::B))));
+        assert!(matches!(
+            controls.button(Key::Return),
+            Some((1, Button::Start))
+        ));
+        // Buttons left out keep their default key
+        assert!(matches!(controls.button(Key::Up), Some((0, Button::Up))));
+        assert!(matches!(
+            controls.button(Key::Numpad2),
+            Some((1, Button::A))
+        ));
+        assert!(controls.button(Key::X).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_names() {
+        let error = |text: &str| match Controls::parse(text) {
+            Err(NesError::InvalidConfig(message)) => message,
+            _ => panic!("{:?} was accepted", text),
+        };
+        assert_eq!(error("[player1]\na = \"Foo\""), "line 2: unknown key");
+        assert_eq!(error("[player1]\nturbo = \"K\""), "line 2: unknown button");
+        assert_eq!(error("[player3]"), "line 1: unknown table");
+        assert_eq!(
+            error("a = \"K\""),
+            "line 1: button outside of a player table"
+        );
+        assert_eq!(error("[player1]\na = K"), "line 2: key name must be quoted");
+    }
+}
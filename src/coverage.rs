@@ -0,0 +1,113 @@
+use crate::error::Result;
+use crate::{cpu, mapper, nes};
+use std::io::Write;
+use std::path::Path;
+
+pub struct Coverage {
+    counts: [u64; 256],
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { counts: [0; 256] }
+    }
+
+    pub fn add(&mut self, cpu: &cpu::Cpu6502) {
+        for (count, executed) in self.counts.iter_mut().zip(cpu.opcode_counts().iter()) {
+            *count += executed;
+        }
+    }
+
+    pub fn counts(&self) -> &[u64; 256] {
+        &self.counts
+    }
+
+    // Runs each ROM headlessly for the given number of frames, a ROM that hits
+    // an unimplemented opcode stops there, is reported to out and the next one
+    // is started
+    pub fn run_roms(
+        &mut self,
+        paths: &[String],
+        frames: usize,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        for path in paths {
+            let mut mapper = mapper::Mapper::new();
+            mapper.load(Path::new(path))?;
+            let mut nes = nes::Nes::new(mapper);
+            nes.set_stop_on_error(true);
+            for _ in 0..frames {
+                nes.run_frame();
+                if nes.halted() {
+                    writeln!(out, "{}: stopped at {:#06X}", path, nes.cpu.pc)?;
+                    break;
+                }
+            }
+            self.add(&nes.cpu);
+        }
+        Ok(())
+    }
+
+    // Stubbed opcodes first, the most executed ones on top
+    pub fn report(&self) -> String {
+        let mut opcodes: Vec<u8> = (0..=255).collect();
+        opcodes.sort_by_key(|&opcode| {
+            (
+                !cpu::Cpu6502::is_stub(opcode),
+                std::cmp::Reverse(self.counts[opcode as usize]),
+            )
+        });
+
+        let mut report = String::new();
+        for opcode in opcodes {
+            let status = if cpu::Cpu6502::is_stub(opcode) {
+                "stub"
+            } else {
+                "ok"
+            };
+            report += &format!(
+                "{:02X} {:>4} {}\n",
+                opcode, status, self.counts[opcode as usize]
+            );
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_roms_counts_the_executed_opcodes() {
+        // LDA #$01, INX, INX, STP
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 2, 0, 0, 0];
+        rom.resize(16 + 32768, 0xEA);
+        rom[16..22].copy_from_slice(&[0xA9, 0x01, 0xE8, 0xE8, 0x02, 0xEA]);
+        for vector in (16 + 0x7FFA..16 + 0x8000).step_by(2) {
+            rom[vector] = 0x00;
+            rom[vector + 1] = 0x80;
+        }
+        let path = std::env::temp_dir().join(format!("nesrust-{}-cov.nes", std::process::id()));
+        std::fs::write(&path, &rom).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let mut coverage = Coverage::new();
+        let mut out = Vec::new();
+        let result = coverage.run_roms(std::slice::from_ref(&path), 1, &mut out);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(coverage.counts()[0xA9], 1);
+        assert_eq!(coverage.counts()[0xE8], 2);
+        assert_eq!(coverage.counts()[0x4C], 0);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(&format!("{}: stopped at", path)));
+    }
+}
@@ -1,3 +1,16 @@
+pub use crate::bus::{BusOps, PeekOps};
+use crate::disasm;
+use crate::error::{NesError, Result};
+use std::io::Write;
+
+// Little endian addresses of the interrupt handlers, BRK shares the IRQ one
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+// Size of the buffer produced by to_bytes
+pub const STATE_SIZE: usize = 24;
+
 enum InterruptType {
     None,
     Reset,
@@ -37,10 +50,12 @@ pub enum CycleResult {
     EndInstruction,
     Continue,
     Error,
+    // STP/KIL jammed the CPU, only a reset brings it back
+    Halted,
 }
 
-#[derive(Copy, Clone)]
-enum AddressingMode {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressingMode {
     Accumulator,
     Immediate,
     ZeroPage,
@@ -55,17 +70,367 @@ enum AddressingMode {
     Relative,
 }
 
-#[derive(Copy, Clone)]
-enum InstructionType {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InstructionType {
     Branching,
     Read,
     ReadModifyWrite,
     Write,
 }
 
-pub trait BusOps {
-    fn read(&mut self, address: u16) -> u8;
-    fn write(&mut self, address: u16, data: u8);
+// One variant per instruction handler, the interrupt sequences included, so
+// the handlers can be dispatched without going through a function pointer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operation {
+    Adc,
+    Ahx,
+    Alr,
+    Anc,
+    And,
+    Arr,
+    Asl,
+    Axs,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dcp,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Irq,
+    Isc,
+    Jmp,
+    Jsr,
+    Las,
+    Lax,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nmi,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rla,
+    Rol,
+    Ror,
+    Rra,
+    Rst,
+    Rti,
+    Rts,
+    Sax,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Shx,
+    Shy,
+    Slo,
+    Sre,
+    Sta,
+    Stp,
+    Stx,
+    Sty,
+    Tas,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    Xaa,
+}
+
+// Decoding for every opcode, built once and indexed on each fetch
+#[rustfmt::skip]
+static INSTRUCTIONS: [(AddressingMode, InstructionType, Operation); 256] = [
+    (AddressingMode::Immediate, InstructionType::Branching, Operation::Brk),         // 00
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Ora),              // 01
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 02
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Slo),   // 03
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Nop),               // 04
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Ora),               // 05
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Asl),    // 06
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Slo),    // 07
+    (AddressingMode::Accumulator, InstructionType::Write, Operation::Php),           // 08
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Ora),              // 09
+    (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Operation::Asl), // 0A
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Anc),              // 0B
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Nop),               // 0C
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Ora),               // 0D
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Asl),    // 0E
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Slo),    // 0F
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bpl),          // 10
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Ora),              // 11
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 12
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Slo),   // 13
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // 14
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Ora),              // 15
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Asl),   // 16
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Slo),   // 17
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Clc),            // 18
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Ora),              // 19
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // 1A
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Slo),   // 1B
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // 1C
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Ora),              // 1D
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Asl),   // 1E
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Slo),   // 1F
+    (AddressingMode::Absolute, InstructionType::Branching, Operation::Jsr),          // 20
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::And),              // 21
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 22
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Rla),   // 23
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Bit),               // 24
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::And),               // 25
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Rol),    // 26
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Rla),    // 27
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Plp),            // 28
+    (AddressingMode::Immediate, InstructionType::Read, Operation::And),              // 29
+    (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Operation::Rol), // 2A
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Anc),              // 2B
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Bit),               // 2C
+    (AddressingMode::Absolute, InstructionType::Read, Operation::And),               // 2D
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Rol),    // 2E
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Rla),    // 2F
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bmi),          // 30
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::And),              // 31
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 32
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Rla),   // 33
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // 34
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::And),              // 35
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Rol),   // 36
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Rla),   // 37
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Sec),            // 38
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::And),              // 39
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // 3A
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Rla),   // 3B
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // 3C
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::And),              // 3D
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Rol),   // 3E
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Rla),   // 3F
+    (AddressingMode::Accumulator, InstructionType::Branching, Operation::Rti),       // 40
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Eor),              // 41
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 42
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Sre),   // 43
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Nop),               // 44
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Eor),               // 45
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Lsr),    // 46
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Sre),    // 47
+    (AddressingMode::Accumulator, InstructionType::Write, Operation::Pha),           // 48
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Eor),              // 49
+    (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Operation::Lsr), // 4A
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Alr),              // 4B
+    (AddressingMode::Absolute, InstructionType::Branching, Operation::Jmp),          // 4C
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Eor),               // 4D
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Lsr),    // 4E
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Sre),    // 4F
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bvc),          // 50
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Eor),              // 51
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 52
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Sre),   // 53
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // 54
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Eor),              // 55
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Lsr),   // 56
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Sre),   // 57
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Cli),            // 58
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Eor),              // 59
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // 5A
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Sre),   // 5B
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // 5C
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Eor),              // 5D
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Lsr),   // 5E
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Sre),   // 5F
+    (AddressingMode::Accumulator, InstructionType::Branching, Operation::Rts),       // 60
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Adc),              // 61
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 62
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Rra),   // 63
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Nop),               // 64
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Adc),               // 65
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Ror),    // 66
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Rra),    // 67
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Pla),            // 68
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Adc),              // 69
+    (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Operation::Ror), // 6A
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Arr),              // 6B
+    (AddressingMode::AbsoluteIndirect, InstructionType::Branching, Operation::Jmp),  // 6C
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Adc),               // 6D
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Ror),    // 6E
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Rra),    // 6F
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bvs),          // 70
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Adc),              // 71
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 72
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Rra),   // 73
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // 74
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Adc),              // 75
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Ror),   // 76
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Rra),   // 77
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Sei),            // 78
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Adc),              // 79
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // 7A
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Rra),   // 7B
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // 7C
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Adc),              // 7D
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Ror),   // 7E
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Rra),   // 7F
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Nop),              // 80
+    (AddressingMode::IndirectX, InstructionType::Write, Operation::Sta),             // 81
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Nop),              // 82
+    (AddressingMode::IndirectX, InstructionType::Write, Operation::Sax),             // 83
+    (AddressingMode::ZeroPage, InstructionType::Write, Operation::Sty),              // 84
+    (AddressingMode::ZeroPage, InstructionType::Write, Operation::Sta),              // 85
+    (AddressingMode::ZeroPage, InstructionType::Write, Operation::Stx),              // 86
+    (AddressingMode::ZeroPage, InstructionType::Write, Operation::Sax),              // 87
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Dey),            // 88
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Nop),              // 89
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Txa),            // 8A
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Xaa),              // 8B
+    (AddressingMode::Absolute, InstructionType::Write, Operation::Sty),              // 8C
+    (AddressingMode::Absolute, InstructionType::Write, Operation::Sta),              // 8D
+    (AddressingMode::Absolute, InstructionType::Write, Operation::Stx),              // 8E
+    (AddressingMode::Absolute, InstructionType::Write, Operation::Sax),              // 8F
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bcc),          // 90
+    (AddressingMode::IndirectY, InstructionType::Write, Operation::Sta),             // 91
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // 92
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Ahx),              // 93
+    (AddressingMode::ZeroPageX, InstructionType::Write, Operation::Sty),             // 94
+    (AddressingMode::ZeroPageX, InstructionType::Write, Operation::Sta),             // 95
+    (AddressingMode::ZeroPageY, InstructionType::Write, Operation::Stx),             // 96
+    (AddressingMode::ZeroPageY, InstructionType::Write, Operation::Sax),             // 97
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Tya),            // 98
+    (AddressingMode::AbsoluteY, InstructionType::Write, Operation::Sta),             // 99
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Txs),            // 9A
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Tas),              // 9B
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Shy),              // 9C
+    (AddressingMode::AbsoluteX, InstructionType::Write, Operation::Sta),             // 9D
+    (AddressingMode::AbsoluteY, InstructionType::Write, Operation::Shx),             // 9E
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Ahx),              // 9F
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Ldy),              // A0
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Lda),              // A1
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Ldx),              // A2
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Lax),              // A3
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Ldy),               // A4
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Lda),               // A5
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Ldx),               // A6
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Lax),               // A7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Tay),            // A8
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Lda),              // A9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Tax),            // AA
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Lax),              // AB
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Ldy),               // AC
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Lda),               // AD
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Ldx),               // AE
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Lax),               // AF
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bcs),          // B0
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Lda),              // B1
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // B2
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Lax),              // B3
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Ldy),              // B4
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Lda),              // B5
+    (AddressingMode::ZeroPageY, InstructionType::Read, Operation::Ldx),              // B6
+    (AddressingMode::ZeroPageY, InstructionType::Read, Operation::Lax),              // B7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Clv),            // B8
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Lda),              // B9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Tsx),            // BA
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Las),              // BB
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Ldy),              // BC
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Lda),              // BD
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Ldx),              // BE
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Lax),              // BF
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Cpy),              // C0
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Cmp),              // C1
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Nop),              // C2
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Dcp),   // C3
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Cpy),               // C4
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Cmp),               // C5
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Dec),    // C6
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Dcp),    // C7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Iny),            // C8
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Cmp),              // C9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Dex),            // CA
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Axs),              // CB
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Cpy),               // CC
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Cmp),               // CD
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Dec),    // CE
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Dcp),    // CF
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Bne),          // D0
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Cmp),              // D1
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // D2
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Dcp),   // D3
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // D4
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Cmp),              // D5
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Dec),   // D6
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Dcp),   // D7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Cld),            // D8
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Cmp),              // D9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // DA
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Dcp),   // DB
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // DC
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Cmp),              // DD
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Dec),   // DE
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Dcp),   // DF
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Cpx),              // E0
+    (AddressingMode::IndirectX, InstructionType::Read, Operation::Sbc),              // E1
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Nop),              // E2
+    (AddressingMode::IndirectX, InstructionType::ReadModifyWrite, Operation::Isc),   // E3
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Cpx),               // E4
+    (AddressingMode::ZeroPage, InstructionType::Read, Operation::Sbc),               // E5
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Inc),    // E6
+    (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Operation::Isc),    // E7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Inx),            // E8
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Sbc),              // E9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // EA
+    (AddressingMode::Immediate, InstructionType::Read, Operation::Sbc),              // EB
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Cpx),               // EC
+    (AddressingMode::Absolute, InstructionType::Read, Operation::Sbc),               // ED
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Inc),    // EE
+    (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Operation::Isc),    // EF
+    (AddressingMode::Relative, InstructionType::Branching, Operation::Beq),          // F0
+    (AddressingMode::IndirectY, InstructionType::Read, Operation::Sbc),              // F1
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Stp),            // F2
+    (AddressingMode::IndirectY, InstructionType::ReadModifyWrite, Operation::Isc),   // F3
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Nop),              // F4
+    (AddressingMode::ZeroPageX, InstructionType::Read, Operation::Sbc),              // F5
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Inc),   // F6
+    (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Operation::Isc),   // F7
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Sed),            // F8
+    (AddressingMode::AbsoluteY, InstructionType::Read, Operation::Sbc),              // F9
+    (AddressingMode::Accumulator, InstructionType::Read, Operation::Nop),            // FA
+    (AddressingMode::AbsoluteY, InstructionType::ReadModifyWrite, Operation::Isc),   // FB
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Nop),              // FC
+    (AddressingMode::AbsoluteX, InstructionType::Read, Operation::Sbc),              // FD
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Inc),   // FE
+    (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Operation::Isc),   // FF
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub sr: u8,
 }
 
 pub struct Cpu6502 {
@@ -76,13 +441,27 @@ pub struct Cpu6502 {
     pub sp: u8,
     pub sr: u8,
     pub value: u8,
+    // The 2A03 has the BCD logic cut out, enable it to run as a stock 6502
+    pub decimal_enabled: bool,
     pub address: u16,
     pub stage: u8,
 
     interrupt_type: InterruptType,
     addressing_mode: AddressingMode,
     instruction_type: InstructionType,
-    instruction_pointer: fn(&mut Cpu6502, &mut dyn BusOps) -> CycleResult,
+    operation: Operation,
+    opcode_counts: [u64; 256],
+    opcode: u8,
+    halted: bool,
+    irq_line: bool,
+    cycles: u64,
+    trace: Option<Box<dyn Write>>,
+}
+
+impl Default for Cpu6502 {
+    fn default() -> Self {
+        Cpu6502::new()
+    }
 }
 
 impl Cpu6502 {
@@ -95,31 +474,83 @@ impl Cpu6502 {
             sp: 0,
             sr: 0,
             value: 0,
+            decimal_enabled: false,
             address: 0,
             stage: 1,
             interrupt_type: InterruptType::None,
             addressing_mode: AddressingMode::Accumulator,
             instruction_type: InstructionType::Read,
-            instruction_pointer: Cpu6502::nop,
+            operation: Operation::Nop,
+            opcode_counts: [0; 256],
+            opcode: 0,
+            halted: false,
+            irq_line: false,
+            cycles: 0,
+            trace: None,
         }
     }
 
-    pub fn reset(&mut self) {
-        self.sp = 0xFD;
+    pub fn power_on(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0;
         self.sr = 0x24;
+        self.reset();
+    }
+
+    // Registers are kept, the reset sequence decrements SP and sets the
+    // interrupt disable flag before jumping through the reset vector
+    pub fn reset(&mut self) {
+        self.set_flag(Flags::InterruptDisable, true);
+        self.stage = 1;
         self.interrupt_type = InterruptType::Reset;
+        self.halted = false;
     }
 
     pub fn set_nmi(&mut self) {
         self.interrupt_type = InterruptType::Nmi;
     }
 
-    pub fn tick(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    // The IRQ input is level sensitive, it is sampled at every instruction
+    // boundary and stays pending until the device drops the line
+    pub fn set_irq(&mut self, line: bool) {
+        self.irq_line = line;
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.irq_line
+    }
+
+    pub fn tick<B: BusOps + PeekOps>(&mut self, bus: &mut B) -> CycleResult {
+        if self.halted {
+            return CycleResult::Halted;
+        }
+
+        let result = self.run_cycle(bus);
+        self.cycles += 1;
+        result
+    }
+
+    fn run_cycle<B: BusOps + PeekOps>(&mut self, bus: &mut B) -> CycleResult {
         if self.stage == 1 {
             if let InterruptType::None = self.interrupt_type {
+                if self.irq_line && !self.is_flag_set(Flags::InterruptDisable) {
+                    self.interrupt_type = InterruptType::Irq;
+                }
+            }
+            if let InterruptType::None = self.interrupt_type {
+                if self.trace.is_some() {
+                    let line = disasm::trace_line(self, bus);
+                    if let Some(trace) = &mut self.trace {
+                        let _ = writeln!(trace, "{}", line);
+                    }
+                }
                 let opcode = self.read_memory(bus, self.pc);
                 self.pc += 1;
                 self.stage += 1;
+                self.opcode_counts[opcode as usize] += 1;
+                self.opcode = opcode;
                 self.fetch_instruction(opcode);
                 return CycleResult::EndCycle;
             } else {
@@ -128,7 +559,7 @@ impl Cpu6502 {
             return CycleResult::EndCycle;
         }
 
-        let result = (self.instruction_pointer)(self, bus);
+        let result = self.execute(bus);
         match result {
             CycleResult::EndInstruction => {
                 self.stage = 1;
@@ -138,38 +569,262 @@ impl Cpu6502 {
                 self.stage += 1;
                 result
             }
+            CycleResult::Halted => {
+                self.halted = true;
+                result
+            }
             _ => CycleResult::Error,
         }
     }
 
+    // Runs the rest of the current instruction, or the whole interrupt
+    // sequence when one is pending, and returns the cycles it took. Stops
+    // early if the CPU jams or hits an unimplemented opcode
+    pub fn step_instruction<B: BusOps + PeekOps>(&mut self, bus: &mut B) -> u64 {
+        let mut cycles = 0;
+        loop {
+            let result = self.tick(bus);
+            cycles += 1;
+            match result {
+                CycleResult::EndInstruction | CycleResult::Error | CycleResult::Halted => {
+                    return cycles
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            pc: self.pc,
+            sp: self.sp,
+            sr: self.sr,
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // CPU cycles run since the CPU was created, the reset sequence included
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Every instruction fetch writes a line in the nestest.log format to the
+    // sink, None turns tracing off
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
+
+    // Last opcode fetched, the one being executed when stage is above 1
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    #[cfg(feature = "test-access")]
+    pub fn addressing_mode(&self) -> AddressingMode {
+        self.addressing_mode
+    }
+
+    #[cfg(feature = "test-access")]
+    pub fn set_addressing_mode(&mut self, addressing_mode: AddressingMode) {
+        self.addressing_mode = addressing_mode;
+    }
+
+    #[cfg(feature = "test-access")]
+    pub fn instruction_type(&self) -> InstructionType {
+        self.instruction_type
+    }
+
+    #[cfg(feature = "test-access")]
+    pub fn set_instruction_type(&mut self, instruction_type: InstructionType) {
+        self.instruction_type = instruction_type;
+    }
+
+    // Decodes the opcode as if it had just been fetched, stage still has to be
+    // set to the one to run next
+    #[cfg(feature = "test-access")]
+    pub fn set_opcode(&mut self, opcode: u8) {
+        self.opcode = opcode;
+        self.fetch_instruction(opcode);
+    }
+
+    // Registers and the progress of the current instruction. The handler is
+    // stored as the opcode it was decoded from, or the interrupt sequence it
+    // runs, so a restored CPU resumes in the middle of the instruction
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sequence = match self.operation {
+            operation if operation == INSTRUCTIONS[self.opcode as usize].2 => 0,
+            Operation::Rst => 1,
+            Operation::Nmi => 2,
+            Operation::Irq => 3,
+            Operation::Brk => 4,
+            _ => 5,
+        };
+        let interrupt_type = match self.interrupt_type {
+            InterruptType::None => 0,
+            InterruptType::Reset => 1,
+            InterruptType::Nmi => 2,
+            InterruptType::Brk => 3,
+            InterruptType::Irq => 4,
+        };
+
+        let mut state = vec![
+            self.a,
+            self.x,
+            self.y,
+            self.sp,
+            self.sr,
+            (self.pc & 0xFF) as u8,
+            (self.pc >> 8) as u8,
+            self.value,
+            (self.address & 0xFF) as u8,
+            (self.address >> 8) as u8,
+            self.stage,
+            self.opcode,
+            sequence,
+            interrupt_type,
+            self.halted as u8,
+            self.irq_line as u8,
+        ];
+        state.extend_from_slice(&self.cycles.to_le_bytes());
+        state
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != STATE_SIZE {
+            return Err(NesError::InvalidState);
+        }
+        let interrupt_type = match state[13] {
+            0 => InterruptType::None,
+            1 => InterruptType::Reset,
+            2 => InterruptType::Nmi,
+            3 => InterruptType::Brk,
+            4 => InterruptType::Irq,
+            _ => return Err(NesError::InvalidState),
+        };
+
+        // Interrupt sequences keep the addressing mode of the last opcode
+        self.opcode = state[11];
+        self.fetch_instruction(self.opcode);
+        self.operation = match state[12] {
+            0 => self.operation,
+            1 => Operation::Rst,
+            2 => Operation::Nmi,
+            3 => Operation::Irq,
+            4 => Operation::Brk,
+            5 => Operation::Nop,
+            _ => return Err(NesError::InvalidState),
+        };
+
+        self.a = state[0];
+        self.x = state[1];
+        self.y = state[2];
+        self.sp = state[3];
+        self.sr = state[4];
+        self.pc = state[5] as u16 | (state[6] as u16) << 8;
+        self.value = state[7];
+        self.address = state[8] as u16 | (state[9] as u16) << 8;
+        self.stage = state[10];
+        self.interrupt_type = interrupt_type;
+        self.halted = state[14] != 0;
+        self.irq_line = state[15] != 0;
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&state[16..24]);
+        self.cycles = u64::from_le_bytes(cycles);
+        Ok(())
+    }
+
+    // Number of times each opcode was fetched since the CPU was created
+    pub fn opcode_counts(&self) -> &[u64; 256] {
+        &self.opcode_counts
+    }
+
+    // Unimplemented opcodes fail on their first stage, so running that stage
+    // against a dummy bus tells them apart
+    pub fn is_stub(opcode: u8) -> bool {
+        struct NullBus;
+        impl BusOps for NullBus {
+            fn read(&mut self, _address: u16) -> u8 {
+                0
+            }
+            fn write(&mut self, _address: u16, _data: u8) {}
+        }
+
+        let mut cpu = Cpu6502::new();
+        cpu.fetch_instruction(opcode);
+        cpu.stage = 2;
+        matches!(cpu.execute(&mut NullBus), CycleResult::Error)
+    }
+
     fn load_interrupt(&mut self) {
-        self.instruction_pointer = match self.interrupt_type {
-            InterruptType::Brk => Cpu6502::brk,
-            InterruptType::Reset => Cpu6502::rst,
-            InterruptType::Nmi => Cpu6502::nmi,
-            InterruptType::Irq => Cpu6502::irq,
-            InterruptType::None => Cpu6502::nop,
+        self.operation = match self.interrupt_type {
+            InterruptType::Brk => Operation::Brk,
+            InterruptType::Reset => Operation::Rst,
+            InterruptType::Nmi => Operation::Nmi,
+            InterruptType::Irq => Operation::Irq,
+            InterruptType::None => Operation::Nop,
         };
 
         self.interrupt_type = InterruptType::None;
         self.stage += 1;
     }
 
-    fn read_memory(&mut self, bus: &mut dyn BusOps, memory_address: u16) -> u8 {
+    // An NMI asserted while BRK/IRQ pushes to the stack hijacks the vector fetch,
+    // the pushed flags are kept but execution continues at the NMI handler
+    fn hijack_vector(&mut self, vector: u16) -> u16 {
+        if let InterruptType::Nmi = self.interrupt_type {
+            self.interrupt_type = InterruptType::None;
+            return NMI_VECTOR;
+        }
+        vector
+    }
+
+    pub fn read_vector<B: BusOps>(bus: &mut B, vector: u16) -> u16 {
+        let address_low = bus.read(vector) as u16;
+        let address_high = (bus.read(vector + 1) as u16) << 8;
+        address_high | address_low
+    }
+
+    // Last two stages of every interrupt sequence, the handler address is
+    // loaded one byte at a time
+    fn fetch_vector<B: BusOps>(&mut self, bus: &mut B, vector: u16) -> CycleResult {
+        match self.stage {
+            6 => {
+                let address_high = self.pc & 0xFF00;
+                let address_low = self.read_memory(bus, vector) as u16;
+                self.pc = address_high | address_low;
+                CycleResult::EndCycle
+            }
+            7 => {
+                let address_high = (self.read_memory(bus, vector + 1) as u16) << 8;
+                let address_low = self.pc & 0xFF;
+                self.pc = address_high | address_low;
+                CycleResult::EndInstruction
+            }
+            _ => CycleResult::Error,
+        }
+    }
+
+    fn read_memory<B: BusOps>(&mut self, bus: &mut B, memory_address: u16) -> u8 {
         return bus.read(memory_address);
     }
 
-    fn write_memory(&mut self, bus: &mut dyn BusOps, memory_address: u16, data: u8) {
+    fn write_memory<B: BusOps>(&mut self, bus: &mut B, memory_address: u16, data: u8) {
         bus.write(memory_address, data);
     }
 
-    fn read_stack(&mut self, bus: &mut dyn BusOps) -> u8 {
+    fn read_stack<B: BusOps>(&mut self, bus: &mut B) -> u8 {
         let stack_base = 0x100;
         let stack_address = stack_base + self.sp as u16;
         return self.read_memory(bus, stack_address);
     }
 
-    fn write_stack(&mut self, bus: &mut dyn BusOps, data: u8) {
+    fn write_stack<B: BusOps>(&mut self, bus: &mut B, data: u8) {
         let stack_base = 0x100;
         let stack_address = stack_base + self.sp as u16;
         self.write_memory(bus, stack_address, data);
@@ -189,276 +844,101 @@ impl Cpu6502 {
         }
     }
 
-    fn fetch_instruction(&mut self, opcode: u8) {
-        type Instruction = fn(&mut Cpu6502, &mut dyn BusOps) -> CycleResult;
-        #[rustfmt::skip]
-        let instructions: [(AddressingMode,InstructionType, Instruction);256] = [
-            (AddressingMode::Immediate, InstructionType::Branching, Cpu6502::brk),         // 00
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::ora),              // 01
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 02
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 03
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::nop),               // 04
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::ora),               // 05
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::asl),    // 06
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 07
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::php),           // 08
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::ora),              // 09
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::asl), // 0A
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::anc),              // 0B
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::nop),               // 0C
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::ora),               // 0D
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::asl),    // 0E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 0F
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bpl),          // 10
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::ora),              // 11
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 12
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 13
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // 14
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::ora),              // 15
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::asl),   // 16
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 17
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::clc),            // 18
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::ora),              // 19
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // 1A
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 1B
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::nop),              // 1C
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::ora),              // 1D
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::asl),   // 1E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::slo), // 1F
-            (AddressingMode::Absolute, InstructionType::Branching, Cpu6502::jsr),          // 20
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::and),              // 21
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 22
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 23
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::bit),               // 24
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::and),               // 25
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::rol),    // 26
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 27
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::plp),            // 28
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::and),              // 29
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rol), // 2A
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::anc),            // 2B
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::bit),               // 2C
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::and),               // 2D
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::rol),    // 2E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 2F
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bmi),          // 30
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::and),              // 31
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 32
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 33
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // 34
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::and),              // 35
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::rol),   // 36
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 37
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::sec),            // 38
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::and),              // 39
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // 3A
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 3B
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::nop),              // 3C
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::and),              // 3D
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::rol),   // 3E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rla), // 3F
-            (AddressingMode::Accumulator, InstructionType::Branching, Cpu6502::rti),       // 40
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::eor),              // 41
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 42
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 43
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::nop),               // 44
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::eor),               // 45
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::lsr),    // 46
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 47
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::pha),           // 48
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::eor),              // 49
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::lsr), // 4A
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::alr),            // 4B
-            (AddressingMode::Absolute, InstructionType::Branching, Cpu6502::jmp),          // 4C
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::eor),               // 4D
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::lsr),    // 4E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 4F
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bvc),          // 50
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::eor),              // 51
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 52
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 53
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // 54
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::eor),              // 55
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::lsr),   // 56
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 57
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::cli),            // 58
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::eor),              // 59
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // 5A
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 5B
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::nop),              // 5C
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::eor),              // 5D
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::lsr),   // 5E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::sre), // 5F
-            (AddressingMode::Accumulator, InstructionType::Branching, Cpu6502::rts),       // 60
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::adc),              // 61
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 62
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 63
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::nop),               // 64
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::adc),               // 65
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::ror),    // 66
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 67
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::pla),            // 68
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::adc),              // 69
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::ror), // 6A
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::arr),            // 6B
-            (AddressingMode::AbsoluteIndirect, InstructionType::Branching, Cpu6502::jmp),  // 6C
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::adc),               // 6D
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::ror),    // 6E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 6F
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bvs),          // 70
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::adc),              // 71
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 72
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 73
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // 74
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::adc),              // 75
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::ror),   // 76
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 77
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::sei),            // 78
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::adc),              // 79
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // 7A
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 7B
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::nop),              // 7C
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::adc),              // 7D
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::ror),   // 7E
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::rra), // 7F
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::nop),              // 80
-            (AddressingMode::IndirectX, InstructionType::Write, Cpu6502::sta),             // 81
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::nop),              // 82
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::sax),           // 83
-            (AddressingMode::ZeroPage, InstructionType::Write, Cpu6502::sty),              // 84
-            (AddressingMode::ZeroPage, InstructionType::Write, Cpu6502::sta),              // 85
-            (AddressingMode::ZeroPage, InstructionType::Write, Cpu6502::stx),              // 86
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::sax),           // 87
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::dey),            // 88
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::nop),              // 89
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::txa),            // 8A
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::xaa),            // 8B
-            (AddressingMode::Absolute, InstructionType::Write, Cpu6502::sty),              // 8C
-            (AddressingMode::Absolute, InstructionType::Write, Cpu6502::sta),              // 8D
-            (AddressingMode::Absolute, InstructionType::Write, Cpu6502::stx),              // 8E
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::sax),           // 8F
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bcc),          // 90
-            (AddressingMode::IndirectY, InstructionType::Write, Cpu6502::sta),             // 91
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // 92
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::ahx),            // 93
-            (AddressingMode::ZeroPageX, InstructionType::Write, Cpu6502::sty),             // 94
-            (AddressingMode::ZeroPageX, InstructionType::Write, Cpu6502::sta),             // 95
-            (AddressingMode::ZeroPageY, InstructionType::Write, Cpu6502::stx),             // 96
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::sax),           // 97
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::tya),            // 98
-            (AddressingMode::AbsoluteY, InstructionType::Write, Cpu6502::sta),             // 99
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::txs),            // 9A
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::tas),            // 9B
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::shy),            // 9C
-            (AddressingMode::AbsoluteX, InstructionType::Write, Cpu6502::sta),             // 9D
-            (AddressingMode::Accumulator, InstructionType::Write, Cpu6502::shx),           // 9E
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::ahx),            // 9F
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::ldy),              // A0
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::lda),              // A1
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::ldx),              // A2
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // A3
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::ldy),               // A4
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::lda),               // A5
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::ldx),               // A6
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // A7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::tay),            // A8
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::lda),              // A9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::tax),            // AA
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // AB
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::ldy),               // AC
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::lda),               // AD
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::ldx),               // AE
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // AF
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bcs),          // B0
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::lda),              // B1
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // B2
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // B3
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::ldy),              // B4
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::lda),              // B5
-            (AddressingMode::ZeroPageY, InstructionType::Read, Cpu6502::ldx),              // B6
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // B7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::clv),            // B8
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::lda),              // B9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::tsx),            // BA
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::las),            // BB
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::ldy),              // BC
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::lda),              // BD
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::ldx),              // BE
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::lax),            // BF
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::cpy),              // C0
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::cmp),              // C1
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::nop),              // C2
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // C3
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::cpy),               // C4
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::cmp),               // C5
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::dec),    // C6
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // C7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::iny),            // C8
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::cmp),              // C9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::dex),            // CA
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::axs),            // CB
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::cpy),               // CC
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::cmp),               // CD
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::dec),    // CE
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // CF
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::bne),          // D0
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::cmp),              // D1
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // D2
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // D3
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // D4
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::cmp),              // D5
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::dec),   // D6
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // D7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::cld),            // D8
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::cmp),              // D9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // DA
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // DB
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::nop),              // DC
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::cmp),              // DD
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::dec),   // DE
-            (AddressingMode::Accumulator, InstructionType::ReadModifyWrite, Cpu6502::dcp), // DF
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::cpx),              // E0
-            (AddressingMode::IndirectX, InstructionType::Read, Cpu6502::sbc),              // E1
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::nop),              // E2
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // E3
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::cpx),               // E4
-            (AddressingMode::ZeroPage, InstructionType::Read, Cpu6502::sbc),               // E5
-            (AddressingMode::ZeroPage, InstructionType::ReadModifyWrite, Cpu6502::inc),    // E6
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // E7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::inx),            // E8
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::sbc),              // E9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // EA
-            (AddressingMode::Immediate, InstructionType::Read, Cpu6502::sbc),            // EB
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::cpx),               // EC
-            (AddressingMode::Absolute, InstructionType::Read, Cpu6502::sbc),               // ED
-            (AddressingMode::Absolute, InstructionType::ReadModifyWrite, Cpu6502::inc),    // EE
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // EF
-            (AddressingMode::Relative, InstructionType::Branching, Cpu6502::beq),          // F0
-            (AddressingMode::IndirectY, InstructionType::Read, Cpu6502::sbc),              // F1
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::stp),            // F2
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // F3
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::nop),              // F4
-            (AddressingMode::ZeroPageX, InstructionType::Read, Cpu6502::sbc),              // F5
-            (AddressingMode::ZeroPageX, InstructionType::ReadModifyWrite, Cpu6502::inc),   // F6
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // F7
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::sed),            // F8
-            (AddressingMode::AbsoluteY, InstructionType::Read, Cpu6502::sbc),              // F9
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::nop),            // FA
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc),            // FB
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::slo),              // FC
-            (AddressingMode::AbsoluteX, InstructionType::Read, Cpu6502::sbc),              // FD
-            (AddressingMode::AbsoluteX, InstructionType::ReadModifyWrite, Cpu6502::inc),   // FE
-            (AddressingMode::Accumulator, InstructionType::Read, Cpu6502::isc)             // FF
-        ];
+    fn execute<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        match self.operation {
+            Operation::Adc => self.adc(bus),
+            Operation::Ahx => self.ahx(bus),
+            Operation::Alr => self.alr(bus),
+            Operation::Anc => self.anc(bus),
+            Operation::And => self.and(bus),
+            Operation::Arr => self.arr(bus),
+            Operation::Asl => self.asl(bus),
+            Operation::Axs => self.axs(bus),
+            Operation::Bcc => self.bcc(bus),
+            Operation::Bcs => self.bcs(bus),
+            Operation::Beq => self.beq(bus),
+            Operation::Bit => self.bit(bus),
+            Operation::Bmi => self.bmi(bus),
+            Operation::Bne => self.bne(bus),
+            Operation::Bpl => self.bpl(bus),
+            Operation::Brk => self.brk(bus),
+            Operation::Bvc => self.bvc(bus),
+            Operation::Bvs => self.bvs(bus),
+            Operation::Clc => self.clc(bus),
+            Operation::Cld => self.cld(bus),
+            Operation::Cli => self.cli(bus),
+            Operation::Clv => self.clv(bus),
+            Operation::Cmp => self.cmp(bus),
+            Operation::Cpx => self.cpx(bus),
+            Operation::Cpy => self.cpy(bus),
+            Operation::Dcp => self.dcp(bus),
+            Operation::Dec => self.dec(bus),
+            Operation::Dex => self.dex(bus),
+            Operation::Dey => self.dey(bus),
+            Operation::Eor => self.eor(bus),
+            Operation::Inc => self.inc(bus),
+            Operation::Inx => self.inx(bus),
+            Operation::Iny => self.iny(bus),
+            Operation::Irq => self.irq(bus),
+            Operation::Isc => self.isc(bus),
+            Operation::Jmp => self.jmp(bus),
+            Operation::Jsr => self.jsr(bus),
+            Operation::Las => self.las(bus),
+            Operation::Lax => self.lax(bus),
+            Operation::Lda => self.lda(bus),
+            Operation::Ldx => self.ldx(bus),
+            Operation::Ldy => self.ldy(bus),
+            Operation::Lsr => self.lsr(bus),
+            Operation::Nmi => self.nmi(bus),
+            Operation::Nop => self.nop(bus),
+            Operation::Ora => self.ora(bus),
+            Operation::Pha => self.pha(bus),
+            Operation::Php => self.php(bus),
+            Operation::Pla => self.pla(bus),
+            Operation::Plp => self.plp(bus),
+            Operation::Rla => self.rla(bus),
+            Operation::Rol => self.rol(bus),
+            Operation::Ror => self.ror(bus),
+            Operation::Rra => self.rra(bus),
+            Operation::Rst => self.rst(bus),
+            Operation::Rti => self.rti(bus),
+            Operation::Rts => self.rts(bus),
+            Operation::Sax => self.sax(bus),
+            Operation::Sbc => self.sbc(bus),
+            Operation::Sec => self.sec(bus),
+            Operation::Sed => self.sed(bus),
+            Operation::Sei => self.sei(bus),
+            Operation::Shx => self.shx(bus),
+            Operation::Shy => self.shy(bus),
+            Operation::Slo => self.slo(bus),
+            Operation::Sre => self.sre(bus),
+            Operation::Sta => self.sta(bus),
+            Operation::Stp => self.stp(bus),
+            Operation::Stx => self.stx(bus),
+            Operation::Sty => self.sty(bus),
+            Operation::Tas => self.tas(bus),
+            Operation::Tax => self.tax(bus),
+            Operation::Tay => self.tay(bus),
+            Operation::Tsx => self.tsx(bus),
+            Operation::Txa => self.txa(bus),
+            Operation::Txs => self.txs(bus),
+            Operation::Tya => self.tya(bus),
+            Operation::Xaa => self.xaa(bus),
+        }
+    }
 
-        let (addressing_mode, instruction_type, instruction_pointer) =
-            &instructions[opcode as usize];
+    pub fn decode(opcode: u8) -> (AddressingMode, InstructionType, Operation) {
+        INSTRUCTIONS[opcode as usize]
+    }
+
+    fn fetch_instruction(&mut self, opcode: u8) {
+        let (addressing_mode, instruction_type, operation) = &INSTRUCTIONS[opcode as usize];
         self.addressing_mode = *addressing_mode;
         self.instruction_type = *instruction_type;
-        self.instruction_pointer = *instruction_pointer;
+        self.operation = *operation;
     }
 
-    fn resolve_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn resolve_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.addressing_mode {
             AddressingMode::Immediate => self.immediate_addressing(bus),
             AddressingMode::Accumulator => self.accumulator_addressing(bus),
@@ -475,7 +955,7 @@ impl Cpu6502 {
         }
     }
 
-    fn immediate_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn immediate_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.value = self.read_memory(bus, self.pc);
@@ -486,7 +966,7 @@ impl Cpu6502 {
         }
     }
 
-    fn accumulator_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn accumulator_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -496,7 +976,7 @@ impl Cpu6502 {
         }
     }
 
-    fn absolute_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn absolute_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 let address_high = self.address & 0xFF00;
@@ -543,7 +1023,7 @@ impl Cpu6502 {
         }
     }
 
-    fn absolute_indirect_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn absolute_indirect_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 let address_high = self.address & 0xFF00;
@@ -577,7 +1057,7 @@ impl Cpu6502 {
         }
     }
 
-    fn absolute_indexed_addressing(&mut self, bus: &mut dyn BusOps, index: u8) -> CycleResult {
+    fn absolute_indexed_addressing<B: BusOps>(&mut self, bus: &mut B, index: u8) -> CycleResult {
         match self.stage {
             2 => {
                 let address_high = self.address & 0xFF00;
@@ -594,21 +1074,24 @@ impl Cpu6502 {
                 CycleResult::EndCycle
             }
             4 => {
-                let address_high = self.address & 0xFF00;
-                let address_low = (self.address.wrapping_add(index as u16)) & 0xFF;
-                let address = address_high | address_low;
-                self.value = self.read_memory(bus, address);
+                let address = self.address.wrapping_add(index as u16);
+                let unfixed_address = (self.address & 0xFF00) | (address & 0xFF);
+                // Read at the unfixed address, for writes and read-modify-writes
+                // this is a dummy read that can still trigger IO side effects
+                self.value = self.read_memory(bus, unfixed_address);
+
+                // The page is crossed when the carry out of the low byte changed
+                // the high byte, e.g. 0x12FF + 1 but not 0x12FE + 1
+                let page_crossed = address != unfixed_address;
+                self.address = address;
 
                 if let InstructionType::Read = self.instruction_type {
-                    // Check for page boundary
-                    if ((self.address & 0xFF).wrapping_add(index as u16)) <= 0xFF {
+                    if !page_crossed {
                         // Boundary not crossed, continue to instruction
-                        self.address = self.address.wrapping_add(index as u16);
                         return CycleResult::Continue;
                     }
                 }
 
-                self.address = self.address.wrapping_add(index as u16);
                 CycleResult::EndCycle
             }
             5 => {
@@ -643,7 +1126,7 @@ impl Cpu6502 {
         }
     }
 
-    fn zero_page_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn zero_page_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.address = self.read_memory(bus, self.pc) as u16;
@@ -677,7 +1160,7 @@ impl Cpu6502 {
         }
     }
 
-    fn zero_page_indexed_addressing(&mut self, bus: &mut dyn BusOps, index: u8) -> CycleResult {
+    fn zero_page_indexed_addressing<B: BusOps>(&mut self, bus: &mut B, index: u8) -> CycleResult {
         match self.stage {
             2 => {
                 self.address = self.read_memory(bus, self.pc) as u16;
@@ -717,7 +1200,7 @@ impl Cpu6502 {
         }
     }
 
-    fn relative_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn relative_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.value = self.read_memory(bus, self.pc);
@@ -750,7 +1233,7 @@ impl Cpu6502 {
         }
     }
 
-    fn indirectx_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn indirectx_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.address = self.read_memory(bus, self.pc) as u16;
@@ -802,7 +1285,7 @@ impl Cpu6502 {
         }
     }
 
-    fn indirecty_addressing(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn indirecty_addressing<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.address = self.read_memory(bus, self.pc) as u16;
@@ -822,25 +1305,23 @@ impl Cpu6502 {
                 return CycleResult::EndCycle;
             }
             5 => {
-                let address_high = self.address & 0xFF00;
-                let address_low = (self.address.wrapping_add(self.y as u16)) & 0xFF;
-                let address = address_high | address_low;
-
-                self.value = self.read_memory(bus, address);
+                let address = self.address.wrapping_add(self.y as u16);
+                let unfixed_address = (self.address & 0xFF00) | (address & 0xFF);
+                // Read at the unfixed address, for writes and read-modify-writes
+                // this is a dummy read that can still trigger IO side effects
+                self.value = self.read_memory(bus, unfixed_address);
 
-                let new_address = self.address.wrapping_add(self.y as u16);
-                // Check for page boundary
+                // Same carry out of the low byte check as absolute indexed
+                let page_crossed = address != unfixed_address;
+                self.address = address;
 
                 if let InstructionType::Read = self.instruction_type {
-                    // Check for page boundary
-                    if ((self.address & 0xFF) + self.y as u16) <= 0xFF {
+                    if !page_crossed {
                         // Boundary not crossed, continue to instruction
-                        self.address = new_address;
                         return CycleResult::Continue;
                     }
                 }
 
-                self.address = new_address;
                 return CycleResult::EndCycle;
             }
             6 => {
@@ -873,7 +1354,7 @@ impl Cpu6502 {
         }
     }
 
-    fn irq(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn irq<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -893,57 +1374,34 @@ impl Cpu6502 {
             }
             5 => {
                 let mut flags_data = self.sr;
-                flags_data |= !Flags::BFlag1.to_int();
+                flags_data |= Flags::BFlag1.to_int();
                 flags_data &= !Flags::BFlag2.to_int();
                 self.write_stack(bus, flags_data);
                 self.sp = self.sp.wrapping_sub(1);
                 self.set_flag(Flags::InterruptDisable, true);
+                self.address = self.hijack_vector(IRQ_VECTOR);
                 CycleResult::EndCycle
             }
-            6 => {
-                let address_high = self.pc & 0xFF00;
-                let address_low = self.read_memory(bus, 0xFFFE) as u16;
-
-                self.pc = address_high | address_low;
-                CycleResult::EndCycle
-            }
-            7 => {
-                let address_high = (self.read_memory(bus, 0xFFFF) as u16) << 8;
-                let address_low = self.pc & 0xFF;
-
-                self.pc = address_high | address_low;
-                CycleResult::EndInstruction
-            }
+            6..=7 => self.fetch_vector(bus, self.address),
             _ => CycleResult::Error,
         }
     }
 
-    fn rst(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn rst<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             1 => CycleResult::EndCycle,
             2 => CycleResult::EndCycle,
-            3 => CycleResult::EndCycle,
-            4 => CycleResult::EndCycle,
-            5 => CycleResult::EndCycle,
-            6 => {
-                let address_high = self.pc & 0xFF00;
-                let address_low = self.read_memory(bus, 0xFFFC);
-
-                self.pc = address_high | address_low as u16;
+            3..=5 => {
+                // Stack pushes with the writes suppressed
+                self.sp = self.sp.wrapping_sub(1);
                 CycleResult::EndCycle
             }
-            7 => {
-                let address_high = (self.read_memory(bus, 0xFFFD) as u16) << 8;
-                let address_low = self.pc & 0xFF;
-
-                self.pc = address_high | address_low;
-                CycleResult::EndInstruction
-            }
+            6..=7 => self.fetch_vector(bus, RESET_VECTOR),
             _ => CycleResult::Error,
         }
     }
 
-    fn brk(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn brk<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -967,25 +1425,15 @@ impl Cpu6502 {
                 self.write_stack(bus, stack_value);
                 self.sp = self.sp.wrapping_sub(1);
                 self.set_flag(Flags::InterruptDisable, true);
+                self.address = self.hijack_vector(IRQ_VECTOR);
                 CycleResult::EndCycle
             }
-            6 => {
-                let address_high = self.pc & 0xFF00;
-                let address_low = self.read_memory(bus, 0xFFFE) as u16;
-                self.pc = address_high | address_low;
-                CycleResult::EndCycle
-            }
-            7 => {
-                let address_high = (self.read_memory(bus, 0xFFFF) as u16) << 8;
-                let address_low = self.pc & 0xFF;
-                self.pc = address_high | address_low;
-                CycleResult::EndInstruction
-            }
+            6..=7 => self.fetch_vector(bus, self.address),
             _ => CycleResult::Error,
         }
     }
 
-    fn nmi(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn nmi<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1010,23 +1458,12 @@ impl Cpu6502 {
                 self.set_flag(Flags::InterruptDisable, true);
                 CycleResult::EndCycle
             }
-            6 => {
-                let address_high = self.pc & 0xFF00;
-                let address_low = self.read_memory(bus, 0xFFFA);
-                self.pc = address_high | address_low as u16;
-                CycleResult::EndCycle
-            }
-            7 => {
-                let address_high = (self.read_memory(bus, 0xFFFB) as u16) << 8;
-                let address_low = self.pc & 0xFF;
-                self.pc = address_high | address_low;
-                CycleResult::EndInstruction
-            }
+            6..=7 => self.fetch_vector(bus, NMI_VECTOR),
             _ => CycleResult::Error,
         }
     }
 
-    fn rti(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn rti<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1060,7 +1497,7 @@ impl Cpu6502 {
         }
     }
 
-    fn rts(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn rts<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1091,7 +1528,7 @@ impl Cpu6502 {
         }
     }
 
-    fn pha(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn pha<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1106,7 +1543,7 @@ impl Cpu6502 {
         }
     }
 
-    fn php(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn php<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1124,7 +1561,7 @@ impl Cpu6502 {
         }
     }
 
-    fn pla(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn pla<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1144,7 +1581,7 @@ impl Cpu6502 {
         }
     }
 
-    fn plp(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn plp<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 self.read_memory(bus, self.pc);
@@ -1164,7 +1601,7 @@ impl Cpu6502 {
         }
     }
 
-    fn jsr(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn jsr<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         match self.stage {
             2 => {
                 let address_high = self.address & 0xFF00;
@@ -1197,29 +1634,92 @@ impl Cpu6502 {
         }
     }
 
-    fn adc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn adc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
-                let carry: u8 = if self.is_flag_set(Flags::Carry) { 1 } else { 0 };
-                let (added_value, overflow_add) = self.a.overflowing_add(self.value);
-                let (added_carry, overflow_carry) = added_value.overflowing_add(carry);
-                let same_signal = (self.a & 0x80) == (self.value & 0x80);
-                let sign = self.value & 0x80;
-                let total_value = (self.a as u16) + (self.value as u16) + (carry as u16);
-                let new_carry = (total_value & 0x100) != 0;
-                self.set_flag(Flags::Carry, new_carry);
-                self.a = added_carry;
-                self.set_flag(Flags::Zero, self.a == 0);
-                self.set_flag(Flags::Overflow, same_signal && (sign != (self.a & 0x80)));
-                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+                self.add_to_accumulator(self.value);
                 CycleResult::EndInstruction
             }
             _ => result,
         }
     }
 
-    fn and(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    // ADC core, shared with the illegal opcodes that end with an add
+    fn add_to_accumulator(&mut self, value: u8) {
+        if self.decimal_enabled && self.is_flag_set(Flags::Decimal) {
+            self.add_decimal(value);
+        } else {
+            self.add_with_carry(value);
+        }
+    }
+
+    // SBC core, shared with the illegal opcodes that end with a subtract
+    fn subtract_from_accumulator(&mut self, value: u8) {
+        let a = self.a;
+        let borrow: i16 = if self.is_flag_set(Flags::Carry) { 0 } else { 1 };
+        // A - M - !C is A + !M + C
+        self.add_with_carry(!value);
+
+        // NMOS parts set every flag from the binary result, only the
+        // accumulator gets the decimal adjust
+        if self.decimal_enabled && self.is_flag_set(Flags::Decimal) {
+            let mut low = (a & 0x0F) as i16 - (value & 0x0F) as i16 - borrow;
+            let mut high = (a >> 4) as i16 - (value >> 4) as i16;
+            if low < 0 {
+                low -= 6;
+                high -= 1;
+            }
+            if high < 0 {
+                high -= 6;
+            }
+            self.a = (((high << 4) | (low & 0x0F)) & 0xFF) as u8;
+        }
+    }
+
+    fn add_decimal(&mut self, value: u8) {
+        let carry: u16 = if self.is_flag_set(Flags::Carry) { 1 } else { 0 };
+        let binary = (self.a as u16 + value as u16 + carry) as u8;
+
+        let mut low = (self.a & 0x0F) as u16 + (value & 0x0F) as u16 + carry;
+        if low > 0x09 {
+            low += 0x06;
+        }
+        let mut high = (self.a >> 4) as u16 + (value >> 4) as u16 + (low > 0x0F) as u16;
+
+        // Zero comes from the binary sum, negative and overflow from the sum
+        // after only the low nibble was adjusted
+        let partial = ((high << 4) | (low & 0x0F)) as u8;
+        self.set_flag(Flags::Zero, binary == 0);
+        self.set_flag(Flags::Negative, (partial & 0x80) != 0);
+        self.set_flag(
+            Flags::Overflow,
+            ((self.a ^ partial) & 0x80) != 0 && ((self.a ^ value) & 0x80) == 0,
+        );
+
+        if high > 0x09 {
+            high += 0x06;
+        }
+        self.set_flag(Flags::Carry, high > 0x0F);
+        self.a = (((high << 4) | (low & 0x0F)) & 0xFF) as u8;
+    }
+
+    fn add_with_carry(&mut self, value: u8) {
+        let carry: u8 = if self.is_flag_set(Flags::Carry) { 1 } else { 0 };
+        let (added_value, overflow_add) = self.a.overflowing_add(value);
+        let (added_carry, overflow_carry) = added_value.overflowing_add(carry);
+        let same_signal = (self.a & 0x80) == (value & 0x80);
+        let sign = value & 0x80;
+        let total_value = (self.a as u16) + (value as u16) + (carry as u16);
+        let new_carry = (total_value & 0x100) != 0;
+        self.set_flag(Flags::Carry, new_carry);
+        self.a = added_carry;
+        self.set_flag(Flags::Zero, self.a == 0);
+        self.set_flag(Flags::Overflow, same_signal && (sign != (self.a & 0x80)));
+        self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+    }
+
+    fn and<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1233,7 +1733,7 @@ impl Cpu6502 {
         }
     }
 
-    fn asl(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn asl<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1263,7 +1763,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bcc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bcc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1277,7 +1777,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bcs(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bcs<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1291,7 +1791,7 @@ impl Cpu6502 {
         }
     }
 
-    fn beq(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn beq<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1305,7 +1805,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bit(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bit<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1320,7 +1820,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bmi(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bmi<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1334,7 +1834,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bne(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bne<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1348,7 +1848,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bpl(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bpl<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1362,7 +1862,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bvc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bvc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1376,7 +1876,7 @@ impl Cpu6502 {
         }
     }
 
-    fn bvs(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn bvs<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1390,27 +1890,27 @@ impl Cpu6502 {
         }
     }
 
-    fn clc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn clc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::Carry, false);
         CycleResult::EndInstruction
     }
 
-    fn cld(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn cld<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::Decimal, false);
         CycleResult::EndInstruction
     }
 
-    fn cli(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn cli<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::InterruptDisable, false);
         CycleResult::EndInstruction
     }
 
-    fn clv(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn clv<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::Overflow, false);
         CycleResult::EndInstruction
     }
 
-    fn cmp(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn cmp<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1424,7 +1924,7 @@ impl Cpu6502 {
         }
     }
 
-    fn cpx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn cpx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1438,7 +1938,7 @@ impl Cpu6502 {
         }
     }
 
-    fn cpy(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn cpy<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1452,7 +1952,7 @@ impl Cpu6502 {
         }
     }
 
-    fn dec(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn dec<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1466,7 +1966,7 @@ impl Cpu6502 {
         }
     }
 
-    fn dex(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn dex<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.x = self.x.wrapping_sub(1);
 
         self.set_flag(Flags::Zero, self.x == 0);
@@ -1475,7 +1975,7 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn dey(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn dey<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.y = self.y.wrapping_sub(1);
 
         self.set_flag(Flags::Zero, self.y == 0);
@@ -1484,7 +1984,7 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn eor(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn eor<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1498,7 +1998,7 @@ impl Cpu6502 {
         }
     }
 
-    fn inc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn inc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1512,7 +2012,7 @@ impl Cpu6502 {
         }
     }
 
-    fn inx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn inx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.x = self.x.wrapping_add(1);
 
         self.set_flag(Flags::Zero, self.x == 0);
@@ -1521,7 +2021,7 @@ impl Cpu6502 {
         return CycleResult::EndInstruction;
     }
 
-    fn iny(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn iny<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.y = self.y.wrapping_add(1);
 
         self.set_flag(Flags::Zero, self.y == 0);
@@ -1530,7 +2030,7 @@ impl Cpu6502 {
         return CycleResult::EndInstruction;
     }
 
-    fn jmp(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn jmp<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1542,7 +2042,7 @@ impl Cpu6502 {
         }
     }
 
-    fn lda(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn lda<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1556,7 +2056,7 @@ impl Cpu6502 {
         }
     }
 
-    fn ldx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn ldx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1570,7 +2070,7 @@ impl Cpu6502 {
         }
     }
 
-    fn ldy(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn ldy<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1584,7 +2084,7 @@ impl Cpu6502 {
         }
     }
 
-    fn lsr(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn lsr<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1614,7 +2114,7 @@ impl Cpu6502 {
         }
     }
 
-    fn nop(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn nop<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => CycleResult::EndInstruction,
@@ -1622,7 +2122,7 @@ impl Cpu6502 {
         }
     }
 
-    fn ora(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn ora<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1637,7 +2137,7 @@ impl Cpu6502 {
         }
     }
 
-    fn rol(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn rol<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1671,7 +2171,7 @@ impl Cpu6502 {
         }
     }
 
-    fn ror(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn ror<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1703,45 +2203,33 @@ impl Cpu6502 {
         }
     }
 
-    fn sbc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sbc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
-                let not_value = !self.value;
-                let carry: u8 = if self.is_flag_set(Flags::Carry) { 1 } else { 0 };
-                let (added_not_value, overflow_add) = self.a.overflowing_add(not_value);
-                let (added_carry, overflow_carry) = added_not_value.overflowing_add(carry);
-                let signal = not_value;
-                let same_signal = (self.a & 0x80) == (signal & 0x80);
-                let sign = self.a & 0x80;
-                let total_value = (self.a as u16) + (not_value as u16) + (carry as u16);
-                self.set_flag(Flags::Carry, (total_value & 0x100) != 0);
-                self.a = added_carry;
-                self.set_flag(Flags::Zero, self.a == 0);
-                self.set_flag(Flags::Overflow, same_signal && (sign != (self.a & 0x80)));
-                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+                self.subtract_from_accumulator(self.value);
                 return CycleResult::EndInstruction;
             }
             _ => result,
         }
     }
 
-    fn sec(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sec<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::Carry, true);
         CycleResult::EndInstruction
     }
 
-    fn sed(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sed<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::Decimal, true);
         CycleResult::EndInstruction
     }
 
-    fn sei(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sei<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.set_flag(Flags::InterruptDisable, true);
         CycleResult::EndInstruction
     }
 
-    fn sta(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sta<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1752,7 +2240,7 @@ impl Cpu6502 {
         }
     }
 
-    fn stx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn stx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1763,7 +2251,7 @@ impl Cpu6502 {
         }
     }
 
-    fn sty(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sty<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         let result = self.resolve_addressing(bus);
         match result {
             CycleResult::Continue => {
@@ -1774,7 +2262,7 @@ impl Cpu6502 {
         }
     }
 
-    fn tax(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn tax<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.x = self.a;
 
         self.set_flag(Flags::Zero, self.x == 0);
@@ -1783,7 +2271,7 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn tay(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn tay<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.y = self.a;
 
         self.set_flag(Flags::Zero, self.y == 0);
@@ -1792,7 +2280,7 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn tsx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn tsx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.x = self.sp;
 
         self.set_flag(Flags::Zero, self.x == 0);
@@ -1801,7 +2289,7 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn txa(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn txa<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.a = self.x;
 
         self.set_flag(Flags::Zero, self.a == 0);
@@ -1810,12 +2298,12 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn txs(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn txs<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.sp = self.x;
         CycleResult::EndInstruction
     }
 
-    fn tya(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn tya<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         self.a = self.y;
 
         self.set_flag(Flags::Zero, self.a == 0);
@@ -1824,61 +2312,664 @@ impl Cpu6502 {
         CycleResult::EndInstruction
     }
 
-    fn stp(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn stp<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        CycleResult::Halted
     }
-    fn anc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn anc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn slo(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn slo<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn rla(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn rla<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // ROL the operand then AND it into the accumulator
+                let carry_set = self.is_flag_set(Flags::Carry);
+                let old_value = self.value;
+                self.value <<= 1;
+                self.value |= if carry_set { 1 } else { 0 };
+                self.write_memory(bus, self.address, self.value);
+
+                self.a &= self.value;
+                self.set_flag(Flags::Carry, (old_value & 0x80) != 0);
+                self.set_flag(Flags::Zero, self.a == 0);
+                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn sre(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sre<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn alr(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn alr<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // AND the operand then LSR the accumulator
+                self.a &= self.value;
+                self.set_flag(Flags::Carry, (self.a & 0x01) != 0);
+                self.a >>= 1;
+
+                self.set_flag(Flags::Zero, self.a == 0);
+                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn rra(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn rra<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // ROR the operand, the bit rotated out is the carry used by the ADC
+                let carry_set = self.is_flag_set(Flags::Carry);
+                let old_value = self.value;
+                self.value >>= 1;
+                self.value |= if carry_set { 0x80 } else { 0 };
+                self.write_memory(bus, self.address, self.value);
+
+                self.set_flag(Flags::Carry, (old_value & 0x01) != 0);
+                self.add_to_accumulator(self.value);
+
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn arr(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn arr<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // AND the operand then ROR the accumulator
+                let carry_set = self.is_flag_set(Flags::Carry);
+                self.a &= self.value;
+                self.a >>= 1;
+                self.a |= if carry_set { 0x80 } else { 0 };
+
+                // The rotate goes through the adder, so the flags don't come
+                // from the bit shifted out: carry is bit 6 of the result and
+                // overflow is bit 6 xor bit 5 (what bit 7 xor bit 6 of the
+                // operand was before the rotate)
+                let bit6 = (self.a & 0x40) != 0;
+                let bit5 = (self.a & 0x20) != 0;
+                self.set_flag(Flags::Carry, bit6);
+                self.set_flag(Flags::Overflow, bit6 != bit5);
+                self.set_flag(Flags::Zero, self.a == 0);
+                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn sax(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn sax<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn xaa(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn xaa<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn ahx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn ahx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn tas(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn tas<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn shy(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn shy<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn shx(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn shx<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn lax(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn lax<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // The immediate form (0xAB) is unstable on hardware, this is
+                // the common behavior where A is ORed with 0xFF first
+                self.a = self.value;
+                self.x = self.value;
+
+                self.set_flag(Flags::Zero, self.a == 0);
+                self.set_flag(Flags::Negative, (self.a & 0x80) != 0);
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn las(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn las<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn dcp(&mut self, bus: &mut dyn BusOps) -> CycleResult {
+    fn dcp<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
         CycleResult::Error
     }
-    fn isc(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn isc<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // INC the operand then SBC it from the accumulator
+                self.value = self.value.wrapping_add(1);
+                self.write_memory(bus, self.address, self.value);
+
+                self.subtract_from_accumulator(self.value);
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
     }
-    fn axs(&mut self, bus: &mut dyn BusOps) -> CycleResult {
-        CycleResult::Error
+    fn axs<B: BusOps>(&mut self, bus: &mut B) -> CycleResult {
+        let result = self.resolve_addressing(bus);
+        match result {
+            CycleResult::Continue => {
+                // Compares A AND X with the operand like CMP, but keeps the
+                // difference in X, the carry is not used as a borrow
+                let and = self.a & self.x;
+                self.x = and.wrapping_sub(self.value);
+                self.set_flag(Flags::Carry, and >= self.value);
+                self.set_flag(Flags::Zero, self.x == 0);
+                self.set_flag(Flags::Negative, (self.x & 0x80) != 0);
+                CycleResult::EndInstruction
+            }
+            _ => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[derive(Debug, PartialEq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    struct TestBus {
+        memory: Vec<u8>,
+        reads: Vec<u16>,
+        writes: Vec<(u16, u8)>,
+        // Reads and writes interleaved in bus order
+        accesses: Vec<Access>,
+    }
+
+    impl BusOps for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            self.accesses.push(Access::Read(address));
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.writes.push((address, data));
+            self.accesses.push(Access::Write(address, data));
+            self.memory[address as usize] = data;
+        }
+    }
+
+    impl PeekOps for TestBus {
+        fn peek(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+    }
+
+    // Program at $8000, already through the reset sequence
+    fn cpu_with_program(program: &[u8]) -> (Cpu6502, TestBus) {
+        let mut bus = TestBus {
+            memory: vec![0; 0x10000],
+            reads: Vec::new(),
+            writes: Vec::new(),
+            accesses: Vec::new(),
+        };
+        bus.memory[0x8000..0x8000 + program.len()].copy_from_slice(program);
+        bus.memory[RESET_VECTOR as usize] = 0x00;
+        bus.memory[RESET_VECTOR as usize + 1] = 0x80;
+
+        let mut cpu = Cpu6502::new();
+        cpu.power_on();
+        cpu.step_instruction(&mut bus);
+        bus.reads.clear();
+        bus.writes.clear();
+        bus.accesses.clear();
+        (cpu, bus)
+    }
+
+    #[test]
+    fn read_modify_write_writes_the_old_value_first() {
+        // INC $10, ASL $0300,X
+        let (mut cpu, mut bus) = cpu_with_program(&[0xE6, 0x10, 0x1E, 0x00, 0x03]);
+        bus.memory[0x10] = 0x41;
+        bus.memory[0x0302] = 0x81;
+        cpu.x = 2;
+
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(bus.writes, vec![(0x10, 0x41), (0x10, 0x42)]);
+
+        bus.writes.clear();
+        assert_eq!(cpu.step_instruction(&mut bus), 7);
+        assert_eq!(bus.writes, vec![(0x0302, 0x81), (0x0302, 0x02)]);
+        assert!(cpu.is_flag_set(Flags::Carry));
+    }
+
+    // BRK at $8000, IRQ/BRK handler at $9000 and NMI handler at $A000
+    fn cpu_at_brk() -> (Cpu6502, TestBus) {
+        let (cpu, mut bus) = cpu_with_program(&[0x00]);
+        bus.memory[IRQ_VECTOR as usize + 1] = 0x90;
+        bus.memory[NMI_VECTOR as usize + 1] = 0xA0;
+        bus.memory[0x9000] = 0xEA;
+        bus.memory[0xA000] = 0xEA;
+        (cpu, bus)
+    }
+
+    #[test]
+    fn nmi_hijacks_the_brk_vector_fetch() {
+        let (mut cpu, mut bus) = cpu_at_brk();
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.pc, 0x9000);
+
+        // NMI raised while BRK pushes to the stack
+        let (mut cpu, mut bus) = cpu_at_brk();
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        cpu.set_nmi();
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(cpu.pc, 0xA000);
+        // The pushed flags still tell a BRK happened
+        assert_eq!(bus.memory[0x01FB] & 0x10, 0x10);
+
+        // The NMI was taken by the hijack, the handler runs next
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.pc, 0xA001);
+    }
+
+    #[test]
+    fn reset_decrements_sp_by_three_without_writing() {
+        let (mut cpu, mut bus) = cpu_with_program(&[0xEA]);
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.pc, 0x8000);
+
+        // The three dummy pushes wrap around the stack page
+        for &(before, after) in [(0x80, 0x7D), (0x00, 0xFD), (0x01, 0xFE), (0x02, 0xFF)].iter() {
+            cpu.sp = before;
+            cpu.reset();
+            assert_eq!(cpu.step_instruction(&mut bus), 7);
+            assert_eq!(cpu.sp, after);
+            assert_eq!(cpu.pc, 0x8000);
+        }
+        assert!(bus.writes.is_empty());
+    }
+
+    #[test]
+    fn rra_rotates_into_the_add_carry() {
+        // RRA $10
+        let (mut cpu, mut bus) = cpu_with_program(&[0x67, 0x10]);
+        bus.memory[0x10] = 0x03;
+        cpu.a = 0x10;
+
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(bus.writes, vec![(0x10, 0x03), (0x10, 0x01)]);
+        // 0x10 + 0x01 plus the bit rotated out
+        assert_eq!(cpu.a, 0x12);
+        assert!(!cpu.is_flag_set(Flags::Carry));
+    }
+
+    #[test]
+    fn rla_rotates_into_the_and() {
+        // RLA $10
+        let (mut cpu, mut bus) = cpu_with_program(&[0x27, 0x10]);
+        bus.memory[0x10] = 0xC0;
+        cpu.a = 0xF0;
+        cpu.set_flag(Flags::Carry, false);
+
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        // Bit 7 goes into carry, the old carry into bit 0
+        assert_eq!(bus.writes, vec![(0x10, 0xC0), (0x10, 0x80)]);
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.is_flag_set(Flags::Carry));
+        assert!(cpu.is_flag_set(Flags::Negative));
+        assert!(!cpu.is_flag_set(Flags::Zero));
+    }
+
+    #[test]
+    fn lax_loads_a_and_x() {
+        // LAX $10, LAX $03F0,Y
+        let (mut cpu, mut bus) = cpu_with_program(&[0xA7, 0x10, 0xBF, 0xF0, 0x03]);
+        bus.memory[0x10] = 0x80;
+        bus.memory[0x0400] = 0x00;
+        cpu.y = 0x10;
+
+        assert_eq!(cpu.step_instruction(&mut bus), 3);
+        assert_eq!((cpu.a, cpu.x), (0x80, 0x80));
+        assert!(cpu.is_flag_set(Flags::Negative));
+
+        // The page cross costs a cycle like any other read
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!((cpu.a, cpu.x), (0x00, 0x00));
+        assert!(cpu.is_flag_set(Flags::Zero));
+    }
+
+    #[test]
+    fn isc_increments_then_subtracts() {
+        // SEC, ISC $10
+        let (mut cpu, mut bus) = cpu_with_program(&[0x38, 0xE7, 0x10]);
+        bus.memory[0x10] = 0x0F;
+        cpu.a = 0x20;
+        cpu.step_instruction(&mut bus);
+
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(bus.writes, vec![(0x10, 0x0F), (0x10, 0x10)]);
+        assert_eq!(cpu.a, 0x10);
+        assert!(cpu.is_flag_set(Flags::Carry));
+    }
+
+    #[test]
+    fn alr_ands_then_shifts_right() {
+        // ALR #$03
+        let (mut cpu, mut bus) = cpu_with_program(&[0x4B, 0x03]);
+        cpu.a = 0xFF;
+
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.is_flag_set(Flags::Carry));
+        assert!(!cpu.is_flag_set(Flags::Negative));
+    }
+
+    #[test]
+    fn arr_flags_come_from_bits_6_and_5() {
+        // ARR #$FF, SEC, ARR #$FF
+        let (mut cpu, mut bus) = cpu_with_program(&[0x6B, 0xFF, 0x38, 0x6B, 0xFF]);
+        cpu.a = 0xC0;
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(cpu.a, 0x60);
+        assert!(cpu.is_flag_set(Flags::Carry));
+        assert!(!cpu.is_flag_set(Flags::Overflow));
+
+        cpu.a = 0x80;
+        cpu.step_instruction(&mut bus);
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.a, 0xC0);
+        assert!(cpu.is_flag_set(Flags::Carry));
+        assert!(cpu.is_flag_set(Flags::Overflow));
+        assert!(cpu.is_flag_set(Flags::Negative));
+    }
+
+    #[test]
+    fn axs_subtracts_from_a_and_x_without_borrow() {
+        // AXS #$02, AXS #$05
+        let (mut cpu, mut bus) = cpu_with_program(&[0xCB, 0x02, 0xCB, 0x05]);
+        cpu.a = 0x0F;
+        cpu.x = 0xF3;
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(cpu.x, 0x01);
+        assert_eq!(cpu.a, 0x0F);
+        assert!(cpu.is_flag_set(Flags::Carry));
+
+        cpu.x = 0xF3;
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.x, 0xFE);
+        assert!(!cpu.is_flag_set(Flags::Carry));
+        assert!(cpu.is_flag_set(Flags::Negative));
+    }
+
+    #[test]
+    fn irq_line_is_taken_while_held_and_unmasked() {
+        // NOP, CLI, NOP with the IRQ handler at $9000
+        let (mut cpu, mut bus) = cpu_with_program(&[0xEA, 0x58, 0xEA]);
+        bus.memory[IRQ_VECTOR as usize + 1] = 0x90;
+        bus.memory[0x9000] = 0xEA;
+        cpu.set_irq(true);
+
+        // Masked since reset
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.pc, 0x8001);
+
+        cpu.step_instruction(&mut bus);
+        while cpu.pc < 0x9000 {
+            assert!(cpu.pc < 0x8004);
+            cpu.step_instruction(&mut bus);
+        }
+        // Pushed with B clear, and the handler isn't interrupted again while
+        // the line stays asserted
+        assert_eq!(bus.memory[0x0100 + cpu.sp as usize + 1] & 0x30, 0x20);
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.pc, 0x9001);
+    }
+
+    #[test]
+    fn decimal_mode_only_applies_when_enabled() {
+        // SED, ADC #$15, SEC, SBC #$15
+        let program = [0xF8, 0x69, 0x15, 0x38, 0xE9, 0x15];
+        let (mut cpu, mut bus) = cpu_with_program(&program);
+        cpu.decimal_enabled = true;
+        cpu.a = 0x27;
+        cpu.step_instruction(&mut bus);
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.a, 0x42);
+        cpu.step_instruction(&mut bus);
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.a, 0x27);
+
+        // The 2A03 ignores the D flag
+        let (mut cpu, mut bus) = cpu_with_program(&program);
+        cpu.a = 0x27;
+        cpu.step_instruction(&mut bus);
+        cpu.step_instruction(&mut bus);
+        assert_eq!(cpu.a, 0x3C);
+    }
+
+    #[test]
+    fn restored_cpu_resumes_mid_instruction() {
+        // INC $10, LDX #$07
+        let (mut cpu, mut bus) = cpu_with_program(&[0xE6, 0x10, 0xA2, 0x07]);
+        bus.memory[0x10] = 0x41;
+        cpu.tick(&mut bus);
+        cpu.tick(&mut bus);
+        let state = cpu.to_bytes();
+        assert_eq!(state.len(), STATE_SIZE);
+
+        let mut restored = Cpu6502::new();
+        restored.from_bytes(&state).unwrap();
+        assert_eq!(restored.step_instruction(&mut bus), 3);
+        assert_eq!(bus.memory[0x10], 0x42);
+        restored.step_instruction(&mut bus);
+        assert_eq!(restored.x, 0x07);
+        assert_eq!(restored.cycles, cpu.cycles + 5);
+
+        assert!(restored.from_bytes(&state[1..]).is_err());
+    }
+
+    #[test]
+    fn registers_snapshot_after_each_step() {
+        // LDX #$07, TXS, INY
+        let (mut cpu, mut bus) = cpu_with_program(&[0xA2, 0x07, 0x9A, 0xC8]);
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(cpu.step_instruction(&mut bus), 2);
+        assert_eq!(
+            cpu.registers(),
+            Registers {
+                a: 0x00,
+                x: 0x07,
+                y: 0x01,
+                pc: 0x8004,
+                sp: 0x07,
+                sr: 0x24,
+            }
+        );
+    }
+
+    #[test]
+    fn indirect_indexed_reads_the_unfixed_address_on_page_cross() {
+        // LDA ($10),Y twice
+        let (mut cpu, mut bus) = cpu_with_program(&[0xB1, 0x10, 0xB1, 0x10]);
+        bus.memory[0x10] = 0xF0;
+        bus.memory[0x11] = 0x03;
+        bus.memory[0x0300] = 0x11;
+        bus.memory[0x0400] = 0x22;
+        bus.memory[0x03F8] = 0x33;
+
+        cpu.y = 0x10;
+        assert_eq!(cpu.step_instruction(&mut bus), 6);
+        assert_eq!(bus.reads[4..], [0x0300, 0x0400][..]);
+        assert_eq!(cpu.a, 0x22);
+
+        bus.reads.clear();
+        cpu.y = 0x08;
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(bus.reads[4..], [0x03F8][..]);
+        assert_eq!(cpu.a, 0x33);
+    }
+
+    #[test]
+    fn absolute_x_store_reads_the_unfixed_address_before_writing() {
+        // STA $12FF,X
+        let (mut cpu, mut bus) = cpu_with_program(&[0x9D, 0xFF, 0x12]);
+        cpu.a = 0x42;
+        cpu.x = 0x01;
+
+        assert_eq!(cpu.step_instruction(&mut bus), 5);
+        assert_eq!(
+            bus.accesses,
+            vec![
+                Access::Read(0x8000),
+                Access::Read(0x8001),
+                Access::Read(0x8002),
+                Access::Read(0x1200),
+                Access::Write(0x1300, 0x42),
+            ]
+        );
+    }
+
+    #[test]
+    fn absolute_x_page_cross_at_the_end_of_the_page() {
+        // (base, X, cycles, effective address)
+        let cases = [
+            (0x12FE, 0x01, 4, 0x12FF),
+            (0x12FE, 0x02, 5, 0x1300),
+            (0x12FE, 0xFF, 5, 0x13FD),
+            (0x12FF, 0x00, 4, 0x12FF),
+            (0x12FF, 0x01, 5, 0x1300),
+            (0x12FF, 0xFF, 5, 0x13FE),
+        ];
+        for &(base, index, cycles, address) in cases.iter() {
+            // LDA base,X
+            let (mut cpu, mut bus) =
+                cpu_with_program(&[0xBD, (base & 0xFF) as u8, (base >> 8) as u8]);
+            bus.memory[address as usize] = 0x5A;
+            cpu.x = index;
+
+            assert_eq!(cpu.step_instruction(&mut bus), cycles, "{:#06X},X", base);
+            let mut reads = vec![0x8000, 0x8001, 0x8002, address];
+            if cycles == 5 {
+                // Dummy read with the high byte not fixed up yet
+                reads.insert(3, (base & 0xFF00) | (address & 0xFF));
+            }
+            assert_eq!(bus.reads, reads);
+            assert_eq!(cpu.a, 0x5A);
+        }
+    }
+
+    #[test]
+    fn read_vector_reads_the_little_endian_handler_address() {
+        let (_, mut bus) = cpu_with_program(&[]);
+        bus.memory[0xFFFA..0x10000].copy_from_slice(&[0x34, 0x12, 0x78, 0x56, 0xBC, 0x9A]);
+
+        assert_eq!(Cpu6502::read_vector(&mut bus, NMI_VECTOR), 0x1234);
+        assert_eq!(Cpu6502::read_vector(&mut bus, RESET_VECTOR), 0x5678);
+        assert_eq!(Cpu6502::read_vector(&mut bus, IRQ_VECTOR), 0x9ABC);
+        assert_eq!(
+            bus.reads,
+            vec![0xFFFA, 0xFFFB, 0xFFFC, 0xFFFD, 0xFFFE, 0xFFFF]
+        );
+    }
+
+    #[cfg(all(test, feature = "test-access"))]
+    #[test]
+    fn single_stage_runs_from_a_set_up_state() {
+        // LDA $10, with the opcode already fetched
+        let (mut cpu, mut bus) = cpu_with_program(&[0xA5, 0x10]);
+        bus.memory[0x10] = 0x42;
+        cpu.set_opcode(0xA5);
+        cpu.pc = 0x8001;
+        cpu.stage = 2;
+        assert_eq!(cpu.addressing_mode(), AddressingMode::ZeroPage);
+        assert_eq!(cpu.instruction_type(), InstructionType::Read);
+
+        // Operand fetch
+        assert!(matches!(cpu.tick(&mut bus), CycleResult::EndCycle));
+        assert_eq!(cpu.stage, 3);
+        assert_eq!(cpu.pc, 0x8002);
+        assert_eq!(bus.reads, vec![0x8001]);
+
+        // Read from the zero page address and the load itself
+        assert!(matches!(cpu.tick(&mut bus), CycleResult::EndInstruction));
+        assert_eq!(cpu.stage, 1);
+        assert_eq!(cpu.a, 0x42);
+
+        // Turned into a read-modify-write, stage 3 now takes a cycle of its own
+        cpu.set_opcode(0xA5);
+        cpu.set_instruction_type(InstructionType::ReadModifyWrite);
+        cpu.stage = 3;
+        assert!(matches!(cpu.tick(&mut bus), CycleResult::EndCycle));
+        assert_eq!(cpu.stage, 4);
+    }
+
+    // Goes through a vtable for every access, like the CPU did when it took
+    // a &mut dyn BusOps
+    trait DynBus: BusOps + PeekOps {}
+
+    impl DynBus for TestBus {}
+
+    struct DynamicBus<'a>(&'a mut dyn DynBus);
+
+    impl<'a> BusOps for DynamicBus<'a> {
+        fn read(&mut self, address: u16) -> u8 {
+            self.0.read(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.0.write(address, data)
+        }
+    }
+
+    impl<'a> PeekOps for DynamicBus<'a> {
+        fn peek(&self, address: u16) -> u8 {
+            self.0.peek(address)
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture bus_dispatch_timing
+    #[test]
+    #[ignore]
+    fn bus_dispatch_timing() {
+        // INC $10, LDA $0200,X, STA $0300,Y, INX, DEY, JMP $8000
+        let program = [
+            0xE6, 0x10, 0xBD, 0x00, 0x02, 0x99, 0x00, 0x03, 0xE8, 0x88, 0x4C, 0x00, 0x80,
+        ];
+        let cycles = 64 * 1024 * 1024;
+
+        let (mut cpu, mut bus) = cpu_with_program(&program);
+        let start = Instant::now();
+        for _ in 0..cycles {
+            cpu.tick(&mut bus);
+        }
+        let static_time = start.elapsed();
+        let static_registers = cpu.registers();
+
+        let (mut cpu, mut bus) = cpu_with_program(&program);
+        let start = Instant::now();
+        for _ in 0..cycles {
+            cpu.tick(&mut DynamicBus(&mut bus));
+        }
+        let dynamic_time = start.elapsed();
+
+        assert_eq!(cpu.registers(), static_registers);
+        println!(
+            "{} CPU cycles: static dispatch {:?}, dynamic dispatch {:?}",
+            cycles, static_time, dynamic_time
+        );
     }
 }
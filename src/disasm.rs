@@ -0,0 +1,271 @@
+use crate::bus::PeekOps;
+use crate::cpu::{AddressingMode, Cpu6502, InstructionType, Operation};
+
+// Bytes taken by the opcode and its operand
+pub fn instruction_length(opcode: u8) -> usize {
+    let (addressing_mode, _, operation) = Cpu6502::decode(opcode);
+    match addressing_mode {
+        // BRK skips a padding byte but it is not part of the instruction
+        _ if operation == Operation::Brk => 1,
+        AddressingMode::Accumulator => 1,
+        AddressingMode::Immediate
+        | AddressingMode::ZeroPage
+        | AddressingMode::ZeroPageX
+        | AddressingMode::ZeroPageY
+        | AddressingMode::IndirectX
+        | AddressingMode::IndirectY
+        | AddressingMode::Relative => 2,
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteX
+        | AddressingMode::AbsoluteY
+        | AddressingMode::AbsoluteIndirect => 3,
+    }
+}
+
+// Everything outside the documented instruction set, the extra NOPs and the
+// SBC copy at $EB included
+pub fn is_unofficial(opcode: u8) -> bool {
+    match Cpu6502::decode(opcode).2 {
+        Operation::Nop => opcode != 0xEA,
+        Operation::Sbc => opcode == 0xEB,
+        Operation::Slo
+        | Operation::Rla
+        | Operation::Sre
+        | Operation::Rra
+        | Operation::Sax
+        | Operation::Lax
+        | Operation::Dcp
+        | Operation::Isc
+        | Operation::Anc
+        | Operation::Alr
+        | Operation::Arr
+        | Operation::Xaa
+        | Operation::Axs
+        | Operation::Ahx
+        | Operation::Tas
+        | Operation::Shy
+        | Operation::Shx
+        | Operation::Las
+        | Operation::Stp => true,
+        _ => false,
+    }
+}
+
+fn mnemonic(operation: Operation) -> String {
+    format!("{:?}", operation).to_uppercase()
+}
+
+// Decodes the instruction at the start of the slice, missing operand bytes
+// read as zero. Without the address of the instruction branch targets are
+// shown relative to it, as in BNE *+4
+pub fn disassemble(bytes: &[u8]) -> (String, usize) {
+    let opcode = *bytes.first().unwrap_or(&0);
+    let length = instruction_length(opcode);
+    (format_instruction(None, bytes), length)
+}
+
+pub fn disassemble_at(bus: &dyn PeekOps, pc: u16) -> (String, usize) {
+    let length = instruction_length(bus.peek(pc));
+    let bytes: Vec<u8> = (0..length)
+        .map(|offset| bus.peek(pc.wrapping_add(offset as u16)))
+        .collect();
+    (format_instruction(Some(pc), &bytes), length)
+}
+
+fn format_instruction(pc: Option<u16>, bytes: &[u8]) -> String {
+    let (addressing_mode, instruction_type, operation) =
+        Cpu6502::decode(*bytes.first().unwrap_or(&0));
+    let name = mnemonic(operation);
+    let byte = *bytes.get(1).unwrap_or(&0);
+    let word = byte as u16 | (*bytes.get(2).unwrap_or(&0) as u16) << 8;
+
+    match addressing_mode {
+        _ if operation == Operation::Brk => name,
+        AddressingMode::Accumulator => match instruction_type {
+            InstructionType::ReadModifyWrite => format!("{} A", name),
+            _ => name,
+        },
+        AddressingMode::Immediate => format!("{} #${:02X}", name, byte),
+        AddressingMode::ZeroPage => format!("{} ${:02X}", name, byte),
+        AddressingMode::ZeroPageX => format!("{} ${:02X},X", name, byte),
+        AddressingMode::ZeroPageY => format!("{} ${:02X},Y", name, byte),
+        AddressingMode::Absolute => format!("{} ${:04X}", name, word),
+        AddressingMode::AbsoluteX => format!("{} ${:04X},X", name, word),
+        AddressingMode::AbsoluteY => format!("{} ${:04X},Y", name, word),
+        AddressingMode::AbsoluteIndirect => format!("{} (${:04X})", name, word),
+        AddressingMode::IndirectX => format!("{} (${:02X},X)", name, byte),
+        AddressingMode::IndirectY => format!("{} (${:02X}),Y", name, byte),
+        AddressingMode::Relative => {
+            let offset = 2 + byte as i8 as i16;
+            match pc {
+                Some(pc) => format!("{} ${:04X}", name, pc.wrapping_add(offset as u16)),
+                None if offset < 0 => format!("{} *-{}", name, -offset),
+                None => format!("{} *+{}", name, offset),
+            }
+        }
+    }
+}
+
+// One line of nestest.log for the instruction at PC, memory operands are
+// annotated with the value they currently hold. The PPU column is left out,
+// the CPU has no view of the dot position
+pub fn trace_line(cpu: &Cpu6502, bus: &dyn PeekOps) -> String {
+    let opcode = bus.peek(cpu.pc);
+    let bytes: Vec<u8> = (0..instruction_length(opcode))
+        .map(|offset| bus.peek(cpu.pc.wrapping_add(offset as u16)))
+        .collect();
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+
+    format!(
+        "{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+        cpu.pc,
+        hex.join(" "),
+        if is_unofficial(opcode) { '*' } else { ' ' },
+        annotated(cpu, bus, &bytes),
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.sr,
+        cpu.sp,
+        cpu.cycles()
+    )
+}
+
+fn annotated(cpu: &Cpu6502, bus: &dyn PeekOps, bytes: &[u8]) -> String {
+    let (addressing_mode, instruction_type, operation) = Cpu6502::decode(bytes[0]);
+    let name = match operation {
+        // nestest.log spells ISC the other common way
+        Operation::Isc => String::from("ISB"),
+        _ => mnemonic(operation),
+    };
+    let byte = *bytes.get(1).unwrap_or(&0);
+    let word = byte as u16 | (*bytes.get(2).unwrap_or(&0) as u16) << 8;
+    // The pointer high byte is fetched without carrying into the page
+    let pointer = |address: u16| {
+        let high = (address & 0xFF00) | (address.wrapping_add(1) & 0x00FF);
+        bus.peek(address) as u16 | (bus.peek(high) as u16) << 8
+    };
+
+    match addressing_mode {
+        _ if operation == Operation::Brk => name,
+        AddressingMode::Accumulator => match instruction_type {
+            InstructionType::ReadModifyWrite => format!("{} A", name),
+            _ => name,
+        },
+        AddressingMode::Immediate => format!("{} #${:02X}", name, byte),
+        AddressingMode::ZeroPage => {
+            format!("{} ${:02X} = {:02X}", name, byte, bus.peek(byte as u16))
+        }
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let (register, index) = match addressing_mode {
+                AddressingMode::ZeroPageX => ('X', cpu.x),
+                _ => ('Y', cpu.y),
+            };
+            let address = byte.wrapping_add(index);
+            format!(
+                "{} ${:02X},{} @ {:02X} = {:02X}",
+                name,
+                byte,
+                register,
+                address,
+                bus.peek(address as u16)
+            )
+        }
+        AddressingMode::Absolute => match operation {
+            Operation::Jmp | Operation::Jsr => format!("{} ${:04X}", name, word),
+            _ => format!("{} ${:04X} = {:02X}", name, word, bus.peek(word)),
+        },
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let (register, index) = match addressing_mode {
+                AddressingMode::AbsoluteX => ('X', cpu.x),
+                _ => ('Y', cpu.y),
+            };
+            let address = word.wrapping_add(index as u16);
+            format!(
+                "{} ${:04X},{} @ {:04X} = {:02X}",
+                name,
+                word,
+                register,
+                address,
+                bus.peek(address)
+            )
+        }
+        AddressingMode::AbsoluteIndirect => {
+            format!("{} (${:04X}) = {:04X}", name, word, pointer(word))
+        }
+        AddressingMode::IndirectX => {
+            let zero_page = byte.wrapping_add(cpu.x);
+            let address = pointer(zero_page as u16);
+            format!(
+                "{} (${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                name,
+                byte,
+                zero_page,
+                address,
+                bus.peek(address)
+            )
+        }
+        AddressingMode::IndirectY => {
+            let base = pointer(byte as u16);
+            let address = base.wrapping_add(cpu.y as u16);
+            format!(
+                "{} (${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                name,
+                byte,
+                base,
+                address,
+                bus.peek(address)
+            )
+        }
+        AddressingMode::Relative => {
+            let target = cpu.pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("{} ${:04X}", name, target)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Memory(Vec<u8>);
+
+    impl PeekOps for Memory {
+        fn peek(&self, address: u16) -> u8 {
+            self.0[address as usize]
+        }
+    }
+
+    // 64KB address space holding the given bytes at $8000
+    fn memory(bytes: &[u8]) -> Memory {
+        let mut memory = vec![0; 0x10000];
+        memory[0x8000..0x8000 + bytes.len()].copy_from_slice(bytes);
+        Memory(memory)
+    }
+
+    #[test]
+    fn disassemble_formats_each_addressing_mode() {
+        assert_eq!(disassemble(&[0x0A]), (String::from("ASL A"), 1));
+        assert_eq!(disassemble(&[0xA9, 0x10]), (String::from("LDA #$10"), 2));
+        assert_eq!(
+            disassemble(&[0xBD, 0x00, 0x03]),
+            (String::from("LDA $0300,X"), 3)
+        );
+        assert_eq!(disassemble(&[0xB1, 0x20]), (String::from("LDA ($20),Y"), 2));
+        assert_eq!(disassemble(&[0x6C, 0xFF]), (String::from("JMP ($00FF)"), 3));
+        assert_eq!(disassemble(&[0xE7, 0x10]), (String::from("ISC $10"), 2));
+        assert_eq!(disassemble(&[0x00, 0xFF]), (String::from("BRK"), 1));
+    }
+
+    #[test]
+    fn branch_targets_need_the_instruction_address() {
+        assert_eq!(disassemble(&[0xD0, 0x02]).0, "BNE *+4");
+        assert_eq!(disassemble(&[0xD0, 0xFC]).0, "BNE *-2");
+
+        let memory = memory(&[0xEA, 0xD0, 0xFC]);
+        assert_eq!(
+            disassemble_at(&memory, 0x8001),
+            (String::from("BNE $7FFF"), 2)
+        );
+    }
+}
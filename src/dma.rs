@@ -1,37 +1,117 @@
-use crate::cpu::BusOps;
+use crate::bus::BusOps;
+use crate::error::Result;
+use crate::savestate::StateReader;
 
 pub struct Dma {
     requested: bool,
+    // The first cycle of a transfer only halts the CPU
+    halt_pending: bool,
     page: u8,
     value: u8,
     progress: usize,
+    // DMC sample fetch, takes over the bus before any OAM transfer
+    dmc_address: u16,
+    dmc_stall: u8,
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Dma::new()
+    }
 }
 
 impl Dma {
     pub fn new() -> Self {
         Dma {
             requested: false,
+            halt_pending: false,
             page: 0,
             value: 0,
             progress: 0,
+            dmc_address: 0,
+            dmc_stall: 0,
         }
     }
 
+    pub fn power_on(&mut self) {
+        *self = Dma::new();
+    }
+
+    // A transfer in progress is dropped
+    pub fn reset(&mut self) {
+        self.requested = false;
+        self.halt_pending = false;
+        self.progress = 0;
+        self.dmc_stall = 0;
+    }
+
+    // The CPU is halted for 4 cycles, the sample byte is read on the last one
+    pub fn request_dmc(&mut self, address: u16) {
+        self.dmc_address = address;
+        self.dmc_stall = 4;
+    }
+
     pub fn cpu_write(&mut self, address: u16, data: u8) {
         if address == 0x4014 {
             self.requested = true;
+            self.halt_pending = true;
             self.page = data;
             self.progress = 0;
         }
     }
 
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.requested as u8,
+            self.halt_pending as u8,
+            self.page,
+            self.value,
+            self.dmc_stall,
+        ];
+        state.extend_from_slice(&(self.progress as u16).to_le_bytes());
+        state.extend_from_slice(&self.dmc_address.to_le_bytes());
+        state
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(state);
+        self.requested = reader.bool()?;
+        self.halt_pending = reader.bool()?;
+        self.page = reader.u8()?;
+        self.value = reader.u8()?;
+        self.dmc_stall = reader.u8()?.min(4);
+        self.progress = reader.u16()? as usize % 512;
+        self.dmc_address = reader.u16()?;
+        reader.finish()
+    }
+
     pub fn active(&self) -> bool {
-        self.requested
+        self.requested || self.dmc_stall > 0
     }
 
-    pub fn execute(&mut self, bus: &mut dyn BusOps) {
-        if self.progress == 0 {
-            bus.write(0x2003, 0);
+    // Called instead of the CPU for every CPU cycle while the transfer is
+    // active. The DMA reads on get (even) cycles and writes on put (odd)
+    // cycles. After the halt cycle, starting on a put cycle costs one extra
+    // cycle to align, so a transfer takes 513 or 514 cycles. Returns the DMC
+    // sample byte on the cycle it is fetched
+    pub fn execute(&mut self, bus: &mut dyn BusOps, cycle: u64) -> Option<u8> {
+        if self.dmc_stall > 0 {
+            self.dmc_stall -= 1;
+            if self.dmc_stall == 0 {
+                return Some(bus.read(self.dmc_address));
+            }
+            return None;
+        }
+
+        if self.halt_pending {
+            self.halt_pending = false;
+            return None;
+        }
+
+        // OAMADDR isn't touched, the writes start wherever it points
+        let get_cycle = cycle & 1 == 0;
+        if self.progress == 0 && !get_cycle {
+            return None;
         }
 
         if self.progress & 1 != 0 {
@@ -48,5 +128,54 @@ impl Dma {
             self.requested = false;
             self.progress = 0;
         }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every address reads back its low byte, the writes are recorded
+    struct TestBus {
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl BusOps for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            address as u8
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.writes.push((address, data));
+        }
+    }
+
+    // Cycles taken by a transfer requested right before the given cycle
+    fn transfer_cycles(first_cycle: u64) -> (u64, TestBus) {
+        let mut dma = Dma::new();
+        let mut bus = TestBus { writes: Vec::new() };
+        dma.cpu_write(0x4014, 0x02);
+        let mut cycle = first_cycle;
+        while dma.active() {
+            dma.execute(&mut bus, cycle);
+            cycle += 1;
+        }
+        (cycle - first_cycle, bus)
+    }
+
+    #[test]
+    fn oam_transfer_halts_then_aligns_to_a_get_cycle() {
+        let (cycles, bus) = transfer_cycles(1);
+        assert_eq!(cycles, 513);
+        // Straight into OAMDATA, OAMADDR is left alone
+        assert_eq!(bus.writes.len(), 256);
+        assert!(bus
+            .writes
+            .iter()
+            .enumerate()
+            .all(|(index, write)| *write == (0x2004, index as u8)));
+
+        assert_eq!(transfer_cycles(2).0, 514);
     }
 }
@@ -0,0 +1,77 @@
+use crate::mapper;
+use std::{error, fmt, io};
+
+#[derive(Debug)]
+pub enum NesError {
+    Io(io::Error),
+    InvalidHeader,
+    NoData,
+    TooMuchData,
+    TruncatedData,
+    UnsupportedMapper(u16),
+    UnsupportedBoard(String),
+    RoutineTimeout(u16),
+    InvalidState,
+    StateVersion(u32),
+    StateRegion(u8),
+    StateRomMismatch,
+    SramSize(usize),
+    InvalidConfig(String),
+    InvalidMovie,
+}
+
+pub type Result<T> = std::result::Result<T, NesError>;
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NesError::Io(error) => write!(f, "{}", error),
+            NesError::InvalidHeader => write!(f, "Invalid nes file"),
+            NesError::NoData => write!(f, "No data in nes file"),
+            NesError::TooMuchData => write!(f, "Too much data"),
+            NesError::TruncatedData => write!(f, "Failed to read nes data"),
+            NesError::UnsupportedMapper(number) => {
+                let supported: Vec<String> = mapper::supported_mappers()
+                    .iter()
+                    .map(|number| number.to_string())
+                    .collect();
+                write!(
+                    f,
+                    "Unsupported mapper {}, supported mappers: {}",
+                    number,
+                    supported.join(", ")
+                )
+            }
+            NesError::UnsupportedBoard(board) => write!(f, "Unsupported UNIF board {}", board),
+            NesError::InvalidState => write!(f, "Invalid save state"),
+            NesError::StateVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            NesError::StateRegion(region) => write!(f, "Save state is for region {}", region),
+            NesError::StateRomMismatch => write!(f, "Save state was made with a different ROM"),
+            NesError::InvalidMovie => write!(f, "Invalid movie file"),
+            NesError::InvalidConfig(message) => write!(f, "Invalid controls config, {}", message),
+            NesError::SramSize(size) => {
+                write!(f, "Battery save has the wrong size ({} bytes)", size)
+            }
+            NesError::RoutineTimeout(address) => {
+                write!(f, "Routine at {:#06X} did not return", address)
+            }
+        }
+    }
+}
+
+impl error::Error for NesError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NesError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NesError {
+    fn from(error: io::Error) -> Self {
+        NesError::Io(error)
+    }
+}
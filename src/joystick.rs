@@ -1,9 +1,59 @@
+use crate::error::Result;
+use crate::savestate::StateReader;
+
+#[derive(Copy, Clone)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // Bit of the button in a state byte, the byte is shifted out from bit 7
+    // so the buttons come out in the A, B, Select, Start, Up, Down, Left, Right
+    // read order
+    fn to_int(self) -> u8 {
+        match self {
+            Button::A => 1 << 7,
+            Button::B => 1 << 6,
+            Button::Select => 1 << 5,
+            Button::Start => 1 << 4,
+            Button::Up => 1 << 3,
+            Button::Down => 1 << 2,
+            Button::Left => 1 << 1,
+            Button::Right => 1 << 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputMode {
+    // Controller 2 stays unplugged
+    Controller1Only,
+    BothControllers,
+    // Controller 2 reads whatever is pressed on controller 1
+    Mirror,
+}
+
 pub struct Joystick {
     strobe: u8,
     index1: u8,
     index2: u8,
     jd1: u8,
     jd2: u8,
+    dmc_conflicts: bool,
+    input_mode: InputMode,
+}
+
+impl Default for Joystick {
+    fn default() -> Self {
+        Joystick::new()
+    }
 }
 
 impl Joystick {
@@ -14,91 +64,290 @@ impl Joystick {
             index2: 0,
             jd1: 0,
             jd2: 0,
+            dmc_conflicts: true,
+            input_mode: InputMode::BothControllers,
         }
     }
+    pub fn power_on(&mut self) {
+        let dmc_conflicts = self.dmc_conflicts;
+        let input_mode = self.input_mode;
+        *self = Joystick::new();
+        self.dmc_conflicts = dmc_conflicts;
+        self.input_mode = input_mode;
+    }
+
+    // The shift registers restart, buttons held down stay pressed
+    pub fn reset(&mut self) {
+        self.strobe = 0;
+        self.index1 = 0;
+        self.index2 = 0;
+    }
+
+    // Only the serial read position, the buttons follow the live input
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![self.strobe, self.index1, self.index2]
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(state);
+        self.strobe = reader.u8()? & 1;
+        self.index1 = reader.u8()? & 7;
+        self.index2 = reader.u8()? & 7;
+        reader.finish()
+    }
+
     pub fn cpu_write(&mut self, address: u16, data: u8) {
+        // While the strobe is high the shift registers keep reloading, the
+        // shifting only starts once it goes back low
         if address == 0x4016 {
             self.strobe = data & 1;
-            self.index1 = 0;
-            self.index2 = 0;
+            if self.strobe == 1 {
+                self.index1 = 0;
+                self.index2 = 0;
+            }
         }
     }
 
     pub fn cpu_read(&mut self, address: u16) -> u8 {
+        // With the strobe held high the index stays put and every read returns
+        // the A button
         if address == 0x4016 {
             let data = self.jd1 & (0x80 >> self.index1);
-            self.index1 += 1;
+            self.index1 += 1 - self.strobe;
             if self.index1 == 8 {
                 self.index1 = 0;
             }
             return if data != 0 { 1 } else { 0 };
         }
 
-        // TODO handle joystick 2
+        if address == 0x4017 {
+            let jd2 = match self.input_mode {
+                InputMode::Controller1Only => 0,
+                InputMode::BothControllers => self.jd2,
+                InputMode::Mirror => self.jd1,
+            };
+            let data = jd2 & (0x80 >> self.index2);
+            self.index2 += 1 - self.strobe;
+            if self.index2 == 8 {
+                self.index2 = 0;
+            }
+            return if data != 0 { 1 } else { 0 };
+        }
+
         return 0;
     }
 
+    // Next bit a read would shift out, the shift indexes are left alone
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        let data = match address {
+            0x4016 => self.jd1 & (0x80 >> self.index1),
+            0x4017 => {
+                let jd2 = match self.input_mode {
+                    InputMode::Controller1Only => 0,
+                    InputMode::BothControllers => self.jd2,
+                    InputMode::Mirror => self.jd1,
+                };
+                jd2 & (0x80 >> self.index2)
+            }
+            _ => 0,
+        };
+        if data != 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn input_mode(&self) -> InputMode {
+        self.input_mode
+    }
+
+    pub fn set_input_mode(&mut self, input_mode: InputMode) {
+        self.input_mode = input_mode;
+    }
+
+    // Port 0 is controller 1 and port 1 is controller 2
+    pub fn press(&mut self, port: usize, button: Button) {
+        match port {
+            0 => self.jd1 |= button.to_int(),
+            _ => self.jd2 |= button.to_int(),
+        }
+    }
+
+    pub fn release(&mut self, port: usize, button: Button) {
+        match port {
+            0 => self.jd1 &= !button.to_int(),
+            _ => self.jd2 &= !button.to_int(),
+        }
+    }
+
+    // Sets every button of a player at once from a state byte, for replays and
+    // netplay, see Button::to_int for the bit of each button
+    pub fn set_state(&mut self, player: u8, buttons: u8) {
+        match player {
+            0 => self.jd1 = buttons,
+            _ => self.jd2 = buttons,
+        }
+    }
+
+    pub fn state(&self, player: u8) -> u8 {
+        match player {
+            0 => self.jd1,
+            _ => self.jd2,
+        }
+    }
+
+    // Turning this off gives the "fixed" behavior where DMC fetches never
+    // corrupt controller reads
+    pub fn set_dmc_conflicts(&mut self, enabled: bool) {
+        self.dmc_conflicts = enabled;
+    }
+
+    // A DMC DMA that halts the CPU on a controller read repeats the read, the
+    // extra one clocks the shift register and the game loses a bit
+    pub fn dmc_conflict(&mut self, address: u16) {
+        if self.dmc_conflicts {
+            self.cpu_read(address);
+        }
+    }
+
     pub fn press_start(&mut self) {
-        self.jd1 |= 1 << 4;
+        self.press(0, Button::Start);
     }
 
     pub fn press_select(&mut self) {
-        self.jd1 |= 1 << 5;
+        self.press(0, Button::Select);
     }
 
     pub fn press_up(&mut self) {
-        self.jd1 |= 1 << 3;
+        self.press(0, Button::Up);
     }
 
     pub fn press_down(&mut self) {
-        self.jd1 |= 1 << 2;
+        self.press(0, Button::Down);
     }
 
     pub fn press_left(&mut self) {
-        self.jd1 |= 1 << 1;
+        self.press(0, Button::Left);
     }
 
     pub fn press_right(&mut self) {
-        self.jd1 |= 1 << 0;
+        self.press(0, Button::Right);
     }
 
     pub fn press_a(&mut self) {
-        self.jd1 |= 1 << 7;
+        self.press(0, Button::A);
     }
 
     pub fn press_b(&mut self) {
-        self.jd1 |= 1 << 6;
+        self.press(0, Button::B);
     }
 
     pub fn release_start(&mut self) {
-        self.jd1 &= !(1 << 4);
+        self.release(0, Button::Start);
     }
 
     pub fn release_select(&mut self) {
-        self.jd1 &= !(1 << 5);
+        self.release(0, Button::Select);
     }
 
     pub fn release_up(&mut self) {
-        self.jd1 &= !(1 << 3);
+        self.release(0, Button::Up);
     }
 
     pub fn release_down(&mut self) {
-        self.jd1 &= !(1 << 2);
+        self.release(0, Button::Down);
     }
 
     pub fn release_left(&mut self) {
-        self.jd1 &= !(1 << 1);
+        self.release(0, Button::Left);
     }
 
     pub fn release_right(&mut self) {
-        self.jd1 &= !(1 << 0);
+        self.release(0, Button::Right);
     }
 
     pub fn release_a(&mut self) {
-        self.jd1 &= !(1 << 7);
+        self.release(0, Button::A);
     }
 
     pub fn release_b(&mut self) {
-        self.jd1 &= !(1 << 6);
+        self.release(0, Button::B);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strobes the controllers and shifts out the 8 buttons of a port
+    fn read_port(joystick: &mut Joystick, address: u16) -> Vec<u8> {
+        joystick.cpu_write(0x4016, 1);
+        joystick.cpu_write(0x4016, 0);
+        (0..8).map(|_| joystick.cpu_read(address)).collect()
+    }
+
+    #[test]
+    fn set_state_reads_back_in_button_order() {
+        let mut joystick = Joystick::new();
+        // A, Start and Right
+        joystick.set_state(0, 0x91);
+        joystick.set_state(1, Button::B.to_int());
+        assert_eq!(
+            read_port(&mut joystick, 0x4016),
+            vec![1, 0, 0, 1, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            read_port(&mut joystick, 0x4017),
+            vec![0, 1, 0, 0, 0, 0, 0, 0]
+        );
+
+        // Single buttons change on top of the byte
+        joystick.release(0, Button::A);
+        joystick.press(0, Button::Up);
+        assert_eq!(
+            read_port(&mut joystick, 0x4016),
+            vec![0, 0, 0, 1, 1, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn held_strobe_keeps_returning_the_a_button() {
+        let mut joystick = Joystick::new();
+        joystick.set_state(0, Button::A.to_int() | Button::Right.to_int());
+        joystick.cpu_write(0x4016, 1);
+        for _ in 0..10 {
+            assert_eq!(joystick.cpu_read(0x4016), 1);
+        }
+
+        // A write of 0 doesn't rewind, only the strobe going high does
+        joystick.cpu_write(0x4016, 0);
+        assert_eq!(joystick.cpu_read(0x4016), 1);
+        assert_eq!(joystick.cpu_read(0x4016), 0);
+        joystick.cpu_write(0x4016, 0);
+        assert_eq!(joystick.cpu_read(0x4016), 0);
+        assert_eq!(read_port(&mut joystick, 0x4016)[7], 1);
+    }
+
+    #[test]
+    fn input_mode_decides_what_port_2_reads() {
+        let mut joystick = Joystick::new();
+        joystick.press(0, Button::A);
+        joystick.press(0, Button::Left);
+        joystick.press(1, Button::Start);
+        let player1 = vec![1, 0, 0, 0, 0, 0, 1, 0];
+
+        joystick.set_input_mode(InputMode::Mirror);
+        assert_eq!(read_port(&mut joystick, 0x4016), player1);
+        assert_eq!(read_port(&mut joystick, 0x4017), player1);
+        assert_eq!(joystick.cpu_peek(0x4017), 1);
+
+        joystick.set_input_mode(InputMode::BothControllers);
+        assert_eq!(
+            read_port(&mut joystick, 0x4017),
+            vec![0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        joystick.set_input_mode(InputMode::Controller1Only);
+        assert_eq!(read_port(&mut joystick, 0x4017), vec![0; 8]);
     }
 }
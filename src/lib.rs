@@ -0,0 +1,22 @@
+// Emulator core, free of any frontend so it can run headless or behind a
+// different window system. Nes ties the components together
+pub mod apu;
+pub mod bus;
+pub mod coverage;
+pub mod cpu;
+pub mod disasm;
+pub mod dma;
+pub mod error;
+pub mod joystick;
+pub mod mapper;
+pub mod memory;
+pub mod movie;
+pub mod nes;
+pub mod nsf;
+pub mod png;
+pub mod ppu;
+pub mod rewind;
+pub mod savestate;
+pub mod scheduler;
+
+pub use nes::Nes;
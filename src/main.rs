@@ -2,271 +2,410 @@ use anyhow::Result;
 use anyhow::*;
 
 use sfml::{
-    graphics::{Color, RenderTarget, RenderWindow, Sprite, Texture, Transformable},
+    graphics::{
+        Color, FloatRect, Font, RenderTarget, RenderWindow, Sprite, Text, Texture, Transformable,
+        View,
+    },
     window::{Event, Key, Style},
 };
-use std::path::Path;
+use std::{
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-mod cpu;
-mod dma;
-mod joystick;
-mod mapper;
-mod memory;
-mod ppu;
+mod controls;
+mod overlay;
 
-impl<'a> ppu::BusOps for mapper::Mapper {
-    fn write(&mut self, address: u16, data: u8) {
-        self.ppu_write(address, data);
-    }
+use nesrust::{coverage, joystick, mapper, movie, nes, png, rewind, scheduler};
 
-    fn read(&mut self, address: u16) -> u8 {
-        self.ppu_read(address)
-    }
-}
+// Common monospace fonts, the first one found is used for the stats overlay
+const OVERLAY_FONTS: [&str; 4] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+    "/Library/Fonts/Courier New.ttf",
+    "C:\\Windows\\Fonts\\consola.ttf",
+];
 
-struct CpuBus<'a> {
-    mapper: &'a mut mapper::Mapper,
-    memory: &'a mut memory::Memory,
-    ppu: &'a mut ppu::Ppu,
-    dma: &'a mut dma::Dma,
-    joystick: &'a mut joystick::Joystick,
-}
+// Key bindings, looked up in the working directory
+const CONTROLS_PATH: &str = "controls.toml";
 
-impl<'a> CpuBus<'a> {
-    fn new(
-        mapper: &'a mut mapper::Mapper,
-        memory: &'a mut memory::Memory,
-        ppu: &'a mut ppu::Ppu,
-        dma: &'a mut dma::Dma,
-        joystick: &'a mut joystick::Joystick,
-    ) -> Self {
-        CpuBus {
-            mapper,
-            memory,
-            ppu,
-            dma,
-            joystick,
-        }
-    }
+// Movie recorded or played back by the frame loop
+enum MovieMode {
+    Off,
+    Recording(movie::Movie),
+    // Next frame to play
+    Playing(movie::Movie, usize),
 }
 
-impl<'a> cpu::BusOps for CpuBus<'a> {
-    fn write(&mut self, address: u16, data: u8) {
-        self.mapper.cpu_write(address, data);
-        self.memory.cpu_write(address, data);
-        self.joystick.cpu_write(address, data);
-        self.dma.cpu_write(address, data);
-        self.ppu.cpu_write(self.mapper, address, data);
-    }
-
-    fn read(&mut self, address: u16) -> u8 {
-        self.mapper.cpu_read(address)
-            | self.memory.cpu_read(address)
-            | self.joystick.cpu_read(address)
-            | self.ppu.cpu_read(self.mapper, address)
-    }
+// Toggles flipped by the hotkeys
+struct UiState {
+    show_stats: bool,
+    // Emulation is stopped but events and drawing keep going
+    paused: bool,
+    // Set by the frame advance key, runs a single frame while paused
+    advance_frame: bool,
 }
 
-struct DmaBus<'a> {
-    mapper: &'a mut mapper::Mapper,
-    memory: &'a mut memory::Memory,
-    ppu: &'a mut ppu::Ppu,
-    joystick: &'a mut joystick::Joystick,
-}
+const WIDTH: usize = 256;
+const HEIGHT: usize = 240;
 
-impl<'a> DmaBus<'a> {
-    fn new(
-        mapper: &'a mut mapper::Mapper,
-        memory: &'a mut memory::Memory,
-        ppu: &'a mut ppu::Ppu,
-        joystick: &'a mut joystick::Joystick,
-    ) -> Self {
-        DmaBus {
-            mapper,
-            memory,
-            ppu,
-            joystick,
-        }
-    }
-}
+// About one minute of emulated time per ROM
+const COVERAGE_FRAMES: usize = 3600;
 
-impl<'a> cpu::BusOps for DmaBus<'a> {
-    fn write(&mut self, address: u16, data: u8) {
-        self.mapper.cpu_write(address, data);
-        self.memory.cpu_write(address, data);
-        self.joystick.cpu_write(address, data);
-        self.ppu.cpu_write(self.mapper, address, data);
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        return Err(anyhow!(
+            "Usage: <bin> <path> [--scale N] [input polls per frame] | \
+             <bin> --coverage <path>... | \
+             <bin> --headless <path> <frames> [framebuffer output]"
+        ));
     }
 
-    fn read(&mut self, address: u16) -> u8 {
-        self.mapper.cpu_read(address)
-            | self.memory.cpu_read(address)
-            | self.joystick.cpu_read(address)
-            | self.ppu.cpu_read(self.mapper, address)
+    if args[1] == "--coverage" {
+        let mut coverage = coverage::Coverage::new();
+        coverage.run_roms(&args[2..], COVERAGE_FRAMES, &mut std::io::stdout())?;
+        print!("{}", coverage.report());
+        return Ok(());
     }
-}
 
-fn exec_frame(
-    cpu: &mut cpu::Cpu6502,
-    ppu: &mut ppu::Ppu,
-    mapper: &mut mapper::Mapper,
-    memory: &mut memory::Memory,
-    dma: &mut dma::Dma,
-    joystick: &mut joystick::Joystick,
-    tick_offset: &mut usize,
-) {
-    loop {
-        *tick_offset += 1;
-        if ppu.nmi_state {
-            ppu.nmi_state = false;
-            cpu.set_nmi();
+    // No window, prints a hash of the last frame and optionally dumps it as
+    // raw RGBA for golden image comparisons
+    if args[1] == "--headless" {
+        if args.len() < 4 {
+            return Err(anyhow!("--headless needs a ROM path and a frame count"));
         }
-
-        if *tick_offset == 3 {
-            *tick_offset = 0;
-
-            if dma.active() {
-                let mut bus = DmaBus::new(mapper, memory, ppu, joystick);
-                dma.execute(&mut bus);
-            } else {
-                let result = {
-                    let mut bus = CpuBus::new(mapper, memory, ppu, dma, joystick);
-                    cpu.tick(&mut bus)
-                };
-
-                match result {
-                    cpu::CycleResult::Error => {
-                        panic!("Error stage: {:#02X}", cpu.stage);
-                    }
-                    _ => {}
-                }
-            }
+        let mut nes = nes::Nes::new(mapper::Mapper::new());
+        nes.load_rom(Path::new(&args[2]))?;
+        nes.run_frames(args[3].parse::<usize>()?);
+        if let Some(output) = args.get(4) {
+            std::fs::write(output, nes.framebuffer())?;
         }
+        println!("{:016X}", nes.ppu.framebuffer_hash());
+        return Ok(());
+    }
 
-        ppu.tick(mapper);
-        if ppu.fetch_frame() {
-            break;
+    // --scale N sizes the window to N times the NES resolution, without it
+    // the frame is drawn at 2x in an 800x600 window
+    let mut scale = None;
+    let mut positional = Vec::new();
+    let mut options = args[2..].iter();
+    while let Some(arg) = options.next() {
+        if arg == "--scale" {
+            let value = options
+                .next()
+                .ok_or_else(|| anyhow!("--scale needs a value"))?;
+            scale = Some(value.parse::<u32>()?.max(1));
+        } else {
+            positional.push(arg);
         }
     }
-}
 
-fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        return Err(anyhow!("Usage: <bin> <path>"));
-    }
+    // Input is normally read once per frame, polling it several times per
+    // frame lets a press near the end of a frame reach the game sooner at the
+    // cost of more event handling per frame. There's nothing left to split
+    // past one poll per CPU cycle
+    let input_polls = match positional.first() {
+        Some(polls) => polls.parse::<u64>()?.clamp(1, nes::CPU_CYCLES_PER_FRAME),
+        None => 1,
+    };
 
     let path = Path::new(&args[1]);
-    let mut mapper = mapper::Mapper::new();
-    mapper.load(path)?;
-
-    let mut cpu = cpu::Cpu6502::new();
-    cpu.pc = 0xC000;
-    cpu.sp = 0xFD;
-    cpu.sr = 0x24;
-    cpu.reset();
+    let mut nes = nes::Nes::new(mapper::Mapper::new());
+    nes.load_rom(path)?;
 
-    let mut ppu = ppu::Ppu::new();
-    let mut memory = memory::Memory::new();
-    let mut dma = dma::Dma::new();
-    let mut joystick = joystick::Joystick::new();
-    let mut tick_offset: usize = 0;
+    // Battery backed PRG-RAM lives in a .sav file next to the ROM
+    let sav_path = path.with_extension("sav");
+    if nes.mapper.has_battery() && sav_path.exists() {
+        nes.mapper.load_sram(&sav_path)?;
+    }
 
-    let mut window = RenderWindow::new((800, 600), "Nesrust", Style::CLOSE, &Default::default());
-    window.set_framerate_limit(60);
+    let controls = controls::Controls::load(Path::new(CONTROLS_PATH))?;
+    let mut gamepads = controls::Gamepads::new();
 
-    const WIDTH: usize = 256;
-    const HEIGHT: usize = 240;
+    let window_size = match scale {
+        Some(scale) => (WIDTH as u32 * scale, HEIGHT as u32 * scale),
+        None => (800, 600),
+    };
+    let frame_scale = scale.unwrap_or(2) as f32;
+    let mut window = RenderWindow::new(
+        window_size,
+        "Nesrust",
+        Style::CLOSE | Style::RESIZE,
+        &Default::default(),
+    );
     let mut texture = Texture::new(WIDTH as u32, HEIGHT as u32).unwrap();
+    let frame_period = scheduler::NTSC_FRAME_PERIOD;
+    let mut scheduler = scheduler::Scheduler::new(frame_period);
 
-    loop {
-        while let Some(event) = window.poll_event() {
-            match event {
-                Event::Closed => return Ok(()),
-                Event::KeyPressed { code, .. } => match code {
-                    Key::Escape => return Ok(()),
-                    Key::S => {
-                        joystick.press_start();
-                    }
-                    Key::Left => {
-                        joystick.press_left();
-                    }
-                    Key::Right => {
-                        joystick.press_right();
-                    }
-                    Key::Up => {
-                        joystick.press_up();
-                    }
-                    Key::Down => {
-                        joystick.press_down();
-                    }
-                    Key::Z => {
-                        joystick.press_b();
-                    }
-                    Key::X => {
-                        joystick.press_a();
-                    }
-                    Key::L => {
-                        joystick.press_select();
-                    }
-                    _ => (),
-                },
-                Event::KeyReleased { code, .. } => match code {
-                    Key::S => {
-                        joystick.release_start();
-                    }
-                    Key::Left => {
-                        joystick.release_left();
-                    }
-                    Key::Right => {
-                        joystick.release_right();
-                    }
-                    Key::Up => {
-                        joystick.release_up();
-                    }
-                    Key::Down => {
-                        joystick.release_down();
-                    }
-                    Key::Z => {
-                        joystick.release_b();
-                    }
-                    Key::X => {
-                        joystick.release_a();
-                    }
-                    Key::L => {
-                        joystick.release_select();
+    let font = OVERLAY_FONTS.iter().find_map(|path| Font::from_file(path));
+    let mut stats = overlay::FrameStats::new(frame_period);
+    let mut ui = UiState {
+        show_stats: false,
+        paused: false,
+        advance_frame: false,
+    };
+    let mut jammed = false;
+    let mut rewind = rewind::Rewind::new();
+    let mut movie_mode = MovieMode::Off;
+
+    'main: loop {
+        if !handle_events(
+            &mut window,
+            &mut nes,
+            &controls,
+            &mut gamepads,
+            path,
+            &mut movie_mode,
+            &mut ui,
+        ) {
+            break;
+        }
+
+        let now = Instant::now();
+        // Frames falling due while paused are dropped so resuming doesn't
+        // catch up on them
+        let mut frames = scheduler.frames_due(now);
+        if ui.paused {
+            frames = ui.advance_frame as usize;
+            ui.advance_frame = false;
+        }
+        for _ in 0..frames {
+            // Movies run whole frames so playback follows the same schedule as
+            // the recording, rewinding would desync them
+            let mut movie_finished = false;
+            match &mut movie_mode {
+                MovieMode::Recording(recording) => recording.record_frame(&nes),
+                MovieMode::Playing(playback, frame) => {
+                    movie_finished = !playback.play_frame(&mut nes, *frame);
+                    *frame += 1;
+                }
+                MovieMode::Off => {
+                    // Holding backspace goes back one snapshot per frame
+                    if window.has_focus() && Key::BackSpace.is_pressed() {
+                        rewind.rewind(&mut nes);
+                        continue;
                     }
-                    _ => (),
-                },
-                _ => {}
+                }
+            }
+            if movie_finished {
+                println!("Movie finished");
+                movie_mode = MovieMode::Off;
+            }
+            if input_polls <= 1 || !matches!(movie_mode, MovieMode::Off) {
+                nes.run_frame();
+                rewind.record(&nes);
+                continue;
+            }
+            while !nes.run_partial_frame(nes::CPU_CYCLES_PER_FRAME / input_polls) {
+                if !handle_events(
+                    &mut window,
+                    &mut nes,
+                    &controls,
+                    &mut gamepads,
+                    path,
+                    &mut movie_mode,
+                    &mut ui,
+                ) {
+                    break 'main;
+                }
             }
+            rewind.record(&nes);
         }
+        stats.record(now, frames);
 
-        exec_frame(
-            &mut cpu,
-            &mut ppu,
-            &mut mapper,
-            &mut memory,
-            &mut dma,
-            &mut joystick,
-            &mut tick_offset,
-        );
+        if nes.cpu_jammed() != jammed {
+            jammed = nes.cpu_jammed();
+            window.set_title(if jammed {
+                "Nesrust - CPU jammed"
+            } else {
+                "Nesrust"
+            });
+        }
 
         if window.is_open() {
             window.clear(Color::BLACK);
             unsafe {
-                texture.update_from_pixels(&ppu.pixels, WIDTH as u32, HEIGHT as u32, 0, 0);
+                texture.update_from_pixels(nes.framebuffer(), WIDTH as u32, HEIGHT as u32, 0, 0);
             }
 
             let mut sprite = Sprite::new();
             sprite.set_texture(&texture, false);
-            sprite.set_scale(sfml::system::Vector2f::new(2f32, 2f32));
+            // Once resized the frame is fit to the window keeping its aspect
+            // ratio, centered on the free axis
+            let size = window.size();
+            if (size.x, size.y) == window_size {
+                sprite.set_scale((frame_scale, frame_scale));
+            } else {
+                let fit = (size.x as f32 / WIDTH as f32).min(size.y as f32 / HEIGHT as f32);
+                sprite.set_scale((fit, fit));
+                sprite.set_position((
+                    (size.x as f32 - WIDTH as f32 * fit) / 2.0,
+                    (size.y as f32 - HEIGHT as f32 * fit) / 2.0,
+                ));
+            }
             window.draw(&sprite);
+
+            if ui.show_stats {
+                match &font {
+                    Some(font) => {
+                        let mut text = Text::new(&stats.text(), font, 14);
+                        text.set_fill_color(Color::WHITE);
+                        text.set_outline_color(Color::BLACK);
+                        text.set_outline_thickness(1.0);
+                        text.set_position((4.0, 4.0));
+                        window.draw(&text);
+                    }
+                    // No font available, fall back to the window title
+                    None => window
+                        .set_title(&format!("Nesrust - {}", stats.text().replace('\n', " | "))),
+                }
+            }
             window.display();
+            std::thread::sleep(scheduler.time_until_next_frame(Instant::now()));
         } else {
             break;
         }
     }
 
+    if nes.mapper.has_battery() {
+        nes.mapper.save_sram(&sav_path)?;
+    }
     Ok(())
 }
+
+fn handle_events(
+    window: &mut RenderWindow,
+    nes: &mut nes::Nes,
+    controls: &controls::Controls,
+    gamepads: &mut controls::Gamepads,
+    rom_path: &Path,
+    movie_mode: &mut MovieMode,
+    ui: &mut UiState,
+) -> bool {
+    // Single save state slot, F5 saves and F9 loads
+    let state_path = &rom_path.with_extension("state");
+    // F6 starts and stops recording from power on, F7 plays the movie back
+    let movie_path = &rom_path.with_extension("movie");
+
+    while let Some(event) = window.poll_event() {
+        match event {
+            Event::Closed => return false,
+            // Keeps one view unit per pixel instead of stretching the contents
+            Event::Resized { width, height } => {
+                let area = FloatRect::new(0.0, 0.0, width as f32, height as f32);
+                window.set_view(&View::from_rect(&area));
+            }
+            Event::KeyPressed { code, shift, .. } => match code {
+                Key::Escape => return false,
+                Key::R => {
+                    nes.reset();
+                }
+                Key::F1 => {
+                    ui.show_stats = !ui.show_stats;
+                    if !ui.show_stats {
+                        window.set_title("Nesrust");
+                    }
+                }
+                Key::P => {
+                    ui.paused = !ui.paused;
+                    ui.advance_frame = false;
+                    println!("{}", if ui.paused { "Paused" } else { "Resumed" });
+                }
+                Key::N if ui.paused => {
+                    ui.advance_frame = true;
+                }
+                Key::F5 => match nes.save_state_file(state_path) {
+                    Ok(()) => println!("State saved to {}", state_path.display()),
+                    Err(error) => println!("Failed to save state: {}", error),
+                },
+                Key::F9 => match nes.load_state_file(state_path) {
+                    Ok(()) => println!("State loaded from {}", state_path.display()),
+                    Err(error) => println!("Failed to load state: {}", error),
+                },
+                Key::F6 => match std::mem::replace(movie_mode, MovieMode::Off) {
+                    MovieMode::Recording(recording) => match recording.save(movie_path) {
+                        Ok(()) => println!(
+                            "Recorded {} frames to {}",
+                            recording.len(),
+                            movie_path.display()
+                        ),
+                        Err(error) => println!("Failed to save movie: {}", error),
+                    },
+                    _ => {
+                        nes.power_on();
+                        *movie_mode = MovieMode::Recording(movie::Movie::start(nes));
+                        println!("Recording movie");
+                    }
+                },
+                Key::F7 => {
+                    let playback = movie::Movie::load(movie_path)
+                        .and_then(|playback| playback.restore_start(nes).map(|_| playback));
+                    match playback {
+                        Ok(playback) => {
+                            println!("Playing {} frames", playback.len());
+                            *movie_mode = MovieMode::Playing(playback, 0);
+                        }
+                        Err(error) => println!("Failed to play movie: {}", error),
+                    }
+                }
+                // F12 saves the frame at its native size, shift+F12 at the
+                // scale it is drawn in the window
+                Key::F12 => {
+                    let scale = if shift {
+                        let size = window.size();
+                        std::cmp::min(size.x as usize / WIDTH, size.y as usize / HEIGHT)
+                    } else {
+                        1
+                    };
+                    let time = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|time| time.as_millis())
+                        .unwrap_or(0);
+                    let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+                    let screenshot_path = rom_path.with_file_name(format!("{}-{}.png", stem, time));
+                    match png::save(&screenshot_path, WIDTH, HEIGHT, nes.framebuffer(), scale) {
+                        Ok(()) => println!("Screenshot saved to {}", screenshot_path.display()),
+                        Err(error) => println!("Failed to save screenshot: {}", error),
+                    }
+                }
+                Key::F3 => {
+                    let input_mode = match nes.joystick.input_mode() {
+                        joystick::InputMode::Controller1Only => {
+                            joystick::InputMode::BothControllers
+                        }
+                        joystick::InputMode::BothControllers => joystick::InputMode::Mirror,
+                        joystick::InputMode::Mirror => joystick::InputMode::Controller1Only,
+                    };
+                    nes.joystick.set_input_mode(input_mode);
+                    println!("Input mode: {:?}", input_mode);
+                }
+                code => {
+                    if let Some((port, button)) = controls.button(code) {
+                        nes.joystick.press(port, button);
+                    }
+                }
+            },
+            Event::KeyReleased { code, .. } => {
+                if let Some((port, button)) = controls.button(code) {
+                    nes.joystick.release(port, button);
+                }
+            }
+            Event::JoystickButtonPressed { joystickid, button } => {
+                gamepads.button_pressed(&mut nes.joystick, joystickid, button);
+            }
+            Event::JoystickButtonReleased { joystickid, button } => {
+                gamepads.button_released(&mut nes.joystick, joystickid, button);
+            }
+            Event::JoystickMoved {
+                joystickid,
+                axis,
+                position,
+            } => {
+                gamepads.axis_moved(&mut nes.joystick, joystickid, axis, position);
+            }
+            _ => {}
+        }
+    }
+    true
+}
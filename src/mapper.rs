@@ -1,94 +1,553 @@
-use anyhow::{Result, *};
+use crate::bus::{BusOps, PeekOps};
+use crate::error::{NesError, Result};
+use crate::savestate;
 use std::{fs::File, io::BufReader, io::Read, path::Path};
 
+// Every iNES mapper number load accepts, keep in sync with the banking code
+const SUPPORTED_MAPPERS: [u16; 4] = [0, 1, 2, 3];
+
+pub fn supported_mappers() -> &'static [u16] {
+    &SUPPORTED_MAPPERS
+}
+
+// The mapper is on both buses, PpuBus exposes the PPU address space while the
+// mapper itself decodes CPU addresses
+pub struct PpuBus<'a>(&'a mut Mapper);
+
+impl<'a> BusOps for PpuBus<'a> {
+    fn write(&mut self, address: u16, data: u8) {
+        self.0.ppu_write(address, data);
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        self.0.ppu_read(address)
+    }
+}
+
+// Read only counterpart of PpuBus, peeks skip the A12 tracking
+pub struct PpuPeekBus<'a>(&'a Mapper);
+
+impl<'a> PeekOps for PpuPeekBus<'a> {
+    fn peek(&self, address: u16) -> u8 {
+        self.0.ppu_peek(address)
+    }
+}
+
+impl BusOps for Mapper {
+    fn write(&mut self, address: u16, data: u8) {
+        self.cpu_write(address, data);
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        self.cpu_read(address)
+    }
+}
+
+// Cartridge description from an iNES or NES 2.0 header, sizes in bytes
+pub struct InesHeader {
+    pub nes2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub prgram_size: usize,
+    pub chr_ram_size: usize,
+    pub region: u8,
+    pub vertical_mirroring: bool,
+    pub battery: bool,
+    pub trainer: bool,
+    pub four_screen: bool,
+}
+
+impl InesHeader {
+    pub fn parse(header: &[u8; 16]) -> Result<InesHeader> {
+        let nesheader = ['N' as u8, 'E' as u8, 'S' as u8, 0x1A];
+        if nesheader != header[0..4] {
+            return Err(NesError::InvalidHeader);
+        }
+
+        let mut ines = InesHeader {
+            nes2: header[7] & 0x0C == 0x08,
+            mapper: ((header[6] & 0xF0) >> 4) as u16 | (header[7] & 0xF0) as u16,
+            submapper: 0,
+            prg_size: header[4] as usize * 16384,
+            chr_size: header[5] as usize * 8192,
+            prgram_size: 0,
+            chr_ram_size: 0,
+            region: 0,
+            vertical_mirroring: header[6] & 0x01 != 0,
+            battery: header[6] & 0x02 != 0,
+            trainer: header[6] & 0x04 != 0,
+            four_screen: header[6] & 0x08 != 0,
+        };
+
+        if !ines.nes2 {
+            // Old dumps have a signature in the unused bytes, byte 7 is part of
+            // it and can't be trusted for the mapper high nibble
+            if header[12..16].iter().any(|&byte| byte != 0) {
+                ines.mapper &= 0x0F;
+            }
+            // PRG-RAM size in 8kb units, zero means a single bank
+            ines.prgram_size = std::cmp::max(header[8] as usize, 1) * 8192;
+            ines.chr_ram_size = if ines.chr_size == 0 { 8192 } else { 0 };
+            return Ok(ines);
+        }
+
+        ines.mapper |= ((header[8] & 0x0F) as u16) << 8;
+        ines.submapper = header[8] >> 4;
+        ines.prg_size = InesHeader::rom_size(header[4], header[9] & 0x0F, 16384);
+        ines.chr_size = InesHeader::rom_size(header[5], header[9] >> 4, 8192);
+        // RAM sizes are shift counts, 64 << count bytes and zero for none,
+        // battery backed PRG-RAM is counted with the rest
+        let shift_size = |count: u8| if count == 0 { 0 } else { 64 << count as usize };
+        ines.prgram_size = shift_size(header[10] & 0x0F) + shift_size(header[10] >> 4);
+        ines.chr_ram_size = shift_size(header[11] & 0x0F) + shift_size(header[11] >> 4);
+        ines.region = header[12] & 0x03;
+        Ok(ines)
+    }
+
+    // An 0xF high nibble switches to the exponent form, 2^E * (M * 2 + 1)
+    // with the low byte split as EEEEEEMM
+    fn rom_size(low: u8, high: u8, unit: usize) -> usize {
+        if high == 0x0F {
+            let exponent = (low >> 2) as u32;
+            let multiplier = (low & 0x3) as usize * 2 + 1;
+            return 2usize.saturating_pow(exponent).saturating_mul(multiplier);
+        }
+        ((high as usize) << 8 | low as usize) * unit
+    }
+}
+
 pub struct Mapper {
+    mapper: u16,
     num_blocks: u8,
-    first_cpu_bank: usize,
-    last_cpu_bank: usize,
+    prg_banks: [usize; 2],
+    chr_banks: [usize; 2],
     first_ppu_bank: usize,
     last_ppu_bank: usize,
-    memory: [u8; 262144],
+    // 512kb of PRG-ROM, kept on the heap so a Mapper can be moved around
+    memory: Box<[u8]>,
     shift: u8,
     mirroring: u8,
+    header_mirroring: u8,
     prg_mode: u8,
+    prg_bank: u8,
+    prg_outer_bank: usize,
     chr_mode: u8,
     chr_ram: [u8; 32768],
+    chr_size: usize,
     nametables: [u8; 4096],
-    prgram: [u8; 16384],
+    prgram: [u8; 32768],
+    prgram_size: usize,
+    prgram_bank: usize,
+    prgram_enabled: bool,
+    battery: bool,
     palettes: [u8; 32],
+    ppu_a12: bool,
+    a12_rising_edges: u64,
+    cpu_cycle: u64,
+    last_write_cycle: u64,
+    // CPU cycles left on the M2 clocked IRQ counter, 0 when it isn't armed
+    irq_counter: u32,
+    irq_pending: bool,
+    rom_hash: u64,
+}
+
+impl Default for Mapper {
+    fn default() -> Self {
+        Mapper::new()
+    }
 }
 
 impl Mapper {
     pub fn new() -> Mapper {
         Mapper {
+            mapper: 0,
             num_blocks: 0,
-            first_cpu_bank: 0,
-            last_cpu_bank: 0,
+            prg_banks: [0; 2],
+            chr_banks: [0; 2],
             first_ppu_bank: 0,
             last_ppu_bank: 0,
-            memory: [0; 262144],
+            memory: vec![0; 524288].into_boxed_slice(),
             shift: 0,
             mirroring: 0,
+            header_mirroring: 0,
             prg_mode: 0,
+            prg_bank: 0,
+            prg_outer_bank: 0,
             chr_mode: 0,
             chr_ram: [0; 32768],
+            chr_size: 0,
             nametables: [0; 4096],
-            prgram: [0; 16384],
+            prgram: [0; 32768],
+            prgram_size: 8192,
+            prgram_bank: 0,
+            prgram_enabled: true,
+            battery: false,
             palettes: [0; 32],
+            ppu_a12: false,
+            a12_rising_edges: 0,
+            cpu_cycle: 0,
+            last_write_cycle: u64::MAX,
+            irq_counter: 0,
+            irq_pending: false,
+            rom_hash: 0,
         }
     }
 
     pub fn load(&mut self, path: &Path) -> Result<()> {
-        let mut reader = BufReader::new(File::open(path)?); // TODO error handling
+        let mut reader = BufReader::new(File::open(path)?);
 
         let mut header: [u8; 16] = [0; 16];
-        reader.read(&mut header)?;
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| NesError::InvalidHeader)?;
 
-        let nesheader = ['N' as u8, 'E' as u8, 'S' as u8, 0x1A];
-        if nesheader != header[0..4] {
-            return Err(anyhow!("Invalid nes file"));
+        let unifheader = *b"UNIF";
+        if unifheader == header[0..4] {
+            return self.load_unif(&mut reader);
+        }
+
+        let ines = InesHeader::parse(&header)?;
+        if ines.prg_size == 0 {
+            return Err(NesError::NoData);
+        } else if ines.prg_size > self.memory.len() {
+            return Err(NesError::TooMuchData);
+        }
+
+        if ines.trainer {
+            let mut trainer = [0; 512];
+            reader
+                .read_exact(&mut trainer)
+                .map_err(|_| NesError::TruncatedData)?;
         }
 
-        if header[7] & 0x0C == 0x08 {
-            return Err(anyhow!("File is ines2.0"));
+        let mut prg = vec![0; ines.prg_size];
+        reader
+            .read_exact(&mut prg)
+            .map_err(|_| NesError::TruncatedData)?;
+        let mut chr = vec![0; ines.chr_size];
+        reader
+            .read_exact(&mut chr)
+            .map_err(|_| NesError::TruncatedData)?;
+        let prgram_size = ines.prgram_size.max(8192).min(self.prgram.len());
+        self.battery = ines.battery;
+        self.header_mirroring = if ines.four_screen {
+            4
+        } else if ines.vertical_mirroring {
+            2
+        } else {
+            3
+        };
+        self.load_parts(ines.mapper, &prg, &chr, prgram_size)
+    }
+
+    // UNIF files are a 32 byte header followed by chunks made of a 4 byte id,
+    // a little endian 32 bit length and the chunk data
+    fn load_unif(&mut self, reader: &mut impl Read) -> Result<()> {
+        let mut header_rest: [u8; 16] = [0; 16];
+        reader
+            .read_exact(&mut header_rest)
+            .map_err(|_| NesError::InvalidHeader)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let mut board = None;
+        let mut battery = false;
+        let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+        let mut offset = 0;
+        while offset < data.len() {
+            if data.len() - offset < 8 {
+                return Err(NesError::TruncatedData);
+            }
+            let id = &data[offset..offset + 4];
+            let length = u32::from_le_bytes([
+                data[offset + 4],
+                data[offset + 5],
+                data[offset + 6],
+                data[offset + 7],
+            ]) as usize;
+            offset += 8;
+            if data.len() - offset < length {
+                return Err(NesError::TruncatedData);
+            }
+            let chunk = &data[offset..offset + length];
+            offset += length;
+
+            match &id[0..3] {
+                b"PRG" => {
+                    if let Some(index) = (id[3] as char).to_digit(16) {
+                        prg_chunks[index as usize] = chunk;
+                    }
+                }
+                b"CHR" => {
+                    if let Some(index) = (id[3] as char).to_digit(16) {
+                        chr_chunks[index as usize] = chunk;
+                    }
+                }
+                b"MAP" if id[3] == b'R' => {
+                    // Null terminated board name
+                    let name = chunk.split(|&byte| byte == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                b"BAT" if id[3] == b'R' => battery = true,
+                _ => (),
+            }
         }
 
-        self.num_blocks = header[4];
-        println!("NumBlocks: {}", self.num_blocks);
+        let board = board.ok_or(NesError::InvalidHeader)?;
+        let mapper = Mapper::unif_board_mapper(&board)?;
+        let prg = prg_chunks.concat();
+        let chr = chr_chunks.concat();
+        if prg.len() % 16384 != 0 {
+            return Err(NesError::TruncatedData);
+        }
+        // UNIF doesn't store the PRG-RAM size, the larger MMC1 boards get it
+        // from the board name
+        let prgram_size = match board.trim_start_matches("NES-") {
+            "SOROM" => 16384,
+            "SXROM" => 32768,
+            _ => 8192,
+        };
+        self.battery = battery;
+        self.load_parts(mapper, &prg, &chr, prgram_size)
+    }
 
-        if self.num_blocks == 0 {
-            return Err(anyhow!("No data in nes file"));
-        } else if self.num_blocks > 16 {
-            return Err(anyhow!("Too much data"));
+    fn unif_board_mapper(board: &str) -> Result<u16> {
+        let name = board
+            .trim_start_matches("NES-")
+            .trim_start_matches("HVC-")
+            .trim_start_matches("UNL-");
+        match name {
+            "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Ok(0),
+            "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM"
+            | "SKROM" | "SLROM" | "SL1ROM" | "SNROM" | "SOROM" | "SUROM" | "SXROM" => Ok(1),
+            "UNROM" | "UOROM" => Ok(2),
+            "CNROM" => Ok(3),
+            _ => Err(NesError::UnsupportedBoard(board.to_string())),
         }
+    }
 
-        let low_nibble = (header[6] & 0xF0) >> 4;
-        let high_nibble = header[7] & 0xF0;
-        let mapper = low_nibble | high_nibble;
-        if mapper != 0 && mapper != 1 {
-            return Err(anyhow!("Unsupported mapper {}", mapper));
+    // Sets up the cartridge from already extracted PRG/CHR data, shared by all
+    // the file formats
+    pub fn load_parts(
+        &mut self,
+        mapper: u16,
+        prg: &[u8],
+        chr: &[u8],
+        prgram_size: usize,
+    ) -> Result<()> {
+        if !supported_mappers().contains(&mapper) {
+            return Err(NesError::UnsupportedMapper(mapper));
+        }
+        if prg.is_empty() {
+            return Err(NesError::NoData);
+        } else if prg.len() > self.memory.len() || chr.len() > self.chr_ram.len() {
+            return Err(NesError::TooMuchData);
         }
 
-        let memory_size = 16384 * self.num_blocks as usize;
-        reader
-            .read_exact(&mut self.memory[0..memory_size])
-            .context("Failed to read nes data")?;
-        self.first_cpu_bank = 0;
-        self.last_cpu_bank = 16384 * (self.num_blocks as usize - 1);
-        self.first_ppu_bank = 0;
-        self.last_ppu_bank = 0;
+        self.mapper = mapper;
+        self.num_blocks = prg.len().div_ceil(16384) as u8;
+
+        self.memory[0..prg.len()].copy_from_slice(prg);
+        self.chr_ram[0..chr.len()].copy_from_slice(chr);
+        self.chr_size = chr.len();
+        self.rom_hash = savestate::hash(&[prg, chr].concat());
+        self.prgram_size = prgram_size;
+        self.power_on();
+        Ok(())
+    }
+
+    // Registers and PPU memories start cleared, PRG-RAM is left alone since it
+    // may hold battery backed data
+    pub fn power_on(&mut self) {
+        self.nametables = [0; 4096];
+        self.palettes = [0; 32];
         self.shift = 1 << 4;
+        self.mirroring = self.header_mirroring;
+        // Last bank fixed at 0xC000
+        self.prg_mode = 3;
+        self.prg_bank = 0;
+        self.prg_outer_bank = 0;
         self.chr_mode = 0;
+        self.first_ppu_bank = 0;
+        self.last_ppu_bank = 0;
+        self.prgram_bank = 0;
+        self.prgram_enabled = true;
+        self.irq_counter = 0;
+        self.irq_pending = false;
+        self.update_prg_banks();
+        self.update_chr_banks();
+    }
+
+    // The reset button doesn't reach the cartridge, the banking survives it
+    pub fn reset(&mut self) {}
+
+    // Banking registers and the cartridge RAMs, the ROM itself is identified
+    // by the hash in the save state header. The bank offsets are derived from
+    // the registers when loading
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = vec![
+            self.shift,
+            self.mirroring,
+            self.prg_mode,
+            self.prg_bank,
+            self.chr_mode,
+            self.prgram_enabled as u8,
+            self.ppu_a12 as u8,
+            self.irq_pending as u8,
+        ];
+        for &offset in [
+            self.prg_outer_bank,
+            self.first_ppu_bank,
+            self.last_ppu_bank,
+            self.prgram_bank,
+        ]
+        .iter()
+        {
+            state.extend_from_slice(&(offset as u64).to_le_bytes());
+        }
+        state.extend_from_slice(&self.a12_rising_edges.to_le_bytes());
+        state.extend_from_slice(&self.cpu_cycle.to_le_bytes());
+        state.extend_from_slice(&self.last_write_cycle.to_le_bytes());
+        state.extend_from_slice(&(self.irq_counter as u64).to_le_bytes());
+        state.extend_from_slice(&self.chr_ram);
+        state.extend_from_slice(&self.nametables);
+        state.extend_from_slice(&self.prgram);
+        state.extend_from_slice(&self.palettes);
+        state
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = savestate::StateReader::new(state);
+        self.shift = reader.u8()?;
+        self.mirroring = reader.u8()?;
+        self.prg_mode = reader.u8()?;
+        self.prg_bank = reader.u8()?;
+        self.chr_mode = reader.u8()?;
+        self.prgram_enabled = reader.bool()?;
+        self.ppu_a12 = reader.bool()?;
+        self.irq_pending = reader.bool()?;
+        self.prg_outer_bank = reader.u64()? as usize;
+        self.first_ppu_bank = reader.u64()? as usize;
+        self.last_ppu_bank = reader.u64()? as usize;
+        self.prgram_bank = reader.u64()? as usize;
+        self.a12_rising_edges = reader.u64()?;
+        self.cpu_cycle = reader.u64()?;
+        self.last_write_cycle = reader.u64()?;
+        self.irq_counter = reader.u64()? as u32;
+        reader.fill(&mut self.chr_ram)?;
+        reader.fill(&mut self.nametables)?;
+        reader.fill(&mut self.prgram)?;
+        reader.fill(&mut self.palettes)?;
+        reader.finish()?;
+
+        if self.prg_outer_bank >= self.memory.len() || self.prgram_bank >= self.prgram_size {
+            return Err(NesError::InvalidState);
+        }
+        self.update_prg_banks();
+        self.update_chr_banks();
+        Ok(())
+    }
+
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    // Whether the cartridge keeps PRG-RAM powered, only then it's worth saving
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    // The .sav file is the raw PRG-RAM contents, like other emulators use
+    pub fn save_sram(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, &self.prgram[0..self.prgram_size])?;
         Ok(())
     }
 
+    pub fn load_sram(&mut self, path: &Path) -> Result<()> {
+        let data = std::fs::read(path)?;
+        if data.len() != self.prgram_size {
+            return Err(NesError::SramSize(data.len()));
+        }
+        self.prgram[0..data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    pub fn ppu_bus(&mut self) -> PpuBus<'_> {
+        PpuBus(self)
+    }
+
+    pub fn ppu_peek_bus(&self) -> PpuPeekBus<'_> {
+        PpuPeekBus(self)
+    }
+
+    // Called once per CPU cycle (M2), mappers with CPU clocked IRQ counters
+    // hook in here
+    pub fn cpu_clock(&mut self) {
+        self.cpu_cycle += 1;
+        if self.irq_counter > 0 {
+            self.irq_counter -= 1;
+            if self.irq_counter == 0 {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    // Arms the M2 clocked IRQ counter to fire after the given number of CPU
+    // cycles, 0 disarms it. Either way a pending IRQ is acknowledged
+    pub fn set_irq_counter(&mut self, cycles: u32) {
+        self.irq_counter = cycles;
+        self.irq_pending = false;
+    }
+
+    // IRQ line driven by the mapper, held from the cycle the counter fires
+    // until it is acknowledged
+    pub fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    // When disabled nothing drives the bus on $6000-$7FFF, reads see open bus
+    pub fn prgram_enabled(&self) -> bool {
+        self.prgram_enabled
+    }
+
     pub fn cpu_write(&mut self, address: u16, data: u8) {
-        if address >= 0x4020 && address <= 0x7FFF {
-            self.prgram[address as usize & 0x1FFF] = data;
+        if address >= 0x4020 && address <= 0x7FFF && self.prgram_enabled {
+            self.prgram[self.prgram_bank + (address as usize & 0x1FFF)] = data;
         }
         if address & 0x8000 == 0 {
             return;
         }
+
+        match self.mapper {
+            1 => self.mmc1_write(address, data),
+            2 =>
+            // UxROM, any write selects the 16kb bank at 0x8000
+            {
+                self.prg_bank = data;
+                self.update_prg_banks();
+            }
+            3 =>
+            // CNROM, any write selects the 8kb CHR bank
+            {
+                let chr_blocks = std::cmp::max(self.chr_size / 8192, 1);
+                self.first_ppu_bank = (data as usize % chr_blocks) * 8192;
+                self.update_chr_banks();
+            }
+            _ => (),
+        }
+    }
+
+    fn mmc1_write(&mut self, address: u16, data: u8) {
+        // MMC1 ignores a serial write on the cycle right after another one,
+        // like the second write of a read-modify-write instruction
+        let consecutive = self.cpu_cycle == self.last_write_cycle.wrapping_add(1);
+        self.last_write_cycle = self.cpu_cycle;
+        if consecutive {
+            return;
+        }
         if data & 0x80 != 0 {
             self.shift = 1 << 4;
             return;
@@ -105,97 +564,146 @@ impl Mapper {
                 self.mirroring = self.shift & 0x3;
                 self.prg_mode = (self.shift & 0xC) >> 2;
                 self.chr_mode = (self.shift & 0x10) >> 4;
+                self.update_prg_banks();
+                self.update_chr_banks();
+            }
+            0x2000 => {
+                match self.chr_mode {
+                    0 => self.first_ppu_bank = (self.shift as usize & 0x1E) * 4096,
+                    _ => self.first_ppu_bank = self.shift as usize * 4096,
+                }
+                self.update_chr_banks();
+
+                // SUROM: bit 4 selects the 256kb half of a 512kb PRG-ROM
+                if self.num_blocks > 16 {
+                    self.prg_outer_bank = (self.shift as usize & 0x10) * 16384;
+                }
+
+                // SOROM: bit 3 selects one of two 8kb PRG-RAM banks
+                // SXROM: bits 2-3 select one of four 8kb PRG-RAM banks
+                self.prgram_bank = match self.prgram_size {
+                    16384 => ((self.shift as usize >> 3) & 0x1) * 8192,
+                    32768 => ((self.shift as usize >> 2) & 0x3) * 8192,
+                    _ => 0,
+                };
+                self.update_prg_banks();
             }
-            0x2000 => match self.chr_mode {
-                0 => self.first_ppu_bank = self.shift as usize & 0x1E * 4096,
-                _ => self.first_ppu_bank = self.shift as usize * 4096,
-            },
             0x4000 => {
                 if self.chr_mode != 0
                 // 8kb bank is ignored
                 {
                     self.last_ppu_bank = self.shift as usize * 4096;
                 }
+                self.update_chr_banks();
             }
             0x6000 => {
-                match self.prg_mode {
-                    0 =>
-                    // 32k mode
-                    {
-                        self.first_cpu_bank = (self.shift as usize & 0xE) * 16384;
-                        self.last_cpu_bank = self.first_cpu_bank + 16384;
-                    }
-                    // 32k mode
-                    1 => {
-                        self.first_cpu_bank = (self.shift as usize & 0xE) * 16384;
-                        self.last_cpu_bank = self.first_cpu_bank + 16384;
-                    }
-                    2 =>
-                    // Fix first at 0x8000 and switch last at 0xC000
-                    {
-                        self.first_cpu_bank = 0;
-                        self.last_cpu_bank = self.shift as usize * 16384;
-                    }
-                    3 =>
-                    // Fix last at 0xC000 and switch first at 0x8000
-                    {
-                        self.first_cpu_bank = self.shift as usize * 16384;
-                        self.last_cpu_bank = 16384 * (self.num_blocks as usize - 1);
-                    }
-                    _ => (),
-                }
+                self.prg_bank = self.shift;
+                // Bit 4 set disables the PRG-RAM
+                self.prgram_enabled = self.shift & 0x10 == 0;
+                self.update_prg_banks();
             }
             _ => (),
         }
         self.shift = 1 << 4;
     }
 
-    pub fn cpu_read(&mut self, address: u16) -> u8 {
-        if address >= 0x4020 && address <= 0x7FFF {
-            return self.prgram[address as usize & 0x1FFF];
+    fn update_prg_banks(&mut self) {
+        if self.mapper == 2 {
+            // UxROM fixes the last bank at 0xC000, the bank number is masked
+            // to the ROM size so 512kb boards see all 32 banks
+            let last_bank = (self.num_blocks as usize).saturating_sub(1);
+            let bank = self.prg_bank as usize & last_bank;
+            self.prg_banks = [bank * 16384, last_bank * 16384];
+            return;
+        }
+
+        let bank = self.prg_bank as usize & 0xF;
+        let last_bank = std::cmp::min(self.num_blocks as usize, 16).saturating_sub(1);
+        match self.prg_mode {
+            0 | 1 =>
+            // 32k mode
+            {
+                let first_bank = self.prg_outer_bank + (bank & 0xE) * 16384;
+                self.prg_banks = [first_bank, first_bank + 16384];
+            }
+            2 =>
+            // Fix first at 0x8000 and switch last at 0xC000
+            {
+                self.prg_banks = [self.prg_outer_bank, self.prg_outer_bank + bank * 16384];
+            }
+            _ =>
+            // Fix last at 0xC000 and switch first at 0x8000
+            {
+                self.prg_banks = [
+                    self.prg_outer_bank + bank * 16384,
+                    self.prg_outer_bank + last_bank * 16384,
+                ];
+            }
         }
-        if address & 0xC000 == 0x8000 {
-            return self.memory[(self.first_cpu_bank) + (address as usize & 0x3FFF)];
+    }
+
+    // Resolves the CHR base offsets once per bank switch so the PPU reads only
+    // need to pick the bank for the 0x0000 or 0x1000 half
+    fn update_chr_banks(&mut self) {
+        let mask = self.chr_ram.len() - 1;
+        if self.chr_mode == 0
+        // 8kb bank
+        {
+            self.chr_banks = [
+                self.first_ppu_bank & mask,
+                (self.first_ppu_bank + 4096) & mask,
+            ];
+        } else {
+            self.chr_banks = [self.first_ppu_bank & mask, self.last_ppu_bank & mask];
+        }
+    }
+
+    pub fn cpu_read(&mut self, address: u16) -> u8 {
+        self.cpu_peek(address)
+    }
+
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        if address >= 0x4020 && address <= 0x7FFF && self.prgram_enabled {
+            return self.prgram[self.prgram_bank + (address as usize & 0x1FFF)];
         }
-        if address & 0xC000 == 0xC000 {
-            return self.memory[(self.last_cpu_bank) + (address as usize & 0x3FFF)];
+        if address & 0x8000 != 0 {
+            let bank = self.prg_banks[(address as usize >> 14) & 1];
+            return self.memory[bank + (address as usize & 0x3FFF)];
         }
 
         0
     }
 
+    // Every PPU access goes through here, rendering fetches and $2007 alike,
+    // so this is where a scanline counter watching A12 gets clocked
+    fn watch_ppu_address(&mut self, address: u16) {
+        // Palette RAM sits inside the PPU, the lookups made for every rendered
+        // pixel never reach the cartridge
+        if address & 0x3FFF >= 0x3F00 {
+            return;
+        }
+        let a12 = address & 0x1000 != 0;
+        if a12 && !self.ppu_a12 {
+            self.a12_rising_edges += 1;
+        }
+        self.ppu_a12 = a12;
+    }
+
+    pub fn a12_rising_edges(&self) -> u64 {
+        self.a12_rising_edges
+    }
+
     pub fn ppu_write(&mut self, address: u16, data: u8) {
+        self.watch_ppu_address(address);
         let address = address & 0x3FFF;
         if address <= 0x1FFF {
-            if self.chr_mode == 0
-            // 8kb bank
-            {
-                self.chr_ram[self.first_ppu_bank + address as usize] = data;
-            } else if address & 0x1000 != 0 {
-                self.chr_ram[self.last_ppu_bank + (address as usize & 0xFFF)] = data;
-            } else {
-                self.chr_ram[self.first_ppu_bank + address as usize] = data;
+            // Carts that ship CHR data have it in ROM, only CHR-RAM is writable
+            if self.chr_size == 0 {
+                let bank = self.chr_banks[(address as usize >> 12) & 1];
+                self.chr_ram[bank + (address as usize & 0xFFF)] = data;
             }
         } else if address <= 0x3EFF {
-            let mut real_address = address as usize & 0xFFF;
-            if self.mirroring == 0
-            // one screen lower bank
-            {
-                real_address &= 0x3FF;
-            } else if self.mirroring == 1
-            // one screen upper bank
-            {
-                // TODO upper bank
-                real_address &= 0x3FF;
-            } else if self.mirroring == 2
-            // Vertical
-            {
-                real_address &= 0x7FF;
-            } else if self.mirroring == 3
-            // horizontal
-            {
-                real_address &= 0xBFF;
-            }
+            let real_address = self.nametable_address(address);
             self.nametables[real_address] = data;
         } else {
             let mut real_address = address as usize;
@@ -208,28 +716,17 @@ impl Mapper {
     }
 
     pub fn ppu_read(&mut self, address: u16) -> u8 {
+        self.watch_ppu_address(address);
+        self.ppu_peek(address)
+    }
+
+    pub fn ppu_peek(&self, address: u16) -> u8 {
         let address = address & 0x3FFF;
         if address <= 0x1FFF {
-            return self.chr_ram[self.first_ppu_bank + address as usize];
+            let bank = self.chr_banks[(address as usize >> 12) & 1];
+            return self.chr_ram[bank + (address as usize & 0xFFF)];
         } else if address <= 0x3EFF {
-            let mut real_address = address as usize & 0xFFF;
-            if self.mirroring == 0
-            // one screen lower bank
-            {
-                real_address &= 0x3FF;
-            } else if self.mirroring == 1
-            // one screen upper bank
-            {
-                real_address &= 0x3FF;
-            } else if self.mirroring == 2
-            // Vertical
-            {
-                real_address &= 0x7FF;
-            } else if self.mirroring == 3
-            // horizontal
-            {
-                real_address &= 0xBFF;
-            }
+            let real_address = self.nametable_address(address);
             return self.nametables[real_address];
         } else {
             let mut address = address as usize;
@@ -240,4 +737,544 @@ impl Mapper {
             return self.palettes[address & 0x1F];
         }
     }
+
+    fn nametable_address(&self, address: u16) -> usize {
+        let mut real_address = address as usize & 0xFFF;
+        if self.mirroring == 0
+        // one screen lower bank
+        {
+            real_address &= 0x3FF;
+        } else if self.mirroring == 1
+        // one screen upper bank
+        {
+            real_address = 0x400 | (real_address & 0x3FF);
+        } else if self.mirroring == 2
+        // Vertical, $2000/$2800 and $2400/$2C00 share a table, A10 selects it
+        {
+            real_address &= 0x7FF;
+        } else if self.mirroring == 3
+        // Horizontal, $2000/$2400 and $2800/$2C00 share a table, A11 selects it
+        {
+            real_address = ((real_address >> 1) & 0x400) | (real_address & 0x3FF);
+        }
+        // Four screen (4), the cartridge provides the other 2kb so all four
+        // tables are distinct and $2000-$2FFF maps straight through
+        real_address
+    }
+
+    // Debug write into one of the four logical nametables, goes through the
+    // current mirroring but not through the PPU
+    pub fn set_nametable_byte(&mut self, nametable: u8, index: u16, value: u8) {
+        let address = 0x2000 + (nametable as u16 & 0x3) * 0x400 + (index & 0x3FF);
+        let real_address = self.nametable_address(address);
+        self.nametables[real_address] = value;
+    }
+
+    pub fn palette_ram(&self) -> [u8; 32] {
+        let mut palette_ram = [0; 32];
+        for (index, entry) in palette_ram.iter_mut().enumerate() {
+            let mut address = index;
+            // Backdrop mirroring
+            if address & 0x13 == 0x10 {
+                address &= 0xF;
+            }
+            *entry = self.palettes[address];
+        }
+        palette_ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // Every byte tells its 16kb bank and offset apart from its neighbours
+    fn mmc1_mapper() -> Mapper {
+        let prg: Vec<u8> = (0..524288).map(|i| (i / 16384 * 7 + i) as u8).collect();
+        let chr: Vec<u8> = (0..32768).map(|i| (i / 4096 * 13 + i) as u8).collect();
+        let mut mapper = Mapper::new();
+        mapper.load_parts(1, &prg, &chr, 8192).unwrap();
+        mapper
+    }
+
+    fn mmc1_register(mapper: &mut Mapper, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_clock();
+            mapper.cpu_clock();
+            mapper.cpu_write(address, (value >> bit) & 1);
+        }
+    }
+
+    // What the reads did before the offsets were cached, the bank is resolved
+    // from the registers on every access
+    fn uncached_cpu_read(mapper: &Mapper, address: u16) -> u8 {
+        let bank = mapper.prg_bank as usize & 0xF;
+        let last_bank = std::cmp::min(mapper.num_blocks as usize, 16) - 1;
+        let high = address & 0x4000 != 0;
+        let bank = match (mapper.prg_mode, high) {
+            (0, _) | (1, _) => (bank & 0xE) + high as usize,
+            (2, false) => 0,
+            (2, true) => bank,
+            (_, false) => bank,
+            (_, true) => last_bank,
+        };
+        mapper.memory[mapper.prg_outer_bank + bank * 16384 + (address as usize & 0x3FFF)]
+    }
+
+    fn uncached_ppu_read(mapper: &Mapper, address: u16) -> u8 {
+        let address = address as usize & 0x1FFF;
+        let offset = if mapper.chr_mode == 0 {
+            mapper.first_ppu_bank + address
+        } else if address & 0x1000 != 0 {
+            mapper.last_ppu_bank + (address & 0xFFF)
+        } else {
+            mapper.first_ppu_bank + address
+        };
+        mapper.chr_ram[offset & (mapper.chr_ram.len() - 1)]
+    }
+
+    #[test]
+    fn cached_prg_banks_match_the_registers() {
+        let mut mapper = mmc1_mapper();
+        for control in 0..32 {
+            mmc1_register(&mut mapper, 0x8000, control);
+            for outer in [0x00, 0x10].iter() {
+                mmc1_register(&mut mapper, 0xA000, *outer);
+                for prg in 0..16 {
+                    mmc1_register(&mut mapper, 0xE000, prg);
+                    for address in [0x8000, 0x9234, 0xBFFF, 0xC000, 0xE234, 0xFFFF].iter() {
+                        assert_eq!(
+                            mapper.cpu_peek(*address),
+                            uncached_cpu_read(&mapper, *address),
+                            "control {:02X} outer {:02X} prg {:02X} address {:04X}",
+                            control,
+                            outer,
+                            prg,
+                            address
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cached_chr_banks_match_the_registers() {
+        let mut mapper = mmc1_mapper();
+        for control in [0x00, 0x10].iter() {
+            mmc1_register(&mut mapper, 0x8000, *control);
+            for chr0 in 0..32 {
+                mmc1_register(&mut mapper, 0xA000, chr0);
+                for chr1 in 0..32 {
+                    mmc1_register(&mut mapper, 0xC000, chr1);
+                    for address in [0x0000, 0x0ABC, 0x0FFF, 0x1000, 0x1ABC, 0x1FFF].iter() {
+                        assert_eq!(
+                            mapper.ppu_peek(*address),
+                            uncached_ppu_read(&mapper, *address),
+                            "control {:02X} chr {:02X} {:02X} address {:04X}",
+                            control,
+                            chr0,
+                            chr1,
+                            address
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Timing behind the cached offsets, run it with
+    // cargo test --release -- --ignored --nocapture bank_lookup_timing
+    #[test]
+    #[ignore]
+    fn bank_lookup_timing() {
+        let mut mapper = mmc1_mapper();
+        mmc1_register(&mut mapper, 0x8000, 0x1C);
+        mmc1_register(&mut mapper, 0xE000, 0x05);
+        let reads = 64 * 1024 * 1024;
+
+        let start = Instant::now();
+        let mut cached = 0u64;
+        for i in 0..reads {
+            let address = 0x8000 | (i as u16).wrapping_mul(40503);
+            cached += mapper.cpu_peek(address) as u64 + mapper.ppu_peek(address & 0x1FFF) as u64;
+        }
+        let cached_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut uncached = 0u64;
+        for i in 0..reads {
+            let address = 0x8000 | (i as u16).wrapping_mul(40503);
+            uncached += uncached_cpu_read(&mapper, address) as u64
+                + uncached_ppu_read(&mapper, address & 0x1FFF) as u64;
+        }
+        let uncached_time = start.elapsed();
+
+        assert_eq!(cached, uncached);
+        println!(
+            "{} CPU and PPU reads: cached {:?}, uncached {:?}",
+            reads, cached_time, uncached_time
+        );
+    }
+
+    #[test]
+    fn uxrom_banks_cover_512kb() {
+        let mut prg = vec![0; 524288];
+        for bank in 0..32 {
+            prg[bank * 16384] = bank as u8;
+        }
+        let mut mapper = Mapper::new();
+        mapper.load_parts(2, &prg, &[], 8192).unwrap();
+        assert_eq!(mapper.cpu_peek(0x8000), 0);
+        assert_eq!(mapper.cpu_peek(0xC000), 31);
+
+        mapper.cpu_write(0x8000, 0x1F);
+        assert_eq!(mapper.cpu_peek(0x8000), 31);
+        assert_eq!(mapper.cpu_peek(0xC000), 31);
+        mapper.cpu_write(0x8000, 0x25);
+        assert_eq!(mapper.cpu_peek(0x8000), 5);
+
+        // Smaller boards ignore the high bits
+        let mut mapper = Mapper::new();
+        mapper.load_parts(2, &prg[0..131072], &[], 8192).unwrap();
+        assert_eq!(mapper.cpu_peek(0xC000), 7);
+        mapper.cpu_write(0x8000, 0x0A);
+        assert_eq!(mapper.cpu_peek(0x8000), 2);
+    }
+
+    #[test]
+    fn palette_ram_mirrors_the_backdrop_entries() {
+        let mut mapper = mmc1_mapper();
+        for index in 0..32 {
+            mapper.ppu_write(0x3F00 + index, index as u8);
+        }
+        // $3F10/$3F14/$3F18/$3F1C are the same bytes as $3F00/$3F04/$3F08/$3F0C
+        mapper.ppu_write(0x3F00, 0x2A);
+        mapper.ppu_write(0x3F1C, 0x3B);
+        let palette_ram = mapper.palette_ram();
+        assert_eq!(palette_ram[0x00], 0x2A);
+        assert_eq!(palette_ram[0x10], 0x2A);
+        assert_eq!(palette_ram[0x0C], 0x3B);
+        assert_eq!(palette_ram[0x1C], 0x3B);
+        assert_eq!(palette_ram[0x04], 0x14);
+        assert_eq!(palette_ram[0x11], 0x11);
+        for (index, entry) in palette_ram.iter().enumerate() {
+            assert_eq!(mapper.ppu_peek(0x3F00 + index as u16), *entry);
+        }
+    }
+
+    #[test]
+    fn surom_selects_the_256kb_half_with_chr_bank_0() {
+        let mut mapper = mmc1_mapper();
+        // Bank N starts with N * 7, the last bank of the first half is fixed
+        assert_eq!(mapper.cpu_peek(0x8000), 0);
+        assert_eq!(mapper.cpu_peek(0xC000), 15 * 7);
+
+        mmc1_register(&mut mapper, 0xA000, 0x10);
+        mmc1_register(&mut mapper, 0xE000, 0x02);
+        assert_eq!(mapper.cpu_peek(0x8000), 18 * 7);
+        assert_eq!(mapper.cpu_peek(0xC000), 31 * 7);
+    }
+
+    #[test]
+    fn sorom_and_sxrom_bank_prg_ram() {
+        // SOROM has two 8kb banks on bit 3, SXROM four on bits 2-3
+        for (prgram_size, banks) in [(16384, [0x00, 0x08]), (32768, [0x04, 0x0C])].iter() {
+            let mut mapper = Mapper::new();
+            mapper
+                .load_parts(1, &[0; 262144], &[], *prgram_size)
+                .unwrap();
+            mmc1_register(&mut mapper, 0xA000, banks[0]);
+            mapper.cpu_write(0x6000, 0x11);
+            mmc1_register(&mut mapper, 0xA000, banks[1]);
+            assert_eq!(mapper.cpu_peek(0x6000), 0x00);
+            mapper.cpu_write(0x6000, 0x22);
+
+            mmc1_register(&mut mapper, 0xA000, banks[0]);
+            assert_eq!(mapper.cpu_peek(0x6000), 0x11);
+            mmc1_register(&mut mapper, 0xA000, banks[1]);
+            assert_eq!(mapper.cpu_peek(0x6000), 0x22);
+        }
+    }
+
+    // Loads the bytes through a file like a ROM from disk
+    fn load_bytes(name: &str, data: &[u8]) -> Result<Mapper> {
+        let path = std::env::temp_dir().join(format!("nesrust-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        let mut mapper = Mapper::new();
+        let result = mapper.load(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.map(|_| mapper)
+    }
+
+    fn ines(prg_banks: u8, mapper: u8) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, 0, mapper << 4, 0];
+        rom.resize(16 + prg_banks as usize * 16384, 0);
+        rom
+    }
+
+    #[test]
+    fn load_reports_what_is_wrong_with_the_rom() {
+        let mut mapper = Mapper::new();
+        let missing = std::env::temp_dir().join("nesrust-missing.nes");
+        assert!(matches!(mapper.load(&missing), Err(NesError::Io(_))));
+
+        assert!(matches!(
+            load_bytes("short", b"NES"),
+            Err(NesError::InvalidHeader)
+        ));
+        let mut rom = ines(1, 0);
+        rom[0] = b'M';
+        assert!(matches!(
+            load_bytes("magic", &rom),
+            Err(NesError::InvalidHeader)
+        ));
+        assert!(matches!(
+            load_bytes("empty", &ines(0, 0)),
+            Err(NesError::NoData)
+        ));
+        assert!(matches!(
+            load_bytes("mapper", &ines(1, 4)),
+            Err(NesError::UnsupportedMapper(4))
+        ));
+        let mut rom = ines(2, 0);
+        rom.truncate(20000);
+        assert!(matches!(
+            load_bytes("truncated", &rom),
+            Err(NesError::TruncatedData)
+        ));
+
+        assert!(load_bytes("good", &ines(2, 0)).is_ok());
+    }
+
+    #[test]
+    fn nametable_mirroring_shares_the_two_tables() {
+        let mut mapper = mmc1_mapper();
+        // Horizontal
+        mmc1_register(&mut mapper, 0x8000, 0x0F);
+        mapper.ppu_write(0x2000, 0x01);
+        mapper.ppu_write(0x2BFF, 0x02);
+        assert_eq!(mapper.ppu_peek(0x2400), 0x01);
+        assert_eq!(mapper.ppu_peek(0x2FFF), 0x02);
+        assert_eq!(mapper.ppu_peek(0x23FF), 0x00);
+        assert_eq!(mapper.ppu_peek(0x2800), 0x00);
+
+        // Vertical
+        mmc1_register(&mut mapper, 0x8000, 0x0E);
+        assert_eq!(mapper.ppu_peek(0x2800), 0x01);
+        assert_eq!(mapper.ppu_peek(0x27FF), 0x02);
+    }
+
+    #[test]
+    fn one_screen_mirroring_selects_either_table() {
+        let mut mapper = mmc1_mapper();
+        // Upper
+        mmc1_register(&mut mapper, 0x8000, 0x0D);
+        mapper.ppu_write(0x2000, 0x05);
+        assert_eq!(mapper.ppu_peek(0x2C00), 0x05);
+
+        // Lower
+        mmc1_register(&mut mapper, 0x8000, 0x0C);
+        assert_eq!(mapper.ppu_peek(0x2400), 0x00);
+        mapper.ppu_write(0x2400, 0x06);
+        assert_eq!(mapper.ppu_peek(0x2800), 0x06);
+        mmc1_register(&mut mapper, 0x8000, 0x0D);
+        assert_eq!(mapper.ppu_peek(0x2000), 0x05);
+    }
+
+    #[test]
+    fn nes2_headers_extend_the_ines_fields() {
+        let header = [
+            b'N', b'E', b'S', 0x1A, 0x50, 0x01, 0x31, 0x18, 0x21, 0x0F, 0x70, 0x07, 0x01, 0, 0, 0,
+        ];
+        let ines = InesHeader::parse(&header).unwrap();
+        assert!(ines.nes2);
+        assert_eq!(ines.mapper, 0x113);
+        assert_eq!(ines.submapper, 2);
+        // Exponent form, 2^20 * 1
+        assert_eq!(ines.prg_size, 1 << 20);
+        assert_eq!(ines.chr_size, 8192);
+        assert_eq!(ines.prgram_size, 8192);
+        assert_eq!(ines.chr_ram_size, 8192);
+        assert_eq!(ines.region, 1);
+        assert!(ines.vertical_mirroring);
+
+        // Read as iNES 1.0 byte 8 is the PRG-RAM size, and the nonzero byte 12
+        // looks like a signature that drops the mapper high nibble
+        let mut header = header;
+        header[7] = 0x10;
+        let ines = InesHeader::parse(&header).unwrap();
+        assert!(!ines.nes2);
+        assert_eq!(ines.mapper, 0x03);
+        assert_eq!(ines.prg_size, 0x50 * 16384);
+        assert_eq!(ines.prgram_size, 0x21 * 8192);
+    }
+
+    #[test]
+    fn cnrom_switches_8kb_chr_banks() {
+        // Every CHR bank filled with its number
+        let mut rom = ines(2, 3);
+        rom[5] = 4;
+        for bank in 0..4 {
+            rom.extend_from_slice(&[bank; 8192]);
+        }
+        let mut mapper = load_bytes("cnrom", &rom).unwrap();
+        assert_eq!(mapper.ppu_peek(0x0000), 0);
+
+        mapper.cpu_write(0x8000, 2);
+        assert_eq!(mapper.ppu_peek(0x0000), 2);
+        assert_eq!(mapper.ppu_peek(0x1FFF), 2);
+        // Bank numbers wrap around the CHR size
+        mapper.cpu_write(0xFFFF, 7);
+        assert_eq!(mapper.ppu_peek(0x1000), 3);
+    }
+
+    #[test]
+    fn battery_ram_round_trips_through_a_sav_file() {
+        let mut rom = ines(2, 0);
+        assert!(!load_bytes("no-battery", &rom).unwrap().has_battery());
+        rom[6] |= 0x02;
+        let mut mapper = load_bytes("battery", &rom).unwrap();
+        assert!(mapper.has_battery());
+
+        let path = std::env::temp_dir().join(format!("nesrust-{}.sav", std::process::id()));
+        mapper.cpu_write(0x6000, 0x12);
+        mapper.cpu_write(0x7FFF, 0x34);
+        mapper.save_sram(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 8192);
+
+        let mut loaded = load_bytes("battery", &rom).unwrap();
+        loaded.load_sram(&path).unwrap();
+        assert_eq!(loaded.cpu_peek(0x6000), 0x12);
+        assert_eq!(loaded.cpu_peek(0x7FFF), 0x34);
+
+        std::fs::write(&path, [0; 100]).unwrap();
+        assert!(matches!(
+            loaded.load_sram(&path),
+            Err(NesError::SramSize(100))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_chr_ram_is_writable() {
+        let mut mapper = load_bytes("chr-ram", &ines(2, 0)).unwrap();
+        mapper.ppu_write(0x0010, 0x5A);
+        assert_eq!(mapper.ppu_peek(0x0010), 0x5A);
+
+        let mut rom = ines(2, 0);
+        rom[5] = 1;
+        rom.extend_from_slice(&[0xA5; 8192]);
+        let mut mapper = load_bytes("chr-rom", &rom).unwrap();
+        mapper.ppu_write(0x0010, 0x5A);
+        assert_eq!(mapper.ppu_peek(0x0010), 0xA5);
+    }
+
+    #[test]
+    fn header_sets_the_nametable_mirroring() {
+        // Horizontal unless the header says otherwise
+        let mut mapper = load_bytes("horizontal", &ines(2, 0)).unwrap();
+        mapper.ppu_write(0x2000, 0x01);
+        assert_eq!(mapper.ppu_peek(0x2400), 0x01);
+
+        let mut rom = ines(2, 0);
+        rom[6] |= 0x01;
+        let mut mapper = load_bytes("vertical", &rom).unwrap();
+        mapper.ppu_write(0x2000, 0x01);
+        assert_eq!(mapper.ppu_peek(0x2800), 0x01);
+        assert_eq!(mapper.ppu_peek(0x2400), 0x00);
+
+        // Four screen wins over the vertical bit
+        rom[6] |= 0x08;
+        let mut mapper = load_bytes("four-screen", &rom).unwrap();
+        for (index, table) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            mapper.ppu_write(*table, index as u8 + 1);
+        }
+        for (index, table) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(mapper.ppu_peek(*table), index as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn mmc1_prg_ram_disable_bit_blocks_access() {
+        let mut mapper = mmc1_mapper();
+        mapper.cpu_write(0x6000, 0x42);
+
+        mmc1_register(&mut mapper, 0xE000, 0x10);
+        assert!(!mapper.prgram_enabled());
+        mapper.cpu_write(0x6000, 0x99);
+
+        // The contents survive while the RAM is disabled
+        mmc1_register(&mut mapper, 0xE000, 0x00);
+        assert!(mapper.prgram_enabled());
+        assert_eq!(mapper.cpu_peek(0x6000), 0x42);
+    }
+
+    fn unif_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn load_unif_picks_the_mapper_from_the_board_name() {
+        let mut prg = vec![0; 32768];
+        prg[0] = 0x11;
+        prg[0x7FFF] = 0x22;
+        let mut chr = vec![0; 8192];
+        chr[0] = 0x33;
+
+        let mut rom = b"UNIF".to_vec();
+        rom.extend_from_slice(&7u32.to_le_bytes());
+        rom.resize(32, 0);
+        rom.extend(unif_chunk(b"MAPR", b"NES-CNROM\0"));
+        rom.extend(unif_chunk(b"PRG0", &prg));
+        rom.extend(unif_chunk(b"CHR0", &chr));
+
+        let mut mapper = load_bytes("board.unf", &rom).unwrap();
+        assert_eq!(mapper.mapper, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 0x11);
+        assert_eq!(mapper.cpu_read(0xFFFF), 0x22);
+        assert_eq!(mapper.ppu_read(0x0000), 0x33);
+
+        let mut unknown = b"UNIF".to_vec();
+        unknown.resize(32, 0);
+        unknown.extend(unif_chunk(b"MAPR", b"NES-TLROM\0"));
+        unknown.extend(unif_chunk(b"PRG0", &prg));
+        assert!(matches!(
+            load_bytes("unknown.unf", &unknown),
+            Err(NesError::UnsupportedBoard(board)) if board == "NES-TLROM"
+        ));
+    }
+
+    #[test]
+    fn set_nametable_byte_follows_the_mirroring() {
+        let mut mapper = Mapper::new();
+        mapper.load_parts(0, &[0; 16384], &[], 8192).unwrap();
+        // (mirroring, the nametables showing the byte written to nametable 1)
+        let cases = [
+            (0, [true, true, true, true]),
+            (1, [true, true, true, true]),
+            (2, [false, true, false, true]),
+            (3, [true, true, false, false]),
+            (4, [false, true, false, false]),
+        ];
+        for &(mirroring, visible) in cases.iter() {
+            mapper.mirroring = mirroring;
+            mapper.nametables = [0; 4096];
+            mapper.set_nametable_byte(1, 0x21, 0x5A);
+            for (nametable, &shown) in visible.iter().enumerate() {
+                let address = 0x2000 + nametable as u16 * 0x400 + 0x21;
+                let expected = if shown { 0x5A } else { 0 };
+                assert_eq!(
+                    mapper.ppu_read(address),
+                    expected,
+                    "mirroring {}",
+                    mirroring
+                );
+            }
+        }
+    }
 }
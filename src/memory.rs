@@ -2,11 +2,30 @@ pub struct Memory {
     ram: [u8; 2048],
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
 impl Memory {
     pub fn new() -> Memory {
         Memory { ram: [0; 2048] }
     }
 
+    // Work RAM is only cleared on power on, a reset keeps its contents
+    pub fn power_on(&mut self) {
+        self.ram = [0; 2048];
+    }
+
+    pub fn ram(&self) -> &[u8; 2048] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8; 2048] {
+        &mut self.ram
+    }
+
     pub fn cpu_write(&mut self, address: u16, data: u8) {
         if address < 0x2000 {
             // println!("Write memory: {:#04X}: {:#02X}\n", address & 0x7FF, data);
@@ -21,4 +40,11 @@ impl Memory {
         }
         0
     }
+
+    pub fn cpu_peek(&self, address: u16) -> u8 {
+        if address < 0x2000 {
+            return self.ram[address as usize & 0x7FF];
+        }
+        0
+    }
 }
@@ -0,0 +1,176 @@
+use crate::error::{NesError, Result};
+use crate::nes::Nes;
+use crate::savestate::StateReader;
+use std::path::Path;
+
+const MAGIC: [u8; 8] = *b"NESRUSTM";
+const VERSION: u32 = 1;
+
+// Controller bytes for every frame since a known machine state. The start
+// state is a versioned save state, so it carries the ROM hash and refuses to
+// play on another game or build
+pub struct Movie {
+    start_state: Vec<u8>,
+    frames: Vec<[u8; 2]>,
+}
+
+impl Movie {
+    // Recording starts from the machine as it is, usually right after power on
+    pub fn start(nes: &Nes) -> Self {
+        Movie {
+            start_state: nes.save_state_versioned(),
+            frames: Vec::new(),
+        }
+    }
+
+    // Called right before each frame runs, with the input for that frame
+    pub fn record_frame(&mut self, nes: &Nes) {
+        self.frames
+            .push([nes.joystick.state(0), nes.joystick.state(1)]);
+    }
+
+    // Puts the machine back in the state the recording started from
+    pub fn restore_start(&self, nes: &mut Nes) -> Result<()> {
+        nes.load_state_versioned(&self.start_state)
+    }
+
+    // Feeds the input of a frame to the controllers, false past the end
+    pub fn play_frame(&self, nes: &mut Nes, frame: usize) -> bool {
+        match self.frames.get(frame) {
+            Some(input) => {
+                nes.set_buttons(0, input[0]);
+                nes.set_buttons(1, input[1]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // Plays the whole movie with no frontend, one run_frame per recorded frame
+    pub fn play(&self, nes: &mut Nes) -> Result<()> {
+        self.restore_start(nes)?;
+        for frame in 0..self.frames.len() {
+            self.play_frame(nes, frame);
+            nes.run_frame();
+        }
+        Ok(())
+    }
+
+    // Layout: magic, version (u32 LE), start state length (u64 LE), start
+    // state, then two controller bytes per frame
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(20 + self.start_state.len() + self.frames.len() * 2);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(self.start_state.len() as u64).to_le_bytes());
+        data.extend_from_slice(&self.start_state);
+        for input in self.frames.iter() {
+            data.extend_from_slice(input);
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() < 12 || data[0..8] != MAGIC {
+            return Err(NesError::InvalidMovie);
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&data[8..12]);
+        if u32::from_le_bytes(version) != VERSION {
+            return Err(NesError::InvalidMovie);
+        }
+
+        let mut reader = StateReader::new(&data[12..]);
+        let start_state = reader
+            .section()
+            .map_err(|_| NesError::InvalidMovie)?
+            .to_vec();
+        let inputs = reader
+            .bytes(data.len() - 20 - start_state.len())
+            .map_err(|_| NesError::InvalidMovie)?;
+        if inputs.len() % 2 != 0 {
+            return Err(NesError::InvalidMovie);
+        }
+        let frames = inputs.chunks(2).map(|input| [input[0], input[1]]).collect();
+        Ok(Movie {
+            start_state,
+            frames,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::PeekOps;
+
+    // Adds the A button of controller 1 into $10 as fast as it can read it
+    fn input_counting_nes() -> Box<Nes> {
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x29,
+            0x01, 0x18, 0x65, 0x10, 0x85, 0x10, 0x4C, 0x00, 0x80,
+        ];
+        let mut prg = vec![0; 32768];
+        prg[0..program.len()].copy_from_slice(&program);
+        prg[0x7FFD] = 0x80;
+        let mut mapper = crate::mapper::Mapper::new();
+        mapper.load_parts(0, &prg, &[], 8192).unwrap();
+        Box::new(Nes::new(mapper))
+    }
+
+    #[test]
+    fn played_movie_ends_in_the_recorded_state() {
+        let mut nes = input_counting_nes();
+        let mut movie = Movie::start(&nes);
+        for frame in 0..6 {
+            nes.set_buttons(0, if frame % 3 == 0 { 0x80 } else { 0x00 });
+            movie.record_frame(&nes);
+            nes.run_frame();
+        }
+        assert_eq!(movie.len(), 6);
+        let recorded = nes.save_state_versioned();
+        assert_ne!(nes.peek_bus().peek(0x0010), 0);
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        let mut replay = input_counting_nes();
+        replay.set_buttons(0, 0x80);
+        replay.run_frames(2);
+        movie.play(&mut replay).unwrap();
+        assert!(replay.save_state_versioned() == recorded);
+    }
+
+    #[test]
+    fn movie_files_are_checked() {
+        let nes = input_counting_nes();
+        let mut data = Movie::start(&nes).to_bytes();
+        assert!(Movie::from_bytes(&data).unwrap().is_empty());
+
+        data.push(0x80);
+        assert!(matches!(
+            Movie::from_bytes(&data),
+            Err(NesError::InvalidMovie)
+        ));
+        data[0] = b'X';
+        assert!(matches!(
+            Movie::from_bytes(&data),
+            Err(NesError::InvalidMovie)
+        ));
+    }
+}
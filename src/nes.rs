@@ -0,0 +1,1056 @@
+use crate::bus::PeekOps;
+use crate::error::{NesError, Result};
+use crate::{apu, cpu, dma, joystick, mapper, memory, ppu, savestate};
+use std::path::Path;
+
+struct CpuBus<'a> {
+    mapper: &'a mut mapper::Mapper,
+    memory: &'a mut memory::Memory,
+    ppu: &'a mut ppu::Ppu,
+    dma: &'a mut dma::Dma,
+    apu: &'a mut apu::Apu,
+    joystick: &'a mut joystick::Joystick,
+    open_bus: &'a mut u8,
+    read_address: &'a mut Option<u16>,
+}
+
+impl<'a> cpu::BusOps for CpuBus<'a> {
+    fn write(&mut self, address: u16, data: u8) {
+        self.mapper.cpu_write(address, data);
+        self.memory.cpu_write(address, data);
+        self.joystick.cpu_write(address, data);
+        self.dma.cpu_write(address, data);
+        self.apu.cpu_write(address, data);
+        self.ppu
+            .cpu_write(&mut self.mapper.ppu_bus(), address, data);
+        *self.open_bus = data;
+        *self.read_address = None;
+    }
+
+    // Every address range has a single owner, anything nobody drives reads
+    // back the last value on the bus
+    fn read(&mut self, address: u16) -> u8 {
+        let data = match address {
+            0x0000..=0x1FFF => self.memory.cpu_read(address),
+            0x2000..=0x3FFF => self.ppu.cpu_read(&mut self.mapper.ppu_bus(), address),
+            // Only the low bits of the controller ports are driven
+            0x4016 | 0x4017 => (*self.open_bus & 0xE0) | self.joystick.cpu_read(address),
+            // Bit 5 isn't driven by the APU
+            0x4015 => (*self.open_bus & 0x20) | self.apu.cpu_read(address),
+            // Write-only APU registers and unused IO space
+            0x4000..=0x401F => *self.open_bus,
+            // Disabled PRG-RAM
+            0x6000..=0x7FFF if !self.mapper.prgram_enabled() => *self.open_bus,
+            _ => self.mapper.cpu_read(address),
+        };
+        *self.open_bus = data;
+        *self.read_address = Some(address);
+        data
+    }
+}
+
+impl<'a> PeekOps for CpuBus<'a> {
+    fn peek(&self, address: u16) -> u8 {
+        PeekBus {
+            mapper: self.mapper,
+            memory: self.memory,
+            ppu: self.ppu,
+            apu: self.apu,
+            joystick: self.joystick,
+            open_bus: *self.open_bus,
+        }
+        .peek(address)
+    }
+}
+
+// Read only view of the CPU address space for memory viewers and debuggers
+pub struct PeekBus<'a> {
+    mapper: &'a mapper::Mapper,
+    memory: &'a memory::Memory,
+    ppu: &'a ppu::Ppu,
+    apu: &'a apu::Apu,
+    joystick: &'a joystick::Joystick,
+    open_bus: u8,
+}
+
+impl<'a> PeekOps for PeekBus<'a> {
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.memory.cpu_peek(address),
+            0x2000..=0x3FFF => self.ppu.cpu_peek(&self.mapper.ppu_peek_bus(), address),
+            0x4016 | 0x4017 => (self.open_bus & 0xE0) | self.joystick.cpu_peek(address),
+            0x4015 => (self.open_bus & 0x20) | self.apu.cpu_peek(address),
+            0x4000..=0x401F => self.open_bus,
+            0x6000..=0x7FFF if !self.mapper.prgram_enabled() => self.open_bus,
+            _ => self.mapper.cpu_peek(address),
+        }
+    }
+}
+
+struct DmaBus<'a> {
+    mapper: &'a mut mapper::Mapper,
+    memory: &'a mut memory::Memory,
+    ppu: &'a mut ppu::Ppu,
+    joystick: &'a mut joystick::Joystick,
+}
+
+impl<'a> DmaBus<'a> {
+    fn new(
+        mapper: &'a mut mapper::Mapper,
+        memory: &'a mut memory::Memory,
+        ppu: &'a mut ppu::Ppu,
+        joystick: &'a mut joystick::Joystick,
+    ) -> Self {
+        DmaBus {
+            mapper,
+            memory,
+            ppu,
+            joystick,
+        }
+    }
+}
+
+impl<'a> cpu::BusOps for DmaBus<'a> {
+    fn write(&mut self, address: u16, data: u8) {
+        self.mapper.cpu_write(address, data);
+        self.memory.cpu_write(address, data);
+        self.joystick.cpu_write(address, data);
+        self.ppu
+            .cpu_write(&mut self.mapper.ppu_bus(), address, data);
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.memory.cpu_read(address),
+            0x2000..=0x3FFF => self.ppu.cpu_read(&mut self.mapper.ppu_bus(), address),
+            0x4016 | 0x4017 => self.joystick.cpu_read(address),
+            0x4000..=0x401F => 0,
+            _ => self.mapper.cpu_read(address),
+        }
+    }
+}
+
+// 341 * 262 / 3 rounded up, the odd frame skipped dot is ignored
+pub const CPU_CYCLES_PER_FRAME: u64 = 29781;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CaptureEvent {
+    // Sprite 0 hit flag set while on the given scanline
+    SpriteZeroHit(u16),
+    // CPU about to fetch an instruction at the given address
+    Breakpoint(u16),
+    // The given opcode was fetched
+    Opcode(u8),
+}
+
+pub struct Nes {
+    pub cpu: cpu::Cpu6502,
+    pub ppu: ppu::Ppu,
+    pub mapper: mapper::Mapper,
+    pub memory: memory::Memory,
+    pub dma: dma::Dma,
+    pub apu: apu::Apu,
+    pub joystick: joystick::Joystick,
+    tick_offset: usize,
+    cpu_cycles: u64,
+    instructions: u64,
+    stop_on_error: bool,
+    halted: bool,
+    cheats: Vec<(u16, u8)>,
+    open_bus: u8,
+    // Address read by the last CPU cycle, None when it was a write
+    cpu_read_address: Option<u16>,
+    capture_event: Option<CaptureEvent>,
+    capture: Option<Vec<u8>>,
+}
+
+impl Nes {
+    pub fn new(mapper: mapper::Mapper) -> Self {
+        let mut nes = Nes {
+            cpu: cpu::Cpu6502::new(),
+            ppu: ppu::Ppu::new(),
+            mapper,
+            memory: memory::Memory::new(),
+            dma: dma::Dma::new(),
+            apu: apu::Apu::new(),
+            joystick: joystick::Joystick::new(),
+            tick_offset: 0,
+            cpu_cycles: 0,
+            instructions: 0,
+            stop_on_error: false,
+            halted: false,
+            cheats: Vec::new(),
+            open_bus: 0,
+            cpu_read_address: None,
+            capture_event: None,
+            capture: None,
+        };
+        nes.power_on();
+        nes
+    }
+
+    // Swaps in the cartridge from a ROM file and powers the machine on
+    pub fn load_rom(&mut self, path: &Path) -> Result<()> {
+        let mut mapper = mapper::Mapper::new();
+        mapper.load(path)?;
+        self.mapper = mapper;
+        self.power_on();
+        Ok(())
+    }
+
+    pub fn power_on(&mut self) {
+        self.cpu.power_on();
+        self.ppu.power_on();
+        self.mapper.power_on();
+        self.memory.power_on();
+        self.dma.power_on();
+        self.apu.power_on();
+        self.joystick.power_on();
+        self.tick_offset = 0;
+        self.open_bus = 0;
+        self.cpu_read_address = None;
+        self.halted = false;
+    }
+
+    // Work RAM, PRG-RAM, VRAM and OAM survive a reset
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.ppu.reset();
+        self.mapper.reset();
+        self.dma.reset();
+        self.apu.reset();
+        self.joystick.reset();
+        self.halted = false;
+    }
+
+    // Advances the system by a single PPU dot, running the CPU every third one
+    pub fn tick(&mut self) {
+        self.tick_offset += 1;
+        if self.ppu.nmi_state {
+            self.ppu.nmi_state = false;
+            self.cpu.set_nmi();
+        }
+
+        if self.tick_offset == 3 {
+            self.tick_offset = 0;
+            self.cpu_cycles += 1;
+            self.mapper.cpu_clock();
+            self.apu.tick();
+            self.cpu.set_irq(self.mapper.irq() || self.apu.irq());
+            if let Some(address) = self.apu.dmc_request() {
+                // A controller read caught by the stall is repeated, see
+                // Joystick::dmc_conflict
+                if let Some(address @ 0x4016..=0x4017) = self.cpu_read_address {
+                    if !self.dma.active() {
+                        self.joystick.dmc_conflict(address);
+                    }
+                }
+                self.dma.request_dmc(address);
+            }
+
+            if self.dma.active() {
+                let mut bus = DmaBus::new(
+                    &mut self.mapper,
+                    &mut self.memory,
+                    &mut self.ppu,
+                    &mut self.joystick,
+                );
+                if let Some(sample) = self.dma.execute(&mut bus, self.cpu_cycles) {
+                    self.apu.dmc_fill(sample);
+                }
+            } else if !self.halted {
+                if self.cpu.stage == 1
+                    && self.capture_event == Some(CaptureEvent::Breakpoint(self.cpu.pc))
+                {
+                    self.take_screenshot();
+                }
+                let opcode_count = match self.capture_event {
+                    Some(CaptureEvent::Opcode(opcode)) => self.cpu.opcode_counts()[opcode as usize],
+                    _ => 0,
+                };
+
+                let result = {
+                    let mut bus = CpuBus {
+                        mapper: &mut self.mapper,
+                        memory: &mut self.memory,
+                        ppu: &mut self.ppu,
+                        dma: &mut self.dma,
+                        apu: &mut self.apu,
+                        joystick: &mut self.joystick,
+                        open_bus: &mut self.open_bus,
+                        read_address: &mut self.cpu_read_address,
+                    };
+                    self.cpu.tick(&mut bus)
+                };
+
+                if let Some(CaptureEvent::Opcode(opcode)) = self.capture_event {
+                    if self.cpu.opcode_counts()[opcode as usize] != opcode_count {
+                        self.take_screenshot();
+                    }
+                }
+
+                match result {
+                    cpu::CycleResult::EndInstruction => self.instructions += 1,
+                    cpu::CycleResult::Error => {
+                        if !self.stop_on_error {
+                            panic!("Error stage: {:#02X}", self.cpu.stage);
+                        }
+                        self.halted = true;
+                    }
+                    // Only a reset brings a jammed CPU back
+                    cpu::CycleResult::Halted => self.halted = true,
+                    _ => {}
+                }
+            }
+        }
+
+        let sprite_zero_hit = self.ppu.sprite_zero_hit();
+        self.ppu.tick(&mut self.mapper.ppu_bus());
+        if let Some(CaptureEvent::SpriteZeroHit(scanline)) = self.capture_event {
+            if !sprite_zero_hit && self.ppu.sprite_zero_hit() && self.ppu.position().1 == scanline {
+                self.take_screenshot();
+            }
+        }
+    }
+
+    // The whole machine along with the CPU/PPU alignment, so a restored state
+    // resumes on the same dot of the same CPU cycle
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.tick_offset as u8, self.halted as u8, self.open_bus];
+        state.push(self.cpu_read_address.is_some() as u8);
+        state.extend_from_slice(&self.cpu_read_address.unwrap_or(0).to_le_bytes());
+        state.extend_from_slice(&self.cpu_cycles.to_le_bytes());
+        state.extend_from_slice(&self.instructions.to_le_bytes());
+        savestate::write_section(&mut state, &self.cpu.to_bytes());
+        savestate::write_section(&mut state, &self.ppu.to_bytes());
+        savestate::write_section(&mut state, &self.mapper.to_bytes());
+        savestate::write_section(&mut state, self.memory.ram());
+        savestate::write_section(&mut state, &self.dma.to_bytes());
+        savestate::write_section(&mut state, &self.joystick.to_bytes());
+        savestate::write_section(&mut state, &self.apu.to_bytes());
+        state
+    }
+
+    // All or nothing, the sections are checked one at a time as they are
+    // applied so the machine is put back from a snapshot if any is rejected
+    fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let snapshot = self.save_state();
+        let result = self.apply_state(state);
+        if result.is_err() {
+            self.apply_state(&snapshot)
+                .expect("the machine loads its own state");
+        }
+        result
+    }
+
+    fn apply_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = savestate::StateReader::new(state);
+        let tick_offset = reader.u8()? as usize;
+        if tick_offset >= 3 {
+            return Err(NesError::InvalidState);
+        }
+        self.tick_offset = tick_offset;
+        self.halted = reader.bool()?;
+        self.open_bus = reader.u8()?;
+        let read = reader.bool()?;
+        let read_address = reader.u16()?;
+        self.cpu_read_address = if read { Some(read_address) } else { None };
+        self.cpu_cycles = reader.u64()?;
+        self.instructions = reader.u64()?;
+        self.cpu.from_bytes(reader.section()?)?;
+        self.ppu.from_bytes(reader.section()?)?;
+        self.mapper.from_bytes(reader.section()?)?;
+        let ram = reader.section()?;
+        if ram.len() != self.memory.ram().len() {
+            return Err(NesError::InvalidState);
+        }
+        self.memory.ram_mut().copy_from_slice(ram);
+        self.dma.from_bytes(reader.section()?)?;
+        self.joystick.from_bytes(reader.section()?)?;
+        self.apu.from_bytes(reader.section()?)?;
+        reader.finish()
+    }
+
+    pub fn save_state_versioned(&self) -> Vec<u8> {
+        savestate::wrap(
+            savestate::REGION_NTSC,
+            self.mapper.rom_hash(),
+            &self.save_state(),
+        )
+    }
+
+    // Rejects states from other versions, regions or ROMs before touching
+    // the machine
+    pub fn load_state_versioned(&mut self, data: &[u8]) -> Result<()> {
+        let state = savestate::unwrap(data, savestate::REGION_NTSC, self.mapper.rom_hash())?;
+        self.load_state(state)
+    }
+
+    pub fn save_state_file(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.save_state_versioned())?;
+        Ok(())
+    }
+
+    pub fn load_state_file(&mut self, path: &Path) -> Result<()> {
+        let data = std::fs::read(path)?;
+        self.load_state_versioned(&data)
+    }
+
+    // Arms a one shot capture of the framebuffer, as rendered so far, for the
+    // next time the event happens
+    pub fn capture_on(&mut self, event: Option<CaptureEvent>) {
+        self.capture_event = event;
+    }
+
+    pub fn take_capture(&mut self) -> Option<Vec<u8>> {
+        self.capture.take()
+    }
+
+    fn take_screenshot(&mut self) {
+        self.capture = Some(self.framebuffer().to_vec());
+        self.capture_event = None;
+    }
+
+    pub fn run_frame(&mut self) {
+        loop {
+            self.tick();
+            if self.ppu.fetch_frame() {
+                break;
+            }
+        }
+        self.apply_cheats();
+    }
+
+    // Fixed number of frames with no frontend involved, from the same power on
+    // state the result is always the same
+    pub fn run_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.run_frame();
+        }
+    }
+
+    // Runs until the frame completes or the cycle budget is spent, returns
+    // whether the frame completed. At least one CPU cycle is always run so a
+    // caller looping on it keeps making progress
+    pub fn run_partial_frame(&mut self, cycles: u64) -> bool {
+        let target = self.cpu_cycles + cycles.max(1);
+        while self.cpu_cycles < target {
+            self.tick();
+            if self.ppu.fetch_frame() {
+                self.apply_cheats();
+                return true;
+            }
+        }
+        false
+    }
+
+    // Stops right after the dot that sets the vblank flag (and raises NMI if enabled)
+    pub fn run_to_vblank(&mut self) {
+        loop {
+            let vblank_start = self.ppu.position() == (1, 241);
+            self.tick();
+            if vblank_start {
+                self.ppu.fetch_frame();
+                break;
+            }
+        }
+        self.apply_cheats();
+    }
+
+    pub fn add_cheat(&mut self, address: u16, value: u8) {
+        self.cheats
+            .retain(|&(cheat_address, _)| cheat_address != address);
+        self.cheats.push((address, value));
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    // Pins the cheat locations once per frame, only work RAM and PRG-RAM are
+    // touched so a cheat can't trigger register side effects
+    fn apply_cheats(&mut self) {
+        for &(address, value) in &self.cheats {
+            if address < 0x2000 {
+                self.memory.cpu_write(address, value);
+            } else if (0x6000..=0x7FFF).contains(&address) {
+                self.mapper.cpu_write(address, value);
+            }
+        }
+    }
+
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles
+    }
+
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let target = self.cpu_cycles + cycles;
+        while self.cpu_cycles < target {
+            self.tick();
+        }
+    }
+
+    // With this set an illegal/halting opcode freezes the CPU instead of
+    // panicking, the PPU keeps running
+    pub fn set_stop_on_error(&mut self, stop_on_error: bool) {
+        self.stop_on_error = stop_on_error;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // An STP opcode jammed the CPU, the machine stays frozen until a reset
+    pub fn cpu_jammed(&self) -> bool {
+        self.cpu.halted()
+    }
+
+    // Steps until the CPU finishes the given number of instructions (interrupt
+    // sequences included) or halts, returns how many were executed
+    pub fn run_instructions(&mut self, count: u64) -> u64 {
+        let target = self.instructions + count;
+        let start = self.instructions;
+        while self.instructions < target && !self.halted {
+            self.tick();
+        }
+        self.instructions - start
+    }
+
+    pub fn peek_bus(&self) -> PeekBus<'_> {
+        PeekBus {
+            mapper: &self.mapper,
+            memory: &self.memory,
+            ppu: &self.ppu,
+            apu: &self.apu,
+            joystick: &self.joystick,
+            open_bus: self.open_bus,
+        }
+    }
+
+    // Whole controller state for one player, see joystick::Button for the bits
+    pub fn set_buttons(&mut self, player: u8, buttons: u8) {
+        self.joystick.set_state(player, buttons);
+    }
+
+    pub fn press(&mut self, port: usize, button: joystick::Button) {
+        self.joystick.press(port, button);
+    }
+
+    pub fn release(&mut self, port: usize, button: joystick::Button) {
+        self.joystick.release(port, button);
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.ppu.pixels
+    }
+
+    // Runs up to the given absolute CPU cycle and returns a copy of the frame
+    // as it is at that point, partially rendered frames included
+    pub fn capture_frame_at_cycle(&mut self, cycle: u64) -> Vec<u8> {
+        if cycle > self.cpu_cycles {
+            self.run_cycles(cycle - self.cpu_cycles);
+        }
+        self.framebuffer().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NROM cartridge with the program at $8000 and every vector pointing at
+    // it, boxed since a few machines don't fit on a test thread stack
+    fn nes_with_program(program: &[u8]) -> Box<Nes> {
+        let mut prg = vec![0xEA; 32768];
+        prg[0..program.len()].copy_from_slice(program);
+        for vector in [cpu::NMI_VECTOR, cpu::RESET_VECTOR, cpu::IRQ_VECTOR].iter() {
+            let offset = (*vector - 0x8000) as usize;
+            prg[offset] = 0x00;
+            prg[offset + 1] = 0x80;
+        }
+        let mut mapper = mapper::Mapper::new();
+        mapper.load_parts(0, &prg, &[], 8192).unwrap();
+        Box::new(Nes::new(mapper))
+    }
+
+    #[test]
+    fn run_instructions_returns_when_stp_jams_the_cpu() {
+        // LDA #$01, STP, LDA #$02
+        let mut nes = nes_with_program(&[0xA9, 0x01, 0x02, 0xA9, 0x02]);
+        // Reset sequence, LDA and the STP itself
+        assert_eq!(nes.run_instructions(1000), 2);
+        assert!(nes.halted());
+        assert!(nes.cpu_jammed());
+        assert_eq!(nes.cpu.a, 0x01);
+        assert_eq!(nes.run_instructions(10), 0);
+    }
+
+    #[test]
+    fn run_partial_frame_always_makes_progress() {
+        let mut nes = nes_with_program(&[]);
+        let cycles = nes.cpu_cycles();
+        assert!(!nes.run_partial_frame(0));
+        assert_eq!(nes.cpu_cycles(), cycles + 1);
+
+        let mut calls = 0;
+        while !nes.run_partial_frame(0) {
+            calls += 1;
+        }
+        assert!(calls <= CPU_CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn input_set_between_partial_runs_is_read_in_the_same_frame() {
+        // Strobe, read the A button of controller 1 into $10 without the
+        // open bus bits, repeat
+        let mut nes = nes_with_program(&[
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x29,
+            0x01, 0x85, 0x10, 0x4C, 0x00, 0x80,
+        ]);
+        nes.run_frame();
+
+        assert!(!nes.run_partial_frame(1000));
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x00);
+        nes.set_buttons(0, 0x80);
+        assert!(!nes.run_partial_frame(1000));
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x01);
+        nes.set_buttons(0, 0x00);
+        assert!(!nes.run_partial_frame(1000));
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x00);
+    }
+
+    #[test]
+    fn power_on_starts_at_the_reset_vector() {
+        // LDX #$07 at $9000, LDA #$55 at the old hardcoded $C000
+        let mut prg = vec![0xEA; 32768];
+        prg[0x1000..0x1002].copy_from_slice(&[0xA2, 0x07]);
+        prg[0x4000..0x4002].copy_from_slice(&[0xA9, 0x55]);
+        let reset = (cpu::RESET_VECTOR - 0x8000) as usize;
+        prg[reset] = 0x00;
+        prg[reset + 1] = 0x90;
+        let mut mapper = mapper::Mapper::new();
+        mapper.load_parts(0, &prg, &[], 8192).unwrap();
+        let mut nes = Nes::new(mapper);
+
+        while nes.cpu.x != 0x07 {
+            assert!(nes.cpu_cycles() < 20);
+            nes.tick();
+        }
+        assert_eq!(nes.cpu.a, 0x00);
+        assert_eq!(nes.cpu.pc, 0x9002);
+    }
+
+    // Starts a DMC sample right away, its first fetch is requested on the
+    // next CPU cycle
+    fn start_dmc(nes: &mut Nes) {
+        nes.apu.cpu_write(0x4013, 0x01);
+        nes.apu.cpu_write(0x4015, 0x10);
+        for _ in 0..3 {
+            nes.tick();
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_repeats_a_controller_read() {
+        // LDA $4016
+        let mut nes = nes_with_program(&[0xAD, 0x16, 0x40]);
+        while nes.cpu_read_address != Some(0x4016) {
+            assert!(nes.cpu_cycles() < 20);
+            nes.tick();
+        }
+        start_dmc(&mut nes);
+        // The CPU read and the repeated one
+        assert_eq!(nes.joystick.to_bytes()[1], 2);
+    }
+
+    #[test]
+    fn dmc_fetch_ignores_a_controller_write() {
+        // LDA #$00, STA $4016, NOP
+        let mut nes = nes_with_program(&[0xA9, 0x00, 0x8D, 0x16, 0x40, 0xEA]);
+        nes.run_instructions(3);
+        assert_eq!(nes.cpu.address, 0x4016);
+        start_dmc(&mut nes);
+        assert_eq!(nes.joystick.to_bytes()[1], 0);
+    }
+
+    #[test]
+    fn rejected_state_leaves_the_machine_untouched() {
+        let mut nes = nes_with_program(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        let mut state = nes.save_state_versioned();
+        nes.run_frames(2);
+        let before = nes.save_state();
+
+        // The last section, the APU, is cut short after everything else
+        // already read fine
+        state.pop();
+        assert!(nes.load_state_versioned(&state).is_err());
+        assert!(nes.save_state() == before);
+    }
+
+    #[test]
+    fn state_from_another_version_or_rom_is_rejected() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let state = nes.save_state_versioned();
+
+        let mut newer = state.clone();
+        newer[8..12].copy_from_slice(&(savestate::VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            nes.load_state_versioned(&newer),
+            Err(NesError::StateVersion(version)) if version == savestate::VERSION + 1
+        ));
+
+        let mut other = nes_with_program(&[0xEA, 0x4C, 0x00, 0x80]);
+        assert!(matches!(
+            other.load_state_versioned(&state),
+            Err(NesError::StateRomMismatch)
+        ));
+        assert!(nes.load_state_versioned(&state).is_ok());
+    }
+
+    #[test]
+    fn run_to_vblank_stops_on_the_vblank_dot() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_to_vblank();
+        assert_eq!(nes.ppu.position(), (2, 241));
+        let status = nes.ppu.cpu_peek(&nes.mapper.ppu_peek_bus(), 0x2002);
+        assert_eq!(status & 0x80, 0x80);
+
+        // A whole frame apart, 341 * 262 dots
+        let cycles = nes.cpu_cycles();
+        nes.run_to_vblank();
+        assert_eq!(nes.ppu.position(), (2, 241));
+        let elapsed = nes.cpu_cycles() - cycles;
+        assert!(elapsed == 29780 || elapsed == 29781);
+    }
+
+    #[test]
+    fn capture_frame_at_cycle_keeps_partial_frames() {
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.mapper.ppu_write(0x3F00, 0x21);
+
+        // About line 100 of the first frame, the backdrop is only drawn so far
+        let frame = nes.capture_frame_at_cycle(100 * 341 / 3);
+        assert_eq!(nes.cpu_cycles(), 100 * 341 / 3);
+        let row = |y: usize| &frame[y * 256 * 4..y * 256 * 4 + 4];
+        assert_eq!(row(50), [76, 154, 236, 0xFF]);
+        assert_eq!(row(200), [0, 0, 0, 0]);
+
+        // Past cycles can't be captured, the current frame is returned as is
+        let again = nes.capture_frame_at_cycle(10);
+        assert_eq!(nes.cpu_cycles(), 100 * 341 / 3);
+        assert!(again == frame);
+    }
+
+    #[test]
+    fn edited_nametable_byte_shows_up_in_the_next_frame() {
+        // LDA #$0A, STA $2001, JMP $8005
+        let mut nes = nes_with_program(&[0xA9, 0x0A, 0x8D, 0x01, 0x20, 0x4C, 0x05, 0x80]);
+        for address in 0x0010..0x0020 {
+            nes.mapper.ppu_write(address, 0xFF);
+        }
+        nes.mapper.ppu_write(0x3F00, 0x21);
+        nes.mapper.ppu_write(0x3F03, 0x30);
+        nes.run_frames(2);
+        assert!(nes.ppu.indexed_pixels.iter().all(|&color| color == 0x21));
+
+        // Tile 1 at row 1, column 2 of nametable 3, the single screen
+        // mirroring of the cartridge shows it through nametable 0
+        nes.mapper.set_nametable_byte(3, 0x22, 0x01);
+        nes.run_frame();
+        let pixel = |x: usize, y: usize| nes.ppu.indexed_pixels[y * 256 + x];
+        assert_eq!(pixel(16, 8), 0x30);
+        assert_eq!(pixel(23, 15), 0x30);
+        assert_eq!(pixel(15, 8), 0x21);
+        assert_eq!(pixel(24, 15), 0x21);
+        assert_eq!(pixel(16, 16), 0x21);
+    }
+
+    #[test]
+    fn mapper_cpu_cycle_irq_reaches_the_cpu() {
+        // CLI, JMP $8001
+        let mut nes = nes_with_program(&[0x58, 0x4C, 0x01, 0x80]);
+        nes.run_instructions(2);
+        let sp = nes.cpu.sp;
+
+        nes.mapper.set_irq_counter(100);
+        nes.run_cycles(99);
+        assert!(!nes.mapper.irq());
+        assert!(!nes.cpu.irq_line());
+        nes.run_cycles(1);
+        assert!(nes.mapper.irq());
+        assert!(nes.cpu.irq_line());
+
+        // Taken at the end of the JMP, at most 3 cycles later, and the
+        // 7 cycle sequence pushes PC and P
+        nes.run_cycles(10);
+        assert_eq!(nes.cpu.sp, sp.wrapping_sub(3));
+
+        // Acknowledging it drops the line on the next cycle
+        nes.mapper.set_irq_counter(0);
+        nes.run_cycles(1);
+        assert!(!nes.cpu.irq_line());
+    }
+
+    #[test]
+    fn capture_fires_only_on_its_event() {
+        // LDA #$01, JMP $8002
+        let mut nes = nes_with_program(&[0xA9, 0x01, 0x4C, 0x02, 0x80]);
+
+        // Never fetched, never executed and nothing rendered
+        for &event in [
+            CaptureEvent::Breakpoint(0x9000),
+            CaptureEvent::Opcode(0xE8),
+            CaptureEvent::SpriteZeroHit(30),
+        ]
+        .iter()
+        {
+            nes.capture_on(Some(event));
+            nes.run_frame();
+            assert!(nes.take_capture().is_none());
+        }
+
+        for &event in [CaptureEvent::Breakpoint(0x8002), CaptureEvent::Opcode(0x4C)].iter() {
+            nes.capture_on(Some(event));
+            nes.run_cycles(10);
+            let capture = nes.take_capture().unwrap();
+            assert_eq!(capture.len(), 256 * 240 * 4);
+            // One shot, the event keeps happening but isn't armed anymore
+            nes.run_cycles(10);
+            assert!(nes.take_capture().is_none());
+        }
+
+        nes.capture_on(Some(CaptureEvent::Breakpoint(0x8002)));
+        nes.capture_on(None);
+        nes.run_cycles(10);
+        assert!(nes.take_capture().is_none());
+    }
+
+    // Background patterns from $0000 and sprites from $1000 with the given
+    // PPUMASK. Returns the A12 rising edges over a whole frame and the dots
+    // of the ones on line 10
+    fn a12_edges(mask: u8) -> (u64, Vec<u16>) {
+        // LDA #$08, STA $2000, LDA #mask, STA $2001, JMP $800A
+        let mut nes = nes_with_program(&[
+            0xA9, 0x08, 0x8D, 0x00, 0x20, 0xA9, mask, 0x8D, 0x01, 0x20, 0x4C, 0x0A, 0x80,
+        ]);
+        nes.run_frames(2);
+
+        let edges = nes.mapper.a12_rising_edges();
+        let mut dots = Vec::new();
+        loop {
+            let before = nes.mapper.a12_rising_edges();
+            let (x, line) = nes.ppu.position();
+            nes.tick();
+            if line == 10 && nes.mapper.a12_rising_edges() != before {
+                // The tick runs the dot after the position
+                dots.push(x + 1);
+            }
+            if nes.ppu.fetch_frame() {
+                break;
+            }
+        }
+        (nes.mapper.a12_rising_edges() - edges, dots)
+    }
+
+    #[test]
+    fn a12_edges_match_with_and_without_the_background() {
+        let (edges, dots) = a12_edges(0x18);
+        // Each of the 8 sprite slots fetches from $1000 after a nametable
+        // read, on every visible line and the prerender line
+        assert_eq!(edges, 241 * 8);
+        assert_eq!(dots, (261..=317).step_by(8).collect::<Vec<u16>>());
+        assert_eq!(a12_edges(0x10), (edges, dots));
+        assert_eq!(a12_edges(0x00).0, 0);
+    }
+
+    #[test]
+    fn one_bus_serves_ram_ppu_registers_and_the_cartridge() {
+        use cpu::BusOps;
+
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        nes.run_to_vblank();
+        let mut bus = CpuBus {
+            mapper: &mut nes.mapper,
+            memory: &mut nes.memory,
+            ppu: &mut nes.ppu,
+            dma: &mut nes.dma,
+            apu: &mut nes.apu,
+            joystick: &mut nes.joystick,
+            open_bus: &mut nes.open_bus,
+            read_address: &mut nes.cpu_read_address,
+        };
+
+        // RAM and its mirror
+        bus.write(0x0010, 0x55);
+        assert_eq!(bus.read(0x0010), 0x55);
+        assert_eq!(bus.read(0x0810), 0x55);
+
+        // PPUSTATUS, the vblank flag clears on the read
+        assert_eq!(bus.read(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.read(0x2002) & 0x80, 0x00);
+
+        // VRAM through PPUADDR/PPUDATA and their mirrors, the first read
+        // only fills the buffer
+        bus.write(0x2006, 0x21);
+        bus.write(0x2006, 0x05);
+        bus.write(0x2007, 0x77);
+        bus.write(0x200E, 0x21);
+        bus.write(0x200E, 0x05);
+        bus.read(0x3FFF);
+        assert_eq!(bus.read(0x2007), 0x77);
+
+        // PRG-ROM
+        assert_eq!(bus.read(0x8000), 0x4C);
+        assert_eq!(bus.read(0x8002), 0x80);
+
+        // The PPU side sees the byte written through the CPU side
+        assert_eq!(nes.mapper.ppu_read(0x2105), 0x77);
+    }
+
+    // Fills the top 8 nametable rows with tiles 0-3 from CHR-ROM and turns the
+    // background on
+    fn headless_rom() -> mapper::Mapper {
+        let program = [
+            0xAD, 0x02, 0x20, // LDA $2002
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // PPUADDR $3F00
+            0xA9, 0x0F, 0x8D, 0x07, 0x20, 0xA9, 0x21, 0x8D, 0x07, 0x20, // Palette 0
+            0xA9, 0x16, 0x8D, 0x07, 0x20, 0xA9, 0x30, 0x8D, 0x07, 0x20, 0xA9, 0x20, 0x8D, 0x06,
+            0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // PPUADDR $2000
+            0xA2, 0x00, // LDX #$00
+            0x8A, 0x29, 0x03, 0x8D, 0x07, 0x20, 0xE8, 0xD0, 0xF7, // Tile X & 3, 256 times
+            0xA9, 0x00, 0x8D, 0x05, 0x20, 0x8D, 0x05, 0x20, // No scroll
+            0xA9, 0x0A, 0x8D, 0x01, 0x20, // Background on
+            0x4C, 0x43, 0x80, // JMP $8043
+        ];
+        let mut prg = vec![0xEA; 32768];
+        prg[0..program.len()].copy_from_slice(&program);
+        prg[0x7FFA..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        let mut chr = vec![0; 8192];
+        for row in 0..8 {
+            chr[0x10 + row] = 0xFF;
+            chr[0x28 + row] = 0xFF;
+            chr[0x30 + row] = 0xAA;
+            chr[0x38 + row] = 0x55 << (row & 1);
+        }
+        let mut mapper = mapper::Mapper::new();
+        mapper.load_parts(0, &prg, &chr, 8192).unwrap();
+        mapper
+    }
+
+    fn headless_hash(frames: usize) -> u64 {
+        let mut nes = Box::new(Nes::new(headless_rom()));
+        nes.run_frames(frames);
+        nes.ppu.framebuffer_hash()
+    }
+
+    #[test]
+    fn headless_frames_have_a_stable_hash() {
+        assert_eq!(headless_hash(3), 0x60CF253C736C4325);
+        // The picture is static, and every run renders it the same
+        assert_eq!(headless_hash(5), headless_hash(3));
+    }
+
+    #[test]
+    fn cheats_pin_ram_every_frame() {
+        // INC $10, INC $6000, JMP $8000
+        let mut nes = nes_with_program(&[0xE6, 0x10, 0xEE, 0x00, 0x60, 0x4C, 0x00, 0x80]);
+        nes.add_cheat(0x0010, 0x41);
+        nes.add_cheat(0x0010, 0x42);
+        nes.add_cheat(0x6000, 0x99);
+        // Registers are left alone, this would turn on NMIs and the handler
+        // would push to the stack
+        nes.add_cheat(0x2000, 0x80);
+
+        nes.run_frame();
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x42);
+        assert_eq!(nes.peek_bus().peek(0x6000), 0x99);
+
+        nes.clear_cheats();
+        nes.run_frame();
+        assert_eq!(nes.cpu.sp, 0xFD);
+        assert_ne!(nes.peek_bus().peek(0x0010), 0x42);
+    }
+
+    #[test]
+    fn run_instructions_is_deterministic() {
+        // INC $10, LDA $10, ADC #$03, STA $11, JMP $8000
+        let program = [
+            0xE6, 0x10, 0xA5, 0x10, 0x69, 0x03, 0x85, 0x11, 0x4C, 0x00, 0x80,
+        ];
+        let mut first = nes_with_program(&program);
+        let mut second = nes_with_program(&program);
+        assert_eq!(first.run_instructions(10000), 10000);
+        assert_eq!(second.run_instructions(4000), 4000);
+        assert_eq!(second.run_instructions(6000), 6000);
+        assert!(first.save_state() == second.save_state());
+    }
+
+    #[test]
+    fn stop_on_error_halts_on_unimplemented_opcodes() {
+        // LDA #$01, SLO ($10,X) isn't implemented
+        let mut nes = nes_with_program(&[0xA9, 0x01, 0x03, 0x10]);
+        nes.set_stop_on_error(true);
+        // The reset sequence and LDA
+        assert_eq!(nes.run_instructions(100), 2);
+        assert!(nes.halted());
+        assert!(!nes.cpu_jammed());
+        assert_eq!(nes.run_instructions(100), 0);
+    }
+
+    #[test]
+    fn io_reads_return_the_open_bus() {
+        // LDA $4000, LDX $4016
+        let mut nes = nes_with_program(&[0xAD, 0x00, 0x40, 0xAE, 0x16, 0x40]);
+        nes.run_instructions(3);
+        // The high byte of the operand was the last value on the bus
+        assert_eq!(nes.cpu.a, 0x40);
+        assert_eq!(nes.cpu.x, 0x40);
+    }
+
+    #[test]
+    fn reset_keeps_ram_and_power_on_clears_it() {
+        // LDA #$42, STA $10, JMP $8004
+        let mut nes = nes_with_program(&[0xA9, 0x42, 0x85, 0x10, 0x4C, 0x04, 0x80]);
+        nes.run_instructions(4);
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x42);
+
+        nes.reset();
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x42);
+        assert_eq!(nes.cpu.a, 0x42);
+
+        nes.power_on();
+        assert_eq!(nes.peek_bus().peek(0x0010), 0x00);
+        assert_eq!(nes.cpu.a, 0x00);
+    }
+
+    #[test]
+    fn each_address_range_reads_from_its_device() {
+        // LDX $3FFA until vblank, LDA #$81, STA $0010, LDA $1810, LDY $8000,
+        // then loop
+        let mut nes = nes_with_program(&[
+            0xAE, 0xFA, 0x3F, 0x10, 0xFB, 0xA9, 0x81, 0x8D, 0x10, 0x00, 0xAD, 0x10, 0x18, 0xAC,
+            0x00, 0x80, 0x4C, 0x10, 0x80,
+        ]);
+        nes.run_frames(2);
+        // RAM and the PPU registers are mirrored across their ranges
+        assert_eq!(nes.cpu.x & 0x80, 0x80);
+        assert_eq!(nes.cpu.a, 0x81);
+        assert_eq!(nes.cpu.y, 0xAE);
+
+        let bus = nes.peek_bus();
+        assert_eq!(bus.peek(0x0810), 0x81);
+        assert_eq!(bus.peek(0x8006), 0x81);
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_the_cpu() {
+        let mut stalled = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let mut running = nes_with_program(&[0x4C, 0x00, 0x80]);
+        start_dmc(&mut stalled);
+        running.run_cycles(1);
+        stalled.run_cycles(100);
+        running.run_cycles(100);
+        assert_eq!(stalled.cpu_cycles(), running.cpu_cycles());
+        assert_eq!(running.cpu.cycles() - stalled.cpu.cycles(), 4);
+    }
+}
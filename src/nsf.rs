@@ -0,0 +1,303 @@
+use crate::error::{NesError, Result};
+use crate::{cpu, memory};
+use std::{fs::File, io::BufReader, io::Read, path::Path, time::Duration};
+
+// Return address pushed before calling init/play, nothing is mapped there so
+// the player knows the routine returned once the CPU is about to fetch from it
+const RETURN_ADDRESS: u16 = 0x4100;
+// Upper bound for a single init/play call, about 20 NTSC frames
+const MAX_ROUTINE_CYCLES: u64 = 600000;
+
+pub struct NsfHeader {
+    pub version: u8,
+    pub total_songs: u8,
+    pub starting_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ntsc_speed: u16,
+    pub bankswitch: [u8; 8],
+}
+
+struct NsfBus {
+    memory: memory::Memory,
+    sram: [u8; 8192],
+    rom: Vec<u8>,
+    banks: [usize; 8],
+    registers: [u8; 0x18],
+}
+
+impl cpu::BusOps for NsfBus {
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory.cpu_write(address, data);
+        if (0x4000..=0x4017).contains(&address) {
+            self.registers[address as usize - 0x4000] = data;
+        } else if (0x5FF8..=0x5FFF).contains(&address) {
+            self.banks[address as usize - 0x5FF8] = data as usize * 4096;
+        } else if (0x6000..=0x7FFF).contains(&address) {
+            self.sram[address as usize & 0x1FFF] = data;
+        }
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        cpu::PeekOps::peek(self, address)
+    }
+}
+
+impl cpu::PeekOps for NsfBus {
+    fn peek(&self, address: u16) -> u8 {
+        if (0x6000..=0x7FFF).contains(&address) {
+            return self.sram[address as usize & 0x1FFF];
+        }
+        if address & 0x8000 != 0 {
+            let bank = self.banks[(address as usize >> 12) & 0x7];
+            return *self
+                .rom
+                .get(bank + (address as usize & 0xFFF))
+                .unwrap_or(&0);
+        }
+        self.memory.cpu_peek(address)
+    }
+}
+
+pub struct Nsf {
+    pub header: NsfHeader,
+    pub cpu: cpu::Cpu6502,
+    bus: NsfBus,
+    track: u8,
+}
+
+impl Default for Nsf {
+    fn default() -> Self {
+        Nsf::new()
+    }
+}
+
+impl Nsf {
+    pub fn new() -> Nsf {
+        Nsf {
+            header: NsfHeader {
+                version: 0,
+                total_songs: 0,
+                starting_song: 0,
+                load_address: 0,
+                init_address: 0,
+                play_address: 0,
+                name: String::new(),
+                artist: String::new(),
+                copyright: String::new(),
+                ntsc_speed: 0,
+                bankswitch: [0; 8],
+            },
+            cpu: cpu::Cpu6502::new(),
+            bus: NsfBus {
+                memory: memory::Memory::new(),
+                sram: [0; 8192],
+                rom: Vec::new(),
+                banks: [0; 8],
+                registers: [0; 0x18],
+            },
+            track: 0,
+        }
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut header: [u8; 128] = [0; 128];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| NesError::InvalidHeader)?;
+
+        let nsfheader = *b"NESM\x1a";
+        if nsfheader != header[0..5] {
+            return Err(NesError::InvalidHeader);
+        }
+
+        let word = |offset: usize| header[offset] as u16 | (header[offset + 1] as u16) << 8;
+        let text = |offset: usize| {
+            let field = header[offset..offset + 32].split(|&byte| byte == 0).next();
+            String::from_utf8_lossy(field.unwrap_or(&[])).into_owned()
+        };
+        self.header = NsfHeader {
+            version: header[5],
+            total_songs: header[6],
+            starting_song: header[7],
+            load_address: word(0x08),
+            init_address: word(0x0A),
+            play_address: word(0x0C),
+            name: text(0x0E),
+            artist: text(0x2E),
+            copyright: text(0x4E),
+            ntsc_speed: word(0x6E),
+            bankswitch: [0; 8],
+        };
+        self.header.bankswitch.copy_from_slice(&header[0x70..0x78]);
+
+        if self.header.total_songs == 0 {
+            return Err(NesError::NoData);
+        }
+        if self.header.load_address < 0x8000 {
+            return Err(NesError::InvalidHeader);
+        }
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Err(NesError::NoData);
+        }
+
+        // Banked tunes are padded so the load address lands at the right spot
+        // of the first 4kb bank, flat tunes are placed at the load address
+        let padding = if self.is_banked() {
+            self.header.load_address as usize & 0xFFF
+        } else {
+            self.header.load_address as usize - 0x8000
+        };
+        if padding + data.len() > 1048576 {
+            return Err(NesError::TooMuchData);
+        }
+        self.bus.rom = vec![0; padding];
+        self.bus.rom.extend_from_slice(&data);
+
+        self.select_track(self.header.starting_song.saturating_sub(1))
+    }
+
+    fn is_banked(&self) -> bool {
+        self.header.bankswitch.iter().any(|&bank| bank != 0)
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // Last values written to the APU registers $4000-$4017
+    pub fn registers(&self) -> &[u8; 0x18] {
+        &self.bus.registers
+    }
+
+    pub fn play_period(&self) -> Duration {
+        match self.header.ntsc_speed {
+            0 => Duration::from_secs(1) / 60,
+            speed => Duration::from_micros(speed as u64),
+        }
+    }
+
+    // Resets the machine and runs the init routine for a zero based track
+    pub fn select_track(&mut self, track: u8) -> Result<()> {
+        self.track = std::cmp::min(track, self.header.total_songs.saturating_sub(1));
+
+        self.bus.memory = memory::Memory::new();
+        self.bus.sram = [0; 8192];
+        self.bus.registers = [0; 0x18];
+        for (index, bank) in self.bus.banks.iter_mut().enumerate() {
+            *bank = index * 4096;
+        }
+        if self.is_banked() {
+            for (bank, &value) in self.bus.banks.iter_mut().zip(&self.header.bankswitch) {
+                *bank = value as usize * 4096;
+            }
+        }
+        // Silence the channels and put the frame counter in 4-step mode
+        for address in 0x4000..0x4014 {
+            cpu::BusOps::write(&mut self.bus, address, 0);
+        }
+        cpu::BusOps::write(&mut self.bus, 0x4015, 0x0F);
+        cpu::BusOps::write(&mut self.bus, 0x4017, 0x40);
+
+        self.cpu = cpu::Cpu6502::new();
+        self.cpu.sp = 0xFD;
+        self.cpu.sr = 0x24;
+        self.cpu.a = self.track;
+        // NTSC
+        self.cpu.x = 0;
+
+        self.call(self.header.init_address)
+    }
+
+    // Runs the play routine once, the caller repeats it every play_period()
+    pub fn play(&mut self) -> Result<()> {
+        self.call(self.header.play_address)
+    }
+
+    // Emulates a JSR to the routine and runs the CPU until it returns
+    fn call(&mut self, address: u16) -> Result<()> {
+        let return_address = RETURN_ADDRESS - 1;
+        cpu::BusOps::write(
+            &mut self.bus,
+            0x100 + self.cpu.sp as u16,
+            (return_address >> 8) as u8,
+        );
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        cpu::BusOps::write(
+            &mut self.bus,
+            0x100 + self.cpu.sp as u16,
+            (return_address & 0xFF) as u8,
+        );
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.pc = address;
+
+        for _ in 0..MAX_ROUTINE_CYCLES {
+            if self.cpu.stage == 1 && self.cpu.pc == RETURN_ADDRESS {
+                return Ok(());
+            }
+            if let cpu::CycleResult::Error = self.cpu.tick(&mut self.bus) {
+                panic!("Error stage: {:#02X}", self.cpu.stage);
+            }
+        }
+        Err(NesError::RoutineTimeout(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Init stores the song number at $00, play counts its calls at $01
+    fn nsf_image() -> Vec<u8> {
+        let mut image = b"NESM\x1a\x01".to_vec();
+        image.resize(128, 0);
+        image[6] = 3;
+        image[7] = 2;
+        image[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x10, 0x80]);
+        image[0x0E..0x12].copy_from_slice(b"Test");
+
+        let mut code = vec![0xEA; 0x20];
+        // STA $00, RTS
+        code[0..3].copy_from_slice(&[0x85, 0x00, 0x60]);
+        // INC $01, RTS
+        code[0x10..0x13].copy_from_slice(&[0xE6, 0x01, 0x60]);
+        image.extend(code);
+        image
+    }
+
+    #[test]
+    fn load_runs_init_with_the_starting_song_in_a() {
+        let path = std::env::temp_dir().join(format!("nesrust-{}-tune.nsf", std::process::id()));
+        std::fs::write(&path, nsf_image()).unwrap();
+        let mut nsf = Box::new(Nsf::new());
+        let result = nsf.load(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        assert_eq!(nsf.header.load_address, 0x8000);
+        assert_eq!(nsf.header.init_address, 0x8000);
+        assert_eq!(nsf.header.play_address, 0x8010);
+        assert_eq!(nsf.header.name, "Test");
+        // Starting song is one based, A holds the zero based track
+        assert_eq!(nsf.track(), 1);
+        assert_eq!(cpu::PeekOps::peek(&nsf.bus, 0x00), 1);
+
+        nsf.play().unwrap();
+        nsf.play().unwrap();
+        assert_eq!(cpu::PeekOps::peek(&nsf.bus, 0x01), 2);
+
+        nsf.select_track(7).unwrap();
+        assert_eq!(nsf.track(), 2);
+        assert_eq!(cpu::PeekOps::peek(&nsf.bus, 0x00), 2);
+        assert_eq!(cpu::PeekOps::peek(&nsf.bus, 0x01), 0);
+    }
+}
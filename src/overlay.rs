@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct FrameStats {
+    frame_period: Duration,
+    frame_number: u64,
+    interval_start: Instant,
+    interval_displayed: u32,
+    interval_emulated: u32,
+    fps: f64,
+    speed: f64,
+}
+
+impl FrameStats {
+    pub fn new(frame_period: Duration) -> Self {
+        FrameStats {
+            frame_period,
+            frame_number: 0,
+            interval_start: Instant::now(),
+            interval_displayed: 0,
+            interval_emulated: 0,
+            fps: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    // Called once per displayed frame with the number of emulated frames behind it
+    pub fn record(&mut self, now: Instant, emulated_frames: usize) {
+        self.frame_number += emulated_frames as u64;
+        self.interval_displayed += 1;
+        self.interval_emulated += emulated_frames as u32;
+
+        let elapsed = now.saturating_duration_since(self.interval_start);
+        if elapsed >= UPDATE_INTERVAL {
+            self.fps = self.interval_displayed as f64 / elapsed.as_secs_f64();
+            self.speed = speed_percentage(self.interval_emulated, elapsed, self.frame_period);
+            self.interval_start = now;
+            self.interval_displayed = 0;
+            self.interval_emulated = 0;
+        }
+    }
+
+    pub fn text(&self) -> String {
+        format!(
+            "FPS: {:.1}\nSpeed: {:.0}%\nFrame: {}",
+            self.fps, self.speed, self.frame_number
+        )
+    }
+}
+
+// Emulated time over host time, 100% means the emulator runs at full speed
+pub fn speed_percentage(emulated_frames: u32, elapsed: Duration, frame_period: Duration) -> f64 {
+    if elapsed.as_secs_f64() == 0.0 {
+        return 0.0;
+    }
+    emulated_frames as f64 * frame_period.as_secs_f64() / elapsed.as_secs_f64() * 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_percentage_compares_emulated_and_host_time() {
+        let period = Duration::from_micros(16_639);
+        // 60 frames emulated in the time 60 frames take
+        assert!((speed_percentage(60, period * 60, period) - 100.0).abs() < 1e-9);
+        // Only half the frames made it, one slow frame took two periods
+        assert!((speed_percentage(30, period * 60, period) - 50.0).abs() < 1e-9);
+        assert!((speed_percentage(1, period * 2, period) - 50.0).abs() < 1e-9);
+        // Nothing measured yet
+        assert_eq!(speed_percentage(60, Duration::from_secs(0), period), 0.0);
+        assert_eq!(speed_percentage(0, period, period), 0.0);
+    }
+}
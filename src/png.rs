@@ -0,0 +1,99 @@
+use crate::error::Result;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// Largest block a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 65535;
+
+// CRC-32 (IEEE) as used by the PNG chunks
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Checksum closing the zlib stream
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// Length, type, data and the CRC of type and data
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// zlib stream made of uncompressed deflate blocks, frames are small enough
+// that skipping compression keeps the encoder trivial
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks = std::cmp::max(data.len().div_ceil(MAX_STORED_BLOCK), 1);
+    for index in 0..blocks {
+        let start = index * MAX_STORED_BLOCK;
+        let block = &data[start..std::cmp::min(start + MAX_STORED_BLOCK, data.len())];
+        // BFINAL on the last block, BTYPE 00 (stored)
+        stream.push((index == blocks - 1) as u8);
+        stream.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        stream.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+// Encodes RGBA8 pixels, rows top to bottom, as an 8 bit RGBA PNG. Each pixel
+// is repeated scale times in both directions
+pub fn encode(width: usize, height: usize, rgba: &[u8], scale: usize) -> Vec<u8> {
+    let scale = std::cmp::max(scale, 1);
+    let out_width = width * scale;
+    let out_height = height * scale;
+
+    // Every scanline starts with its filter type, 0 is no filtering
+    let mut raw = Vec::with_capacity(out_height * (1 + out_width * 4));
+    for y in 0..out_height {
+        raw.push(0);
+        let row = &rgba[(y / scale) * width * 4..(y / scale + 1) * width * 4];
+        for pixel in row.chunks(4) {
+            for _ in 0..scale {
+                raw.extend_from_slice(pixel);
+            }
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(out_width as u32).to_be_bytes());
+    header.extend_from_slice(&(out_height as u32).to_be_bytes());
+    // Bit depth 8, color type 6 (RGBA), default compression, filter and no
+    // interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn save(path: &Path, width: usize, height: usize, rgba: &[u8], scale: usize) -> Result<()> {
+    std::fs::write(path, encode(width, height, rgba, scale))?;
+    Ok(())
+}
@@ -1,3 +1,7 @@
+pub use crate::bus::{BusOps, PeekOps};
+use crate::error::Result;
+use crate::savestate::{self, StateReader};
+
 const OAM_OFFSET_Y: usize = 0;
 const OAM_OFFSET_INDEX: usize = 1;
 const OAM_OFFSET_ATTR: usize = 2;
@@ -6,11 +10,17 @@ const OAM_DATA_SIZE: usize = 4;
 const SPRITES_IN_PRIMARY: usize = 64;
 const SPRITES_IN_SECONDARY: usize = 8;
 
+// Palette indexes used by the debug layer views
+const DEBUG_BACKDROP_COLOR: u8 = 0x0F;
+const DEBUG_BACKGROUND_COLOR: u8 = 0x2A;
+const DEBUG_SPRITE_COLOR: u8 = 0x16;
+
 const SCREEN_WIDTH: usize = 256;
 const SCREEN_HEIGHT: usize = 240;
 pub struct Ppu {
     pub nmi_state: bool,
     pub pixels: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+    pub indexed_pixels: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
     primary_oam: [u8; SPRITES_IN_PRIMARY * OAM_DATA_SIZE],
     secondary_oam: [u8; SPRITES_IN_SECONDARY * OAM_DATA_SIZE],
     sprite_counter: [u8; SPRITES_IN_SECONDARY],
@@ -22,16 +32,26 @@ pub struct Ppu {
     sprite_index: usize,
     sprite_priority: u8,
     sprite_zero_hit: bool,
+    sprite_zero_hit_pending: bool,
+    sprite_overflow: bool,
+    sprite_overflow_mode: SpriteOverflowMode,
+    layer_view: LayerView,
     sprite_zero_present: bool,
-    active_sprites: usize,
+    sprite_active: [bool; SPRITES_IN_SECONDARY],
     secondary_sprites: usize,
-    ppu_addr: u16,
+    // Loopy registers: current VRAM address, temporary address, fine X
+    // scroll and the write toggle shared by PPUSCROLL and PPUADDR
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+    read_buffer: u8,
+    scroll_mode: ScrollMode,
     oam_addr: u8,
-    first_addr: bool,
     render_x: u16,
     render_y: u16,
     vblank_started: bool,
-    base_nametable: u16,
+    vblank_suppressed: bool,
     addr_increment: u8,
     sprite_pattern_table: u16,
     background_pattern_table: u16,
@@ -39,20 +59,61 @@ pub struct Ppu {
     nmi_enable: bool,
     render_sprite_enable: bool,
     render_background_enable: bool,
-    first_scroll_write: bool,
+    render_sprite_left: bool,
+    render_background_left: bool,
     background_pattern1: u8,
     background_pattern2: u8,
     background_attribute: u8,
     background_pixel: u8,
     background_counter: u8,
-    scroll_x: u16,
-    scroll_y: u16,
+    background_tile: u8,
+    background_next_attribute: u8,
+    background_shift_low: u16,
+    background_shift_high: u16,
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
     frame: bool,
+    scanline_callback: Option<Box<dyn FnMut(u16)>>,
 }
 
-pub trait BusOps {
-    fn read(&mut self, address: u16) -> u8;
-    fn write(&mut self, address: u16, data: u8);
+#[derive(Copy, Clone, PartialEq)]
+pub enum LayerView {
+    Normal,
+    // Every pixel shows which layer won: backdrop, background or sprite
+    Layers,
+    // Real colors except for sprite pixels
+    SpriteHighlight,
+}
+
+#[derive(Copy, Clone)]
+pub enum SpriteOverflowMode {
+    // Reproduces the diagonal OAM reads, false positives and negatives included
+    Hardware,
+    // Overflow whenever more than 8 sprites are on the scanline
+    Simple,
+}
+
+#[derive(Copy, Clone)]
+pub enum ScrollMode {
+    // Background fetched through v, with the increments and copies from t
+    // happening on the same dots as the hardware, handles mid-frame splits
+    Loopy,
+    // Whole frame scrolled by the last PPUSCROLL and PPUCTRL values, kept to
+    // compare against the loopy path
+    Simple,
+}
+
+#[derive(Copy, Clone)]
+pub struct ScrollState {
+    pub x: u16,
+    pub y: u16,
+    pub nametable: u16,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Ppu::new()
+    }
 }
 
 impl Ppu {
@@ -60,6 +121,7 @@ impl Ppu {
         Ppu {
             nmi_state: false,
             pixels: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            indexed_pixels: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
             primary_oam: [0; SPRITES_IN_PRIMARY * OAM_DATA_SIZE],
             secondary_oam: [0; SPRITES_IN_SECONDARY * OAM_DATA_SIZE],
             sprite_counter: [0; SPRITES_IN_SECONDARY],
@@ -71,16 +133,24 @@ impl Ppu {
             sprite_index: 0,
             sprite_priority: 0,
             sprite_zero_hit: false,
+            sprite_zero_hit_pending: false,
+            sprite_overflow: false,
+            sprite_overflow_mode: SpriteOverflowMode::Hardware,
+            layer_view: LayerView::Normal,
             sprite_zero_present: false,
-            active_sprites: 0,
+            sprite_active: [false; SPRITES_IN_SECONDARY],
             secondary_sprites: 0,
-            ppu_addr: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            scroll_mode: ScrollMode::Loopy,
             oam_addr: 0,
-            first_addr: true,
             render_x: 0,
             render_y: 0,
             vblank_started: false,
-            base_nametable: 0,
+            vblank_suppressed: false,
             addr_increment: 1,
             sprite_pattern_table: 0,
             background_pattern_table: 0,
@@ -88,16 +158,179 @@ impl Ppu {
             nmi_enable: false,
             render_sprite_enable: false,
             render_background_enable: false,
-            first_scroll_write: true,
+            render_sprite_left: false,
+            render_background_left: false,
             background_pattern1: 0,
             background_pattern2: 0,
             background_attribute: 0,
             background_pixel: 0,
             background_counter: 0,
-            scroll_x: 0,
-            scroll_y: 0,
+            background_tile: 0,
+            background_next_attribute: 0,
+            background_shift_low: 0,
+            background_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
             frame: false,
+            scanline_callback: None,
+        }
+    }
+
+    pub fn power_on(&mut self) {
+        let scanline_callback = self.scanline_callback.take();
+        let sprite_overflow_mode = self.sprite_overflow_mode;
+        let layer_view = self.layer_view;
+        let scroll_mode = self.scroll_mode;
+        *self = Ppu::new();
+        self.scanline_callback = scanline_callback;
+        self.sprite_overflow_mode = sprite_overflow_mode;
+        self.layer_view = layer_view;
+        self.scroll_mode = scroll_mode;
+    }
+
+    pub fn set_debug_layer_view(&mut self, layer_view: LayerView) {
+        self.layer_view = layer_view;
+    }
+
+    pub fn set_sprite_overflow_mode(&mut self, mode: SpriteOverflowMode) {
+        self.sprite_overflow_mode = mode;
+    }
+
+    pub fn set_scroll_mode(&mut self, mode: ScrollMode) {
+        self.scroll_mode = mode;
+    }
+
+    // Reset clears PPUCTRL, PPUMASK, the scroll, the write toggle and the
+    // PPUDATA read buffer. VRAM, OAM, the status flags and the frame position
+    // are kept
+    pub fn reset(&mut self) {
+        self.addr_increment = 1;
+        self.sprite_pattern_table = 0;
+        self.background_pattern_table = 0;
+        self.sprite_size = 8;
+        self.nmi_enable = false;
+        self.nmi_state = false;
+        self.render_background_enable = false;
+        self.render_sprite_enable = false;
+        self.render_background_left = false;
+        self.render_sprite_left = false;
+        self.t = 0;
+        self.fine_x = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+    }
+
+    // Everything but the debug and accuracy settings, which stay as configured
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.pixels);
+        state.extend_from_slice(&self.indexed_pixels);
+        state.extend_from_slice(&self.primary_oam);
+        state.extend_from_slice(&self.secondary_oam);
+        state.extend_from_slice(&self.sprite_counter);
+        state.extend_from_slice(&self.sprite_attribute);
+        state.extend_from_slice(&self.sprite_pattern1);
+        state.extend_from_slice(&self.sprite_pattern2);
+        state.extend(self.sprite_active.iter().map(|&active| active as u8));
+        state.extend_from_slice(&self.v.to_le_bytes());
+        state.extend_from_slice(&self.t.to_le_bytes());
+        state.push(self.fine_x);
+        state.push(self.write_toggle as u8);
+        state.push(self.read_buffer);
+        state.push(self.oam_addr);
+        state.extend_from_slice(&self.render_x.to_le_bytes());
+        state.extend_from_slice(&self.render_y.to_le_bytes());
+        state.push(self.frame as u8);
+        state.push(self.nmi_state as u8);
+        state.push(self.vblank_started as u8);
+        state.push(self.vblank_suppressed as u8);
+        state.push(self.addr_increment);
+        state.extend_from_slice(&self.sprite_pattern_table.to_le_bytes());
+        state.extend_from_slice(&self.background_pattern_table.to_le_bytes());
+        state.push(self.sprite_size);
+        state.push(self.nmi_enable as u8);
+        state.push(self.render_sprite_enable as u8);
+        state.push(self.render_background_enable as u8);
+        state.push(self.render_sprite_left as u8);
+        state.push(self.render_background_left as u8);
+        state.push(self.sprite_pixel);
+        state.push(self.sprite_palette);
+        state.extend_from_slice(&(self.sprite_index as u16).to_le_bytes());
+        state.push(self.sprite_priority);
+        state.push(self.sprite_zero_hit as u8);
+        state.push(self.sprite_zero_hit_pending as u8);
+        state.push(self.sprite_overflow as u8);
+        state.push(self.sprite_zero_present as u8);
+        state.extend_from_slice(&(self.secondary_sprites as u16).to_le_bytes());
+        state.push(self.background_pattern1);
+        state.push(self.background_pattern2);
+        state.push(self.background_attribute);
+        state.push(self.background_pixel);
+        state.push(self.background_counter);
+        state.push(self.background_tile);
+        state.push(self.background_next_attribute);
+        state.extend_from_slice(&self.background_shift_low.to_le_bytes());
+        state.extend_from_slice(&self.background_shift_high.to_le_bytes());
+        state.extend_from_slice(&self.attribute_shift_low.to_le_bytes());
+        state.extend_from_slice(&self.attribute_shift_high.to_le_bytes());
+        state
+    }
+
+    pub fn from_bytes(&mut self, state: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(state);
+        reader.fill(&mut self.pixels)?;
+        reader.fill(&mut self.indexed_pixels)?;
+        reader.fill(&mut self.primary_oam)?;
+        reader.fill(&mut self.secondary_oam)?;
+        reader.fill(&mut self.sprite_counter)?;
+        reader.fill(&mut self.sprite_attribute)?;
+        reader.fill(&mut self.sprite_pattern1)?;
+        reader.fill(&mut self.sprite_pattern2)?;
+        for active in self.sprite_active.iter_mut() {
+            *active = reader.bool()?;
         }
+        self.v = reader.u16()?;
+        self.t = reader.u16()?;
+        self.fine_x = reader.u8()?;
+        self.write_toggle = reader.bool()?;
+        self.read_buffer = reader.u8()?;
+        self.oam_addr = reader.u8()?;
+        self.render_x = reader.u16()?;
+        self.render_y = reader.u16()?;
+        self.frame = reader.bool()?;
+        self.nmi_state = reader.bool()?;
+        self.vblank_started = reader.bool()?;
+        self.vblank_suppressed = reader.bool()?;
+        self.addr_increment = reader.u8()?;
+        self.sprite_pattern_table = reader.u16()?;
+        self.background_pattern_table = reader.u16()?;
+        self.sprite_size = reader.u8()?;
+        self.nmi_enable = reader.bool()?;
+        self.render_sprite_enable = reader.bool()?;
+        self.render_background_enable = reader.bool()?;
+        self.render_sprite_left = reader.bool()?;
+        self.render_background_left = reader.bool()?;
+        self.sprite_pixel = reader.u8()?;
+        self.sprite_palette = reader.u8()?;
+        self.sprite_index = reader.u16()? as usize;
+        self.sprite_priority = reader.u8()?;
+        self.sprite_zero_hit = reader.bool()?;
+        self.sprite_zero_hit_pending = reader.bool()?;
+        self.sprite_overflow = reader.bool()?;
+        self.sprite_zero_present = reader.bool()?;
+        self.secondary_sprites = reader.u16()? as usize;
+        self.background_pattern1 = reader.u8()?;
+        self.background_pattern2 = reader.u8()?;
+        self.background_attribute = reader.u8()?;
+        self.background_pixel = reader.u8()?;
+        self.background_counter = reader.u8()?;
+        self.background_tile = reader.u8()?;
+        self.background_next_attribute = reader.u8()?;
+        self.background_shift_low = reader.u16()?;
+        self.background_shift_high = reader.u16()?;
+        self.attribute_shift_low = reader.u16()?;
+        self.attribute_shift_high = reader.u16()?;
+        reader.finish()
     }
 
     pub fn fetch_frame(&mut self) -> bool {
@@ -106,38 +339,155 @@ impl Ppu {
         result
     }
 
-    pub fn tick(&mut self, ppu_bus: &mut dyn BusOps) {
-        if self.render_y <= 240 && self.render_x < 256 {
-            if self.render_x == 0 {
-                self.load_secondary_oam();
+    // FNV-1a of the color channels, stable across builds and platforms so
+    // it can be compared against a known good frame. Alpha is skipped like
+    // in framebuffer_crc
+    pub fn framebuffer_hash(&self) -> u64 {
+        let rgb: Vec<u8> = self
+            .pixels
+            .chunks(4)
+            .flat_map(|pixel| pixel[0..3].iter().copied())
+            .collect();
+        savestate::hash(&rgb)
+    }
+
+    // CRC-32 (IEEE) of the color channels only, the alpha bytes are skipped
+    pub fn framebuffer_crc(&self) -> u32 {
+        let mut crc = !0u32;
+        for pixel in self.pixels.chunks(4) {
+            for &byte in &pixel[0..3] {
+                crc ^= byte as u32;
+                for _ in 0..8 {
+                    crc = if crc & 1 != 0 {
+                        (crc >> 1) ^ 0xEDB88320
+                    } else {
+                        crc >> 1
+                    };
+                }
             }
+        }
+        !crc
+    }
+
+    pub fn sprite_zero_hit(&self) -> bool {
+        self.sprite_zero_hit
+    }
+
+    pub fn position(&self) -> (u16, u16) {
+        (self.render_x, self.render_y)
+    }
+
+    // Scroll as last written through PPUCTRL and PPUSCROLL, kept in t
+    pub fn scroll(&self) -> ScrollState {
+        ScrollState {
+            x: (self.t & 0x1F) * 8 + self.fine_x as u16,
+            y: ((self.t >> 5) & 0x1F) * 8 + ((self.t >> 12) & 0x7),
+            nametable: 0x2000 | (self.t & 0x0C00),
+        }
+    }
+
+    // Direct OAM access for tools, bypasses OAMADDR/OAMDATA and the DMA timing
+    pub fn oam(&self) -> &[u8; SPRITES_IN_PRIMARY * OAM_DATA_SIZE] {
+        &self.primary_oam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8; SPRITES_IN_PRIMARY * OAM_DATA_SIZE] {
+        &mut self.primary_oam
+    }
+
+    // Called with the scanline number at the end of each visible scanline
+    pub fn set_scanline_callback(&mut self, callback: Option<Box<dyn FnMut(u16)>>) {
+        self.scanline_callback = callback;
+    }
+
+    pub fn tick(&mut self, ppu_bus: &mut dyn BusOps) {
+        // Pixel N is output on dot N + 1, the hit found while rendering the
+        // previous pixel shows up in PPUSTATUS now
+        if self.sprite_zero_hit_pending {
+            self.sprite_zero_hit_pending = false;
+            self.sprite_zero_hit = true;
+        }
 
-            if self.render_background_enable {
-                self.fetch_background(ppu_bus);
+        // The pattern fetches happen whenever rendering is on, the enable
+        // bits only hide the layer, so the mapper sees the same PPU address
+        // pattern in every combination
+        let rendering = self.render_background_enable || self.render_sprite_enable;
+        if let ScrollMode::Loopy = self.scroll_mode {
+            if rendering && (self.render_y < 240 || self.render_y == 261) {
+                // Pixel X is output on dot X + 1
+                self.fetch_background(ppu_bus, self.render_x + 1);
             }
+        }
 
-            if self.render_sprite_enable {
-                self.update_x_position(ppu_bus);
+        if self.render_y <= 240 && self.render_x < 256 {
+            if rendering {
+                match self.scroll_mode {
+                    ScrollMode::Loopy => self.shift_background_pixel(),
+                    ScrollMode::Simple => self.fetch_background_simple(ppu_bus),
+                }
+                self.update_x_position();
                 self.render_sprites();
             }
+            let left_column = self.render_x < 8;
+            if !self.render_background_enable || (left_column && !self.render_background_left) {
+                self.background_pixel = 0;
+            }
+            if !self.render_sprite_enable || (left_column && !self.render_sprite_left) {
+                self.sprite_pixel = 0;
+            }
             self.render_pixel(ppu_bus);
         }
 
+        // Sprites for the next line are evaluated once this one is drawn and
+        // their patterns fetched on dots 257-320, the prerender line fetches
+        // for line 0 which never has sprites
+        if self.render_y < 240 || self.render_y == 261 {
+            let dot = self.render_x + 1;
+            let next_line = if self.render_y == 261 {
+                0
+            } else {
+                self.render_y + 1
+            };
+            if dot == 257 {
+                self.load_secondary_oam(next_line);
+            }
+            if (257..=320).contains(&dot) {
+                if rendering {
+                    self.fetch_sprite(ppu_bus, next_line, dot);
+                    // OAMADDR is cleared while the patterns are fetched, so
+                    // only mid-frame OAMADDR writes move the evaluation start
+                    self.oam_addr = 0;
+                } else if (dot - 257) % 8 == 7 {
+                    self.latch_sprite(((dot - 257) / 8) as usize, 0, 0);
+                }
+            }
+        }
+
         if self.render_x == 1 && self.render_y == 241 {
-            self.vblank_started = true;
-            if self.nmi_enable {
-                self.nmi_state = true;
+            if !self.vblank_suppressed {
+                self.vblank_started = true;
+                if self.nmi_enable {
+                    self.nmi_state = true;
+                }
             }
+            self.vblank_suppressed = false;
             self.frame = true;
         }
 
         self.render_x += 1;
         if self.render_x == 341 {
+            if (self.render_y as usize) < SCREEN_HEIGHT {
+                if let Some(callback) = self.scanline_callback.as_mut() {
+                    callback(self.render_y);
+                }
+            }
             self.render_x = 0;
             self.render_y += 1;
             if self.render_y == 262 {
                 self.vblank_started = false;
                 self.sprite_zero_hit = false;
+                self.sprite_zero_hit_pending = false;
+                self.sprite_overflow = false;
                 self.render_y = 0;
             }
         }
@@ -151,26 +501,24 @@ impl Ppu {
                 0 =>
                 // ppuctrl
                 {
-                    self.base_nametable = match data & 3 {
-                        0 => 0x2000,
-                        1 => 0x2400,
-                        2 => 0x2800,
-                        3 => 0x2C00,
-                        _ => 0x2000,
-                    };
+                    self.t = (self.t & !0x0C00) | ((data as u16 & 0x3) << 10);
                     self.addr_increment = if data & 0x4 != 0 { 32 } else { 1 };
                     self.sprite_pattern_table = if data & 0x8 != 0 { 0x1000 } else { 0x0 };
                     self.background_pattern_table = if data & 0x10 != 0 { 0x1000 } else { 0x0 };
-                    self.sprite_size = if data & 0x20 != 0 { 0x16 } else { 0x8 };
-                    self.nmi_enable = data & 0x80 != 0;
-                    if self.nmi_enable && self.vblank_started {
-                        // TODO enabling this breaks rendering
-                        // self.nmi_state = true;
+                    self.sprite_size = if data & 0x20 != 0 { 16 } else { 8 };
+                    // Only turning NMI on during vblank raises it again, NMI
+                    // handlers rewrite PPUCTRL with the bit already set
+                    let nmi_enable = data & 0x80 != 0;
+                    if nmi_enable && !self.nmi_enable && self.vblank_started {
+                        self.nmi_state = true;
                     }
+                    self.nmi_enable = nmi_enable;
                 }
                 1 =>
                 // ppumask
                 {
+                    self.render_background_left = data & 0x02 != 0;
+                    self.render_sprite_left = data & 0x04 != 0;
                     self.render_background_enable = data & 0x08 != 0;
                     self.render_sprite_enable = data & 0x10 != 0;
                 }
@@ -189,28 +537,36 @@ impl Ppu {
                 5 =>
                 // ppuscroll
                 {
-                    if self.first_scroll_write {
-                        self.scroll_x = data as u16;
+                    if !self.write_toggle {
+                        // Coarse X and fine X
+                        self.t = (self.t & !0x001F) | (data as u16 >> 3);
+                        self.fine_x = data & 0x7;
                     } else {
-                        self.scroll_y = data as u16;
+                        // Fine Y and coarse Y
+                        self.t = (self.t & !0x73E0)
+                            | ((data as u16 & 0x7) << 12)
+                            | ((data as u16 & 0xF8) << 2);
                     }
-                    self.first_scroll_write = !self.first_scroll_write;
+                    self.write_toggle = !self.write_toggle;
                 }
                 6 =>
                 // ppuaddr
                 {
-                    if self.first_addr {
-                        self.ppu_addr = (data as u16) << 8;
+                    // The high byte write also clears bit 14, v only changes
+                    // once the low byte is in
+                    if !self.write_toggle {
+                        self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
                     } else {
-                        self.ppu_addr |= data as u16;
+                        self.t = (self.t & 0xFF00) | data as u16;
+                        self.v = self.t;
                     }
-                    self.first_addr = !self.first_addr;
+                    self.write_toggle = !self.write_toggle;
                 }
                 7 =>
                 // ppudata
                 {
-                    ppu_bus.write(self.ppu_addr, data);
-                    self.ppu_addr += self.addr_increment as u16;
+                    ppu_bus.write(self.v & 0x3FFF, data);
+                    self.increment_vram_address();
                 }
                 _ => (),
             }
@@ -227,6 +583,14 @@ impl Ppu {
                 2 =>
                 // ppustatus
                 {
+                    // Reading the status also resets the PPUSCROLL/PPUADDR
+                    // write toggle, games do it before setting an address
+                    self.write_toggle = false;
+                    // A read on the dot the flag gets set sees it clear and
+                    // keeps it, and the NMI, from happening this frame
+                    if self.render_x == 1 && self.render_y == 241 {
+                        self.vblank_suppressed = true;
+                    }
                     let mut data = 0;
                     if self.vblank_started {
                         self.vblank_started = false;
@@ -235,6 +599,9 @@ impl Ppu {
                     if self.sprite_zero_hit {
                         data |= 0x40;
                     }
+                    if self.sprite_overflow {
+                        data |= 0x20;
+                    }
                     data
                 }
                 3 => 0, // oamaddr
@@ -244,7 +611,20 @@ impl Ppu {
                 7 =>
                 // ppudata
                 {
-                    ppu_bus.read(self.ppu_addr)
+                    // Reads return the buffered byte from the previous read,
+                    // palette reads come straight back but the buffer still
+                    // gets the nametable byte underneath them
+                    let address = self.v & 0x3FFF;
+                    let data = if address >= 0x3F00 {
+                        self.read_buffer = ppu_bus.read(address - 0x1000);
+                        ppu_bus.read(address)
+                    } else {
+                        let data = self.read_buffer;
+                        self.read_buffer = ppu_bus.read(address);
+                        data
+                    };
+                    self.increment_vram_address();
+                    data
                 }
                 _ => 0,
             }
@@ -253,23 +633,93 @@ impl Ppu {
         }
     }
 
-    fn load_secondary_oam(&mut self) {
-        self.active_sprites = 0;
+    // Same decoding as cpu_read, but reading PPUSTATUS keeps the vblank flag
+    pub fn cpu_peek(&self, ppu_bus: &dyn PeekOps, address: u16) -> u8 {
+        if address & 0xE000 != 0x2000 {
+            return 0;
+        }
+        match address & 0x7 {
+            // ppustatus
+            2 => {
+                let mut data = 0;
+                if self.vblank_started {
+                    data |= 0x80;
+                }
+                if self.sprite_zero_hit {
+                    data |= 0x40;
+                }
+                if self.sprite_overflow {
+                    data |= 0x20;
+                }
+                data
+            }
+            // ppudata
+            7 => match self.v & 0x3FFF {
+                address if address >= 0x3F00 => ppu_bus.peek(address),
+                _ => self.read_buffer,
+            },
+            _ => 0,
+        }
+    }
+
+    // PPUDATA accesses step v by the PPUCTRL increment, while rendering they
+    // bump coarse X and Y together instead
+    fn increment_vram_address(&mut self) {
+        let rendering = self.render_background_enable || self.render_sprite_enable;
+        if rendering && (self.render_y < 240 || self.render_y == 261) {
+            self.increment_coarse_x();
+            self.increment_y();
+        } else {
+            self.v = (self.v + self.addr_increment as u16) & 0x7FFF;
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            // Wrap into the horizontally adjacent nametable
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            // Last row of tiles, switch to the vertically adjacent nametable
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Rows 30 and 31 are the attribute table, wrap without switching
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Fills secondary OAM with the sprites that have a row on the given line
+    fn load_secondary_oam(&mut self, line: u16) {
         self.secondary_sprites = 0;
         self.secondary_oam = [0xFF; SPRITES_IN_SECONDARY * OAM_DATA_SIZE];
-        self.sprite_counter = [0; SPRITES_IN_SECONDARY];
         self.sprite_zero_present = false;
-        let range = 0..(SPRITES_IN_PRIMARY * OAM_DATA_SIZE);
+        // Evaluation starts at OAMADDR, whichever sprite sits there acts as
+        // sprite 0 and the sprites before it are never evaluated
+        let first_offset = self.oam_addr as usize;
+        let range = first_offset..(SPRITES_IN_PRIMARY * OAM_DATA_SIZE);
         for sprite_offset in range.step_by(4) {
-            let sprite_x = self.primary_oam[sprite_offset + OAM_OFFSET_X];
-            let sprite_y = self.primary_oam[sprite_offset + OAM_OFFSET_Y];
-            let sprite_attr = self.primary_oam[sprite_offset + OAM_OFFSET_ATTR];
-            let sprite_index = self.primary_oam[sprite_offset + OAM_OFFSET_INDEX];
-            if self.render_y > self.primary_oam[sprite_offset + OAM_OFFSET_Y] as u16
-                && self.render_y
-                    <= (self.primary_oam[sprite_offset + OAM_OFFSET_Y] as u16
-                        + self.sprite_size as u16)
-            {
+            let oam_byte = |offset: usize| (sprite_offset + offset) & 0xFF;
+            let sprite_x = self.primary_oam[oam_byte(OAM_OFFSET_X)];
+            let sprite_y = self.primary_oam[oam_byte(OAM_OFFSET_Y)];
+            let sprite_attr = self.primary_oam[oam_byte(OAM_OFFSET_ATTR)];
+            let sprite_index = self.primary_oam[oam_byte(OAM_OFFSET_INDEX)];
+            if line > sprite_y as u16 && line <= (sprite_y as u16 + self.sprite_size as u16) {
                 self.secondary_oam[self.secondary_sprites * OAM_DATA_SIZE + OAM_OFFSET_Y] =
                     sprite_y;
                 self.secondary_oam[self.secondary_sprites * OAM_DATA_SIZE + OAM_OFFSET_INDEX] =
@@ -278,83 +728,235 @@ impl Ppu {
                     sprite_x;
                 self.secondary_oam[self.secondary_sprites * OAM_DATA_SIZE + OAM_OFFSET_ATTR] =
                     sprite_attr;
-                self.sprite_counter[self.secondary_sprites] =
-                    self.primary_oam[sprite_offset + OAM_OFFSET_X];
 
                 self.secondary_sprites += 1;
 
-                if sprite_offset == 0 {
+                if sprite_offset == first_offset {
                     self.sprite_zero_present = true;
                 }
             }
 
             if self.secondary_sprites >= 8 {
+                self.evaluate_overflow(line, sprite_offset + OAM_DATA_SIZE);
                 break;
             }
         }
     }
 
-    fn update_x_position(&mut self, ppu_bus: &mut dyn BusOps) {
-        // TODO Check sprites at X=0
+    fn evaluate_overflow(&mut self, line: u16, next_offset: usize) {
+        let sprite_size = self.sprite_size as u16;
+        let in_range =
+            |sprite_y: u8| line > sprite_y as u16 && line <= sprite_y as u16 + sprite_size;
+
+        match self.sprite_overflow_mode {
+            SpriteOverflowMode::Simple => {
+                let range = next_offset..(SPRITES_IN_PRIMARY * OAM_DATA_SIZE);
+                for sprite_offset in range.step_by(4) {
+                    if in_range(self.primary_oam[sprite_offset]) {
+                        self.sprite_overflow = true;
+                        break;
+                    }
+                }
+            }
+            SpriteOverflowMode::Hardware => {
+                // Once 8 sprites are found a miss increments the byte index
+                // along with the sprite index, so OAM is read diagonally and
+                // tile, attribute or X bytes get compared as Y coordinates
+                let mut sprite = next_offset / OAM_DATA_SIZE;
+                let mut byte = next_offset % OAM_DATA_SIZE;
+                while sprite < SPRITES_IN_PRIMARY {
+                    if in_range(self.primary_oam[sprite * OAM_DATA_SIZE + byte]) {
+                        self.sprite_overflow = true;
+                        break;
+                    }
+                    sprite += 1;
+                    byte = (byte + 1) % OAM_DATA_SIZE;
+                }
+            }
+        }
+    }
+
+    // The patterns are already latched, a sprite starts shifting out on the
+    // pixel its X counter has run down to 0, so a sprite at X 0 is drawn from
+    // the first pixel
+    fn update_x_position(&mut self) {
         for sprite_offset in 0..self.secondary_sprites {
-            self.sprite_counter[sprite_offset] = self.sprite_counter[sprite_offset].wrapping_sub(1);
-            if self.sprite_counter[sprite_offset] != 0 {
-                continue;
+            if self.sprite_counter[sprite_offset] == 0 {
+                self.sprite_active[sprite_offset] = true;
+            } else {
+                self.sprite_counter[sprite_offset] -= 1;
             }
+        }
+    }
 
-            let sprite_attribute =
-                self.secondary_oam[sprite_offset * OAM_DATA_SIZE + OAM_OFFSET_ATTR];
-            let vertical_flip = sprite_attribute & 0x80 != 0;
+    // One dot of the sprite fetches, each of the 8 slots takes 8 dots: two
+    // garbage nametable reads and the two pattern planes. Slots without a
+    // sprite fetch tile $FF, so a mapper watching A12 sees the same address
+    // pattern on every line
+    fn fetch_sprite(&mut self, ppu_bus: &mut dyn BusOps, line: u16, dot: u16) {
+        let slot = ((dot - 257) / 8) as usize;
+        match (dot - 257) % 8 {
+            // The read on dot 257 itself is done by the background pipeline
+            0 if dot != 257 => {
+                ppu_bus.read(0x2000 | (self.v & 0x0FFF));
+            }
+            2 => {
+                ppu_bus.read(0x2000 | (self.v & 0x0FFF));
+            }
+            4 => {
+                let address = self.sprite_pattern_address(slot, line);
+                self.sprite_pattern1[slot] = ppu_bus.read(address);
+            }
+            6 => {
+                let address = self.sprite_pattern_address(slot, line);
+                self.sprite_pattern2[slot] = ppu_bus.read(address + 8);
+            }
+            7 => {
+                // Empty slots are loaded transparent whatever tile $FF holds
+                if slot < self.secondary_sprites {
+                    self.latch_sprite(slot, self.sprite_pattern1[slot], self.sprite_pattern2[slot]);
+                } else {
+                    self.latch_sprite(slot, 0, 0);
+                }
+            }
+            _ => {}
+        }
+    }
 
-            let offset_y = self.render_y
-                - self.secondary_oam[sprite_offset * OAM_DATA_SIZE + OAM_OFFSET_Y] as u16
-                - 1;
+    fn latch_sprite(&mut self, slot: usize, pattern1: u8, pattern2: u8) {
+        self.sprite_pattern1[slot] = pattern1;
+        self.sprite_pattern2[slot] = pattern2;
+        self.sprite_attribute[slot] = self.secondary_oam[slot * OAM_DATA_SIZE + OAM_OFFSET_ATTR];
+        self.sprite_counter[slot] = self.secondary_oam[slot * OAM_DATA_SIZE + OAM_OFFSET_X];
+        self.sprite_active[slot] = false;
+    }
 
-            if self.sprite_size == 8 {
-                let sprite_index =
-                    self.secondary_oam[sprite_offset * OAM_DATA_SIZE + OAM_OFFSET_INDEX] as u16;
+    // Low plane address of the sprite row in the slot for the given line, the
+    // high plane is 8 bytes after it
+    fn sprite_pattern_address(&self, slot: usize, line: u16) -> u16 {
+        let sprite = &self.secondary_oam[slot * OAM_DATA_SIZE..(slot + 1) * OAM_DATA_SIZE];
+        let sprite_index = sprite[OAM_OFFSET_INDEX];
+        let vertical_flip = sprite[OAM_OFFSET_ATTR] & 0x80 != 0;
 
-                if vertical_flip {
-                    self.sprite_pattern1[self.active_sprites] = ppu_bus
-                        .read(self.sprite_pattern_table + (sprite_index * 16) + 8 - offset_y);
-                    self.sprite_pattern2[self.active_sprites] = ppu_bus
-                        .read(self.sprite_pattern_table + (sprite_index * 16) + 8 - offset_y + 8);
-                } else {
-                    self.sprite_pattern1[self.active_sprites] =
-                        ppu_bus.read(self.sprite_pattern_table + (sprite_index * 16) + offset_y);
-                    self.sprite_pattern2[self.active_sprites] = ppu_bus
-                        .read(self.sprite_pattern_table + (sprite_index * 16) + offset_y + 8);
-                }
-            } else {
-                // TODO handle vertical flip
-                let is_bottom = offset_y >= 8;
+        // Row of the sprite on the line, 0 to sprite_size - 1, empty slots
+        // fetch their first row
+        let mut offset_y = if slot < self.secondary_sprites {
+            line - sprite[OAM_OFFSET_Y] as u16 - 1
+        } else {
+            0
+        };
+        if vertical_flip && slot < self.secondary_sprites {
+            offset_y = self.sprite_size as u16 - 1 - offset_y;
+        }
 
-                let sprite_index_value =
-                    self.secondary_oam[sprite_offset * OAM_DATA_SIZE + OAM_OFFSET_INDEX];
-                let pattern_table = if sprite_index_value & 0x1 != 0 {
-                    0x1000
-                } else {
-                    0x0
-                };
+        if self.sprite_size == 8 {
+            return self.sprite_pattern_table + (sprite_index as u16 * 16) + offset_y;
+        }
+
+        // 8x16 sprites are two consecutive tiles, flipping swaps them
+        let is_bottom = offset_y >= 8;
+        let offset_y = offset_y & 0x7;
+        let pattern_table = if sprite_index & 0x1 != 0 { 0x1000 } else { 0x0 };
+        let sprite_index_offset = if is_bottom { 1 } else { 0 };
+        let sprite_index = (sprite_index & 0xFE) as u16 + sprite_index_offset;
+        pattern_table + (sprite_index * 16) + offset_y
+    }
 
-                let sprite_index_offset = if is_bottom { 1 } else { 0 };
-                let sprite_index = (sprite_index_value as u16 >> 1) + sprite_index_offset;
+    // One dot of the background pipeline on a visible or prerender line. The
+    // tile for 8 dots ahead is fetched through v while the shift registers
+    // feed the current pixel
+    fn fetch_background(&mut self, ppu_bus: &mut dyn BusOps, dot: u16) {
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.background_shift_low <<= 1;
+            self.background_shift_high <<= 1;
+            self.attribute_shift_low <<= 1;
+            self.attribute_shift_high <<= 1;
 
-                self.sprite_pattern1[self.active_sprites] =
-                    ppu_bus.read(pattern_table + (sprite_index * 16) + offset_y);
-                self.sprite_pattern2[self.active_sprites] =
-                    ppu_bus.read(pattern_table + (sprite_index * 16) + offset_y + 8);
+            let fine_y = (self.v >> 12) & 0x7;
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.background_tile = ppu_bus.read(0x2000 | (self.v & 0x0FFF));
+                }
+                2 => {
+                    let attribute_addr = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let mut attribute = ppu_bus.read(attribute_addr);
+                    // Each byte covers 4x4 tiles, pick the 2x2 quadrant
+                    if self.v & 0x0040 != 0 {
+                        attribute >>= 4;
+                    }
+                    if self.v & 0x0002 != 0 {
+                        attribute >>= 2;
+                    }
+                    self.background_next_attribute = attribute & 0x3;
+                }
+                4 => {
+                    self.background_pattern1 = ppu_bus.read(
+                        self.background_pattern_table + self.background_tile as u16 * 16 + fine_y,
+                    );
+                }
+                6 => {
+                    self.background_pattern2 = ppu_bus.read(
+                        self.background_pattern_table
+                            + self.background_tile as u16 * 16
+                            + fine_y
+                            + 8,
+                    );
+                }
+                7 => self.increment_coarse_x(),
+                _ => {}
             }
+        }
 
-            self.sprite_attribute[self.active_sprites as usize] = sprite_attribute;
-            self.active_sprites += 1;
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            // Horizontal position back to the start of the line
+            self.v = (self.v & !0x041F) | (self.t & 0x041F);
+        }
+        if self.render_y == 261 && (280..=304).contains(&dot) {
+            // Vertical position back to the top of the frame
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
         }
     }
 
-    fn fetch_background(&mut self, ppu_bus: &mut dyn BusOps) {
-        let mut fetch_x = self.render_x + self.scroll_x;
-        let mut fetch_y = self.render_y + self.scroll_y;
-        let mut nametable_addr = self.base_nametable;
+    fn load_background_shifters(&mut self) {
+        self.background_shift_low =
+            (self.background_shift_low & 0xFF00) | self.background_pattern1 as u16;
+        self.background_shift_high =
+            (self.background_shift_high & 0xFF00) | self.background_pattern2 as u16;
+        let attribute_low = if self.background_next_attribute & 0x1 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let attribute_high = if self.background_next_attribute & 0x2 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        self.attribute_shift_low = (self.attribute_shift_low & 0xFF00) | attribute_low;
+        self.attribute_shift_high = (self.attribute_shift_high & 0xFF00) | attribute_high;
+    }
+
+    fn shift_background_pixel(&mut self) {
+        let bit = 0x8000 >> self.fine_x;
+        let pixel_bit = |register: u16| if register & bit != 0 { 1 } else { 0 };
+        self.background_pixel =
+            pixel_bit(self.background_shift_low) | pixel_bit(self.background_shift_high) << 1;
+        self.background_attribute =
+            pixel_bit(self.attribute_shift_low) | pixel_bit(self.attribute_shift_high) << 1;
+    }
+
+    fn fetch_background_simple(&mut self, ppu_bus: &mut dyn BusOps) {
+        let scroll = self.scroll();
+        let mut fetch_x = self.render_x + scroll.x;
+        let mut fetch_y = self.render_y + scroll.y;
+        let mut nametable_addr = scroll.nametable;
         if fetch_y >= 240 {
             fetch_y -= 240;
             nametable_addr += 0x800;
@@ -394,7 +996,13 @@ impl Ppu {
 
     fn render_sprites(&mut self) {
         self.sprite_pixel = 0;
-        for index in 0..self.active_sprites {
+        // Secondary OAM keeps the OAM order, so the lowest index opaque sprite wins
+        // and only its priority bit is considered against the background
+        for index in 0..self.secondary_sprites {
+            if !self.sprite_active[index] {
+                continue;
+            }
+
             let sprite_attribute = self.sprite_attribute[index];
             let horizontal_flip = sprite_attribute & 0x40 != 0;
             let bitmask = if horizontal_flip { 0x1 } else { 0x80 };
@@ -423,20 +1031,32 @@ impl Ppu {
         if self.sprite_pixel != 0 && (self.sprite_priority == 0 || self.background_pixel == 0) {
             color =
                 ppu_bus.read(0x3F10 + (self.sprite_palette as u16 * 4) + self.sprite_pixel as u16);
+            if self.layer_view != LayerView::Normal {
+                color = DEBUG_SPRITE_COLOR;
+            }
         } else if self.background_pixel != 0 {
             color = ppu_bus.read(
                 0x3F00 + (self.background_attribute as u16 * 4) + self.background_pixel as u16,
             );
+            if self.layer_view == LayerView::Layers {
+                color = DEBUG_BACKGROUND_COLOR;
+            }
         } else {
             color = ppu_bus.read(0x3F00);
+            if self.layer_view == LayerView::Layers {
+                color = DEBUG_BACKDROP_COLOR;
+            }
         }
 
+        // No hit on the last pixel of the line, the left column is already
+        // handled by the clipped pixels being transparent
         if self.background_pixel != 0
             && self.sprite_pixel != 0
             && self.sprite_index == 0
             && self.sprite_zero_present
+            && self.render_x != 255
         {
-            self.sprite_zero_hit = true;
+            self.sprite_zero_hit_pending = true;
         }
         self.write_pixel(color);
     }
@@ -466,6 +1086,562 @@ impl Ppu {
             self.pixels[pixel_index + 1] = colors[color_index + 1];
             self.pixels[pixel_index + 2] = colors[color_index + 2];
             self.pixels[pixel_index + 3] = 0xFF;
+            self.indexed_pixels[self.render_y as usize * SCREEN_WIDTH + self.render_x as usize] =
+                color;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RecordingBus {
+        memory: Vec<u8>,
+        reads: Vec<u16>,
+    }
+
+    impl BusOps for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            self.memory[address as usize & 0x3FFF]
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.memory[address as usize & 0x3FFF] = data;
+        }
+    }
+
+    fn test_bus() -> RecordingBus {
+        RecordingBus {
+            memory: vec![0; 0x4000],
+            reads: Vec::new(),
+        }
+    }
+
+    // Every sprite but the ones placed by the test sits below the screen
+    fn hide_sprites(ppu: &mut Ppu) {
+        for sprite in ppu.primary_oam.chunks_mut(OAM_DATA_SIZE) {
+            sprite[OAM_OFFSET_Y] = 0xF0;
+        }
+    }
+
+    // Runs from the prerender line to the end of the given line
+    fn render_until(ppu: &mut Ppu, bus: &mut RecordingBus, line: u16) {
+        ppu.render_y = 261;
+        ppu.render_x = 0;
+        while ppu.render_y == 261 || ppu.render_y <= line {
+            ppu.tick(bus);
+        }
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> u8 {
+        ppu.indexed_pixels[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn sprite_patterns_are_fetched_on_dots_257_to_320() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        bus.memory[0x1030] = 0x81;
+        bus.memory[0x1FF0] = 0xFF;
+        bus.memory[0x1FF8] = 0xFF;
+
+        // Sprites from $1000, background from $0000, one sprite on line 10
+        ppu.cpu_write(&mut bus, 0x2000, 0x08);
+        ppu.cpu_write(&mut bus, 0x2001, 0x18);
+        ppu.primary_oam[0..4].copy_from_slice(&[9, 3, 0x00, 20]);
+        ppu.render_y = 9;
+        ppu.render_x = 0;
+
+        let mut visible_reads = Vec::new();
+        let mut sprite_reads: Vec<u16> = Vec::new();
+        while ppu.render_y == 9 {
+            let dot = ppu.render_x + 1;
+            bus.reads.clear();
+            ppu.tick(&mut bus);
+            if dot <= 256 {
+                visible_reads.extend_from_slice(&bus.reads);
+            } else if dot <= 320 {
+                sprite_reads.extend(bus.reads.iter().filter(|a| **a < 0x2000));
+            }
+        }
+
+        assert!(visible_reads
+            .iter()
+            .all(|a| *a >= 0x2000 || *a & 0x1000 == 0));
+        let mut expected = vec![0x1030, 0x1038];
+        for _ in 1..SPRITES_IN_SECONDARY {
+            expected.extend_from_slice(&[0x1FF0, 0x1FF8]);
+        }
+        assert_eq!(sprite_reads, expected);
+
+        // The shifters are loaded with the fetched rows, empty slots stay
+        // transparent
+        assert_eq!(ppu.sprite_pattern1[0], 0x81);
+        assert_eq!(ppu.sprite_counter[0], 20);
+        assert!(ppu.sprite_pattern1[1..].iter().all(|p| *p == 0));
+        assert!(ppu.sprite_pattern2[1..].iter().all(|p| *p == 0));
+    }
+
+    // Runs from the prerender line to the end of line 30 with an opaque
+    // background and an opaque sprite 0 covering only the left column
+    fn sprite_zero_hit_in_left_column(mask: u8) -> bool {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        for byte in bus.memory[0..16].iter_mut() {
+            *byte = 0xFF;
+        }
+
+        ppu.cpu_write(&mut bus, 0x2000, 0x00);
+        ppu.cpu_write(&mut bus, 0x2001, mask);
+        hide_sprites(&mut ppu);
+        ppu.primary_oam[0..4].copy_from_slice(&[20, 0, 0x00, 0]);
+        render_until(&mut ppu, &mut bus, 30);
+        ppu.sprite_zero_hit
+    }
+
+    #[test]
+    fn clipped_sprite_zero_does_not_hit_in_left_column() {
+        assert!(!sprite_zero_hit_in_left_column(0x18));
+        // Clipping only one of the layers is enough to hide the hit
+        assert!(!sprite_zero_hit_in_left_column(0x1A));
+        assert!(!sprite_zero_hit_in_left_column(0x1C));
+        assert!(sprite_zero_hit_in_left_column(0x1E));
+    }
+
+    #[test]
+    fn sprite_zero_hit_shows_up_one_dot_after_the_pixel() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        for byte in bus.memory[0..16].iter_mut() {
+            *byte = 0xFF;
         }
+        ppu.cpu_write(&mut bus, 0x2001, 0x1E);
+        hide_sprites(&mut ppu);
+        // Opaque sprite 0 drawn from line 21, pixel 40
+        ppu.primary_oam[0..4].copy_from_slice(&[20, 0, 0x00, 40]);
+        ppu.render_y = 261;
+        ppu.render_x = 0;
+
+        let mut hit = None;
+        while hit.is_none() {
+            assert!(ppu.render_y == 261 || ppu.render_y < 30);
+            let position = (ppu.render_y, ppu.render_x + 1);
+            ppu.tick(&mut bus);
+            if ppu.cpu_read(&mut bus, 0x2002) & 0x40 != 0 {
+                hit = Some(position);
+            }
+        }
+        // Pixel 40 is output on dot 41, PPUSTATUS has the flag on the next dot
+        assert_eq!(hit, Some((21, 42)));
+    }
+
+    // Sprite 0 at X 20 and sprite 1 at X 16 overlap on 20-23, both are
+    // opaque tile 1 with their own palette. Returns the colors where only
+    // sprite 1, both and only sprite 0 are drawn
+    fn overlapping_sprites(sprite0_attribute: u8, opaque_background: bool) -> [u8; 3] {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        for byte in bus.memory[0x10..0x18].iter_mut() {
+            *byte = 0xFF;
+        }
+        if opaque_background {
+            for byte in bus.memory[0x00..0x08].iter_mut() {
+                *byte = 0xFF;
+            }
+        }
+        bus.memory[0x3F00] = 0x0F;
+        bus.memory[0x3F01] = 0x30;
+        bus.memory[0x3F15] = 0x21;
+        bus.memory[0x3F19] = 0x22;
+
+        ppu.cpu_write(&mut bus, 0x2001, 0x1E);
+        hide_sprites(&mut ppu);
+        ppu.primary_oam[0..8].copy_from_slice(&[9, 1, sprite0_attribute, 20, 9, 1, 0x02, 16]);
+        render_until(&mut ppu, &mut bus, 12);
+        [
+            pixel(&ppu, 17, 12),
+            pixel(&ppu, 22, 12),
+            pixel(&ppu, 25, 12),
+        ]
+    }
+
+    #[test]
+    fn lowest_oam_index_wins_overlapping_sprites() {
+        // Sprite 1 starts first but sprite 0 still wins the overlap
+        assert_eq!(overlapping_sprites(0x01, false), [0x22, 0x21, 0x21]);
+        // A sprite 0 behind the background still hides sprite 1 under it,
+        // the background shows through both
+        assert_eq!(overlapping_sprites(0x21, true), [0x22, 0x30, 0x30]);
+        assert_eq!(overlapping_sprites(0x21, false), [0x22, 0x21, 0x21]);
+    }
+
+    // Opaque background with an opaque sprite at X 40 on line 21. Returns the
+    // colors of a background pixel and a sprite pixel
+    fn layer_view_colors(view: LayerView, mask: u8) -> [u8; 2] {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        for byte in bus.memory[0x00..0x20].iter_mut() {
+            *byte = 0xFF;
+        }
+        bus.memory[0x3F00] = 0x21;
+        bus.memory[0x3F03] = 0x30;
+        bus.memory[0x3F13] = 0x27;
+
+        ppu.set_debug_layer_view(view);
+        ppu.cpu_write(&mut bus, 0x2001, mask);
+        hide_sprites(&mut ppu);
+        ppu.primary_oam[0..4].copy_from_slice(&[20, 1, 0x00, 40]);
+        render_until(&mut ppu, &mut bus, 21);
+        [pixel(&ppu, 10, 21), pixel(&ppu, 42, 21)]
+    }
+
+    #[test]
+    fn layer_view_replaces_colors_with_sentinels() {
+        assert_eq!(layer_view_colors(LayerView::Normal, 0x1E), [0x30, 0x27]);
+        assert_eq!(
+            layer_view_colors(LayerView::Layers, 0x1E),
+            [DEBUG_BACKGROUND_COLOR, DEBUG_SPRITE_COLOR]
+        );
+        // With the background disabled its pixels show the backdrop sentinel,
+        // not the backdrop color
+        assert_eq!(
+            layer_view_colors(LayerView::Layers, 0x14),
+            [DEBUG_BACKDROP_COLOR, DEBUG_SPRITE_COLOR]
+        );
+        assert_eq!(
+            layer_view_colors(LayerView::SpriteHighlight, 0x1E),
+            [0x30, DEBUG_SPRITE_COLOR]
+        );
+        assert_eq!(
+            layer_view_colors(LayerView::SpriteHighlight, 0x14),
+            [0x21, DEBUG_SPRITE_COLOR]
+        );
+    }
+
+    #[test]
+    fn indexed_framebuffer_matches_rgba() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        for byte in bus.memory[0x00..0x08].iter_mut() {
+            *byte = 0xF0;
+        }
+        bus.memory[0x3F00] = 0x21;
+        bus.memory[0x3F01] = 0x16;
+        ppu.cpu_write(&mut bus, 0x2001, 0x0A);
+        render_until(&mut ppu, &mut bus, 0);
+
+        // Tile 0 is opaque on its left half, the rest is the backdrop
+        assert_eq!(pixel(&ppu, 0, 0), 0x16);
+        assert_eq!(pixel(&ppu, 6, 0), 0x21);
+        assert_eq!(ppu.pixels[0..4], [152, 34, 32, 0xFF][..]);
+        assert_eq!(ppu.pixels[6 * 4..6 * 4 + 4], [76, 154, 236, 0xFF][..]);
+    }
+
+    // Eight sprites on line 10 followed by sprite 8 off the line, with the
+    // given tile number in sprite 9
+    fn overflow_after_eight_sprites(mode: SpriteOverflowMode, sprite9_tile: u8) -> bool {
+        let mut ppu = Box::new(Ppu::new());
+        ppu.set_sprite_overflow_mode(mode);
+        hide_sprites(&mut ppu);
+        for sprite in ppu.primary_oam[0..32].chunks_mut(OAM_DATA_SIZE) {
+            sprite[OAM_OFFSET_Y] = 5;
+        }
+        ppu.primary_oam[9 * OAM_DATA_SIZE + OAM_OFFSET_INDEX] = sprite9_tile;
+        ppu.load_secondary_oam(10);
+        assert_eq!(ppu.secondary_sprites, 8);
+        ppu.sprite_overflow
+    }
+
+    #[test]
+    fn hardware_overflow_reads_oam_diagonally() {
+        // The miss on sprite 8 moves the compare to the tile byte of sprite
+        // 9, which is in range when read as a Y coordinate
+        assert!(overflow_after_eight_sprites(
+            SpriteOverflowMode::Hardware,
+            5
+        ));
+        assert!(!overflow_after_eight_sprites(SpriteOverflowMode::Simple, 5));
+        assert!(!overflow_after_eight_sprites(
+            SpriteOverflowMode::Hardware,
+            0xF0
+        ));
+    }
+
+    #[test]
+    fn scroll_reads_back_ppuctrl_and_ppuscroll() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2000, 0x03);
+        ppu.cpu_write(&mut bus, 0x2005, 0x7D);
+        ppu.cpu_write(&mut bus, 0x2005, 0x5E);
+
+        let scroll = ppu.scroll();
+        assert_eq!(scroll.x, 0x7D);
+        assert_eq!(scroll.y, 0x5E);
+        assert_eq!(scroll.nametable, 0x2C00);
+    }
+
+    #[test]
+    fn oam_accessors_share_oamdata_memory() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2003, 0x10);
+        ppu.cpu_write(&mut bus, 0x2004, 0x77);
+        assert_eq!(ppu.oam()[0x10], 0x77);
+
+        // A sprite placed through the accessor is drawn, and OAMADDR is left
+        // where OAMDATA moved it
+        for byte in bus.memory[0x10..0x18].iter_mut() {
+            *byte = 0xFF;
+        }
+        bus.memory[0x3F11] = 0x27;
+        ppu.cpu_write(&mut bus, 0x2001, 0x16);
+        hide_sprites(&mut ppu);
+        ppu.oam_mut()[0..4].copy_from_slice(&[19, 0x01, 0x00, 40]);
+        assert_eq!(ppu.oam_addr, 0x11);
+        render_until(&mut ppu, &mut bus, 20);
+        assert_eq!(pixel(&ppu, 43, 20), 0x27);
+    }
+
+    #[test]
+    fn scanline_callback_sees_every_visible_line() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let recorded = lines.clone();
+        ppu.set_scanline_callback(Some(Box::new(move |line| recorded.borrow_mut().push(line))));
+
+        render_until(&mut ppu, &mut bus, 239);
+        while ppu.render_y != 261 {
+            ppu.tick(&mut bus);
+        }
+        assert_eq!(*lines.borrow(), (0..240).collect::<Vec<u16>>());
+
+        ppu.set_scanline_callback(None);
+        render_until(&mut ppu, &mut bus, 10);
+        assert_eq!(lines.borrow().len(), 240);
+    }
+
+    #[test]
+    fn sprite_evaluation_starts_at_oamaddr() {
+        let mut ppu = Box::new(Ppu::new());
+        hide_sprites(&mut ppu);
+        ppu.primary_oam[0..4].copy_from_slice(&[9, 1, 0x00, 10]);
+        ppu.primary_oam[8..12].copy_from_slice(&[9, 2, 0x00, 30]);
+        ppu.oam_addr = 8;
+        ppu.load_secondary_oam(10);
+
+        // Sprite 2 is evaluated first and takes the role of sprite 0
+        assert_eq!(ppu.secondary_sprites, 1);
+        assert_eq!(ppu.secondary_oam[OAM_OFFSET_X], 30);
+        assert!(ppu.sprite_zero_present);
+    }
+
+    #[test]
+    fn sprite_fetches_clear_oamaddr() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2001, 0x18);
+        ppu.cpu_write(&mut bus, 0x2003, 0x20);
+        ppu.render_y = 10;
+        ppu.render_x = 0;
+        // Up to dot 256
+        while ppu.render_x < 256 {
+            ppu.tick(&mut bus);
+        }
+        assert_eq!(ppu.oam_addr, 0x20);
+        ppu.tick(&mut bus);
+        assert_eq!(ppu.oam_addr, 0);
+    }
+
+    #[test]
+    fn scroll_and_address_writes_fill_t_and_v() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2000, 0x00);
+        ppu.cpu_write(&mut bus, 0x2005, 0x7D);
+        ppu.cpu_write(&mut bus, 0x2005, 0x5E);
+        assert_eq!(ppu.t, 0x616F);
+        assert_eq!(ppu.fine_x, 5);
+
+        ppu.cpu_write(&mut bus, 0x2006, 0x3D);
+        assert_eq!(ppu.t, 0x3D6F);
+        ppu.cpu_write(&mut bus, 0x2006, 0xF0);
+        assert_eq!(ppu.t, 0x3DF0);
+        assert_eq!(ppu.v, 0x3DF0);
+        assert!(!ppu.write_toggle);
+    }
+
+    #[test]
+    fn prerender_line_copies_t_into_v() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2001, 0x08);
+        ppu.cpu_write(&mut bus, 0x2005, 0x7D);
+        ppu.cpu_write(&mut bus, 0x2005, 0x5E);
+        ppu.render_y = 261;
+        ppu.render_x = 0;
+        while ppu.render_x < 304 {
+            ppu.tick(&mut bus);
+        }
+        assert_eq!(ppu.v, ppu.t);
+    }
+
+    #[test]
+    fn status_read_resets_the_write_toggle() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2005, 0x7D);
+        assert!(ppu.write_toggle);
+        ppu.cpu_read(&mut bus, 0x2002);
+        assert!(!ppu.write_toggle);
+
+        // The next write is an X scroll again
+        ppu.cpu_write(&mut bus, 0x2005, 0x13);
+        assert_eq!(ppu.fine_x, 3);
+        assert_eq!(ppu.t & 0x001F, 0x02);
+    }
+
+    #[test]
+    fn tall_sprites_span_two_tiles() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2000, 0x20);
+        assert_eq!(ppu.sprite_size, 16);
+        hide_sprites(&mut ppu);
+        // Odd index, tiles 2 and 3 from $1000
+        ppu.primary_oam[0..4].copy_from_slice(&[9, 0x03, 0x00, 20]);
+
+        let address_on = |ppu: &mut Ppu, line: u16| {
+            ppu.load_secondary_oam(line);
+            assert_eq!(ppu.secondary_sprites, 1);
+            ppu.sprite_pattern_address(0, line)
+        };
+        assert_eq!(address_on(&mut ppu, 10), 0x1020);
+        assert_eq!(address_on(&mut ppu, 18), 0x1030);
+        assert_eq!(address_on(&mut ppu, 25), 0x1037);
+        ppu.load_secondary_oam(26);
+        assert_eq!(ppu.secondary_sprites, 0);
+
+        // Flipping swaps the tiles along with the rows
+        ppu.primary_oam[OAM_OFFSET_ATTR] = 0x80;
+        assert_eq!(address_on(&mut ppu, 10), 0x1037);
+        assert_eq!(address_on(&mut ppu, 25), 0x1020);
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered_except_palettes() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        bus.memory[0x2005] = 0x55;
+        bus.memory[0x2006] = 0x66;
+        bus.memory[0x2F00] = 0x44;
+        bus.memory[0x3F00] = 0x21;
+
+        ppu.cpu_write(&mut bus, 0x2006, 0x20);
+        ppu.cpu_write(&mut bus, 0x2006, 0x05);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x00);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x55);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x66);
+
+        // The palette comes back right away, the buffer gets the nametable
+        // byte under it
+        ppu.cpu_write(&mut bus, 0x2006, 0x3F);
+        ppu.cpu_write(&mut bus, 0x2006, 0x00);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x21);
+        ppu.cpu_write(&mut bus, 0x2006, 0x20);
+        ppu.cpu_write(&mut bus, 0x2006, 0x00);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x44);
+    }
+
+    #[test]
+    fn status_read_on_the_vblank_dot_suppresses_it() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.cpu_write(&mut bus, 0x2000, 0x80);
+        ppu.render_y = 241;
+        ppu.render_x = 1;
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2002) & 0x80, 0);
+        ppu.tick(&mut bus);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2002) & 0x80, 0);
+        assert!(!ppu.nmi_state);
+
+        // Only for that frame
+        ppu.render_y = 241;
+        ppu.render_x = 1;
+        ppu.tick(&mut bus);
+        assert!(ppu.nmi_state);
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2002) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_raises_it() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        ppu.render_y = 241;
+        ppu.render_x = 1;
+        ppu.tick(&mut bus);
+        assert!(!ppu.nmi_state);
+
+        ppu.cpu_write(&mut bus, 0x2000, 0x80);
+        assert!(ppu.nmi_state);
+
+        // Rewriting the bit already set doesn't
+        ppu.nmi_state = false;
+        ppu.cpu_write(&mut bus, 0x2000, 0x80);
+        assert!(!ppu.nmi_state);
+        ppu.cpu_write(&mut bus, 0x2000, 0x00);
+        ppu.cpu_write(&mut bus, 0x2000, 0x80);
+        assert!(ppu.nmi_state);
+
+        // Nor does enabling it once the vblank flag was read
+        ppu.nmi_state = false;
+        ppu.cpu_write(&mut bus, 0x2000, 0x00);
+        ppu.cpu_read(&mut bus, 0x2002);
+        ppu.cpu_write(&mut bus, 0x2000, 0x80);
+        assert!(!ppu.nmi_state);
+    }
+
+    #[test]
+    fn framebuffer_crc_skips_the_alpha_channel() {
+        let mut ppu = Box::new(Ppu::new());
+        for (pixel, rgb) in ppu.pixels.chunks_mut(4).zip(b"123456789".chunks(3)) {
+            pixel[0..3].copy_from_slice(rgb);
+        }
+        // zlib.crc32 of the color bytes
+        assert_eq!(ppu.framebuffer_crc(), 0xE3E2F189);
+
+        let hash = ppu.framebuffer_hash();
+        for pixel in ppu.pixels.chunks_mut(4) {
+            pixel[3] = 0xFF;
+        }
+        assert_eq!(ppu.framebuffer_crc(), 0xE3E2F189);
+        assert_eq!(ppu.framebuffer_hash(), hash);
+    }
+
+    #[test]
+    fn blank_framebuffer_has_known_checksums() {
+        let ppu = Box::new(Ppu::new());
+        // zlib.crc32 and FNV-1a of 256 * 240 * 3 zero bytes
+        assert_eq!(ppu.framebuffer_crc(), 0xB77D18AB);
+        assert_eq!(ppu.framebuffer_hash(), 0x96D63225EA926325);
+    }
+
+    #[test]
+    fn reset_clears_the_ppudata_read_buffer() {
+        let mut ppu = Box::new(Ppu::new());
+        let mut bus = test_bus();
+        bus.memory[0x2000] = 0x55;
+        ppu.cpu_write(&mut bus, 0x2006, 0x20);
+        ppu.cpu_write(&mut bus, 0x2006, 0x00);
+        ppu.cpu_read(&mut bus, 0x2007);
+
+        ppu.reset();
+        assert_eq!(ppu.cpu_read(&mut bus, 0x2007), 0x00);
     }
 }
@@ -0,0 +1,116 @@
+use crate::nes::Nes;
+use std::collections::VecDeque;
+
+const DEFAULT_INTERVAL: usize = 10;
+// With the default interval this is about 10 seconds of play, every snapshot
+// is a few hundred kilobytes
+const DEFAULT_CAPACITY: usize = 60;
+
+// Ring of whole machine save states taken every few frames, rewinding walks
+// back through them. The states carry the CPU/PPU alignment so play resumes
+// exactly where the snapshot was taken
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames: usize,
+    snapshots: VecDeque<Vec<u8>>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new()
+    }
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Rewind {
+            interval: DEFAULT_INTERVAL,
+            capacity: DEFAULT_CAPACITY,
+            frames: 0,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    // Frames between snapshots
+    pub fn set_interval(&mut self, frames: usize) {
+        self.interval = std::cmp::max(frames, 1);
+    }
+
+    // The oldest snapshots are dropped past this count
+    pub fn set_capacity(&mut self, snapshots: usize) {
+        self.capacity = std::cmp::max(snapshots, 1);
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Called after every emulated frame
+    pub fn record(&mut self, nes: &Nes) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(nes.save_state_versioned());
+    }
+
+    // Restores the most recent snapshot and forgets it, returns false once
+    // there is nothing left to go back to
+    pub fn rewind(&mut self, nes: &mut Nes) -> bool {
+        self.frames = 0;
+        match self.snapshots.pop_back() {
+            Some(snapshot) => nes.load_state_versioned(&snapshot).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.snapshots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INC $10 in a loop, boxed like the machines in the nes tests
+    fn counting_nes() -> Box<Nes> {
+        let mut prg = vec![0; 32768];
+        prg[0..5].copy_from_slice(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        prg[0x7FFD] = 0x80;
+        let mut mapper = crate::mapper::Mapper::new();
+        mapper.load_parts(0, &prg, &[], 8192).unwrap();
+        Box::new(Nes::new(mapper))
+    }
+
+    #[test]
+    fn rewind_walks_back_through_the_snapshots() {
+        let mut nes = counting_nes();
+        let mut rewind = Rewind::new();
+        rewind.set_interval(2);
+        rewind.set_capacity(2);
+
+        let mut states = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..2 {
+                nes.run_frame();
+                rewind.record(&nes);
+            }
+            states.push(nes.save_state_versioned());
+        }
+        nes.run_frame();
+
+        // Only the last two snapshots are kept
+        assert!(rewind.rewind(&mut nes));
+        assert!(nes.save_state_versioned() == states[2]);
+        assert!(rewind.rewind(&mut nes));
+        assert!(nes.save_state_versioned() == states[1]);
+        assert!(!rewind.rewind(&mut nes));
+        assert!(nes.save_state_versioned() == states[1]);
+    }
+}
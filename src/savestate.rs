@@ -0,0 +1,119 @@
+use crate::error::{NesError, Result};
+
+const MAGIC: [u8; 8] = *b"NESRUSTS";
+pub const VERSION: u32 = 7;
+// Only NTSC timing is emulated
+pub const REGION_NTSC: u8 = 0;
+const HEADER_SIZE: usize = 8 + 4 + 1 + 8;
+
+// 64 bit FNV-1a, enough to tell ROMs and frames apart
+pub fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+    hash
+}
+
+// Layout: magic, version (u32 LE), region, ROM hash (u64 LE), state payload
+pub fn wrap(region: u8, rom_hash: u64, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.push(region);
+    data.extend_from_slice(&rom_hash.to_le_bytes());
+    data.extend_from_slice(payload);
+    data
+}
+
+// Checks the header against the running machine and returns the payload
+pub fn unwrap(data: &[u8], region: u8, rom_hash: u64) -> Result<&[u8]> {
+    if data.len() < HEADER_SIZE || data[0..8] != MAGIC {
+        return Err(NesError::InvalidState);
+    }
+
+    let mut version = [0; 4];
+    version.copy_from_slice(&data[8..12]);
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(NesError::StateVersion(version));
+    }
+
+    if data[12] != region {
+        return Err(NesError::StateRegion(data[12]));
+    }
+
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&data[13..21]);
+    if u64::from_le_bytes(hash) != rom_hash {
+        return Err(NesError::StateRomMismatch);
+    }
+
+    Ok(&data[HEADER_SIZE..])
+}
+
+// Walks a state buffer written field by field, every read fails with
+// InvalidState once the buffer runs out
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            return Err(NesError::InvalidState);
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn fill(&mut self, target: &mut [u8]) -> Result<()> {
+        target.copy_from_slice(self.bytes(target.len())?);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // Nested state written with write_section
+    pub fn section(&mut self) -> Result<&'a [u8]> {
+        let length = self.u64()? as usize;
+        self.bytes(length)
+    }
+
+    // Leftover data means the state doesn't match the layout
+    pub fn finish(self) -> Result<()> {
+        if !self.data.is_empty() {
+            return Err(NesError::InvalidState);
+        }
+        Ok(())
+    }
+}
+
+// Length prefixed block, read back with StateReader::section
+pub fn write_section(state: &mut Vec<u8>, section: &[u8]) {
+    state.extend_from_slice(&(section.len() as u64).to_le_bytes());
+    state.extend_from_slice(section);
+}
@@ -0,0 +1,82 @@
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_CATCH_UP: usize = 3;
+
+// 60.0988 frames per second: 29780.5 CPU cycles at 1.789773MHz
+pub const NTSC_FRAME_PERIOD: Duration = Duration::from_nanos(16639267);
+
+pub struct Scheduler {
+    frame_period: Duration,
+    max_catch_up: usize,
+    last_update: Instant,
+    pending: Duration,
+}
+
+impl Scheduler {
+    pub fn new(frame_period: Duration) -> Self {
+        Scheduler {
+            frame_period,
+            max_catch_up: DEFAULT_MAX_CATCH_UP,
+            last_update: Instant::now(),
+            pending: Duration::from_secs(0),
+        }
+    }
+
+    pub fn set_max_catch_up(&mut self, frames: usize) {
+        self.max_catch_up = std::cmp::max(frames, 1);
+    }
+
+    // Number of frames to emulate to keep up with the host clock, clamped so a
+    // long stall doesn't make the emulator fall further and further behind
+    pub fn frames_due(&mut self, now: Instant) -> usize {
+        self.pending += now.saturating_duration_since(self.last_update);
+        self.last_update = now;
+
+        let mut frames = 0;
+        while self.pending >= self.frame_period && frames < self.max_catch_up {
+            self.pending -= self.frame_period;
+            frames += 1;
+        }
+
+        if self.pending >= self.frame_period {
+            // Drop the time that can't be caught up with
+            self.pending = Duration::from_secs(0);
+        }
+        frames
+    }
+
+    // How long the host can sleep before the next frame is due
+    pub fn time_until_next_frame(&self, now: Instant) -> Duration {
+        let pending = self.pending + now.saturating_duration_since(self.last_update);
+        self.frame_period
+            .checked_sub(pending)
+            .unwrap_or(Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_only_catch_up_a_few_frames() {
+        let period = Duration::from_millis(10);
+        let mut scheduler = Scheduler::new(period);
+        let start = scheduler.last_update;
+
+        assert_eq!(scheduler.frames_due(start + Duration::from_millis(25)), 2);
+        assert_eq!(
+            scheduler.time_until_next_frame(start + Duration::from_millis(25)),
+            Duration::from_millis(5)
+        );
+
+        // A one second stall runs the maximum and forgets the rest
+        let now = start + Duration::from_millis(1025);
+        assert_eq!(scheduler.frames_due(now), DEFAULT_MAX_CATCH_UP);
+        assert_eq!(scheduler.frames_due(now + Duration::from_millis(5)), 0);
+        assert_eq!(scheduler.frames_due(now + Duration::from_millis(10)), 1);
+
+        scheduler.set_max_catch_up(0);
+        assert_eq!(scheduler.frames_due(now + Duration::from_millis(100)), 1);
+    }
+}